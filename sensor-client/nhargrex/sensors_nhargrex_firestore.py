@@ -9,19 +9,37 @@
 # -- update_state_and_notify_user(user, state)
 # -- >>> from sensors_nhargrex_firestore import update_state_and_notify_user
 # -- >>> update_state_and_notify_user('2U0...', 'open')
-# -- returns 0 if Ok
+# -- returns 0 if Ok (2 if the user was notified)
 # -- returns 1 if Error
+# -- returns 3 if rejected by validation (retrying can't help)
+# -- notify/video (from the security mode) turn the notification and door-open clip off,
+# -- the state is still written
 # Update temperature and humidity entry point
 # -- update_temp_and_humidity(user, temp_f, humidity)
 # -- >>> from sensors_nhargrex_firestore import update_temp_and_humidity
 # -- >>> update_temp_and_humidity('2U0...', 72.5, 45.0)
 # -- returns 0 if Ok
 # -- returns 1 if Error
+# -- returns 3 if rejected by validation (retrying can't help)
+# On-demand capture entry point
+# -- capture_media(user, kind, seconds)
+# -- >>> from sensors_nhargrex_firestore import capture_media
+# -- >>> capture_media('2U0...', 'clip', 10)
+# -- returns the Storage path (gs://<bucket>/videos/<user>/capture-....mp4) if Ok
+# -- raises if the camera is busy or the capture/upload failed
+# Notification entry point
+# -- send_notification(user, message)
+# -- sends `message` to the user's device, raises if it failed
+# Diagnostics upload entry point
+# -- upload_diagnostics(user, filename)
+# -- uploads a gzipped diagnostics bundle to diagnostics/<user>/
+# -- returns the Storage path if Ok, raises if the upload failed
 """
 import json
 import logging
 import os
 import subprocess
+import threading
 import firebase_admin
 import requests
 import google.auth.transport.requests
@@ -35,8 +53,8 @@ import pyrebase
 from firebase_admin import firestore
 from firebase_admin import credentials as firebase_credentials
 from firebase_admin import firestore, storage
-from picamera2 import Picamera2
 from google.cloud import pubsub_v1
+import google.auth.credentials
 
 logging.basicConfig(level=logging.INFO)
 
@@ -47,8 +65,11 @@ FCM_URL = BASE_URL + '/' + FCM_ENDPOINT
 SCOPES = ['https://www.googleapis.com/auth/firebase.messaging']
 
 PUBSUB_TOPIC_ID = "sensor-data" # Replace with your desired Pub/Sub topic name
-PUBSUB_PUBLISHER = pubsub_v1.PublisherClient()
-PUBSUB_TOPIC_PATH = PUBSUB_PUBLISHER.topic_path(PROJECT_ID, PUBSUB_TOPIC_ID)
+
+class _EmulatorCredential(firebase_credentials.Base):
+  """Anonymous credential used when FIRESTORE_EMULATOR_HOST is set."""
+  def get_credential(self):
+    return google.auth.credentials.AnonymousCredentials()
 
 def _get_access_token(credentials_file):
 # [START retrieve_access_token]
@@ -59,6 +80,9 @@ def _get_access_token(credentials_file):
 # [END retrieve_access_token]
 
 def _send_fcm_message(fcm_message):
+  if os.environ.get("FIRESTORE_EMULATOR_HOST"):
+    logging.info("Firestore emulator in use; not sending FCM message")
+    return
   headers = {
     'Authorization': 'Bearer ' + _get_access_token(os.environ["GOOGLE_APPLICATION_CREDENTIALS"]),
     'Content-Type': 'application/json; UTF-8',
@@ -102,7 +126,11 @@ def _ensure_firebase_app():
             logging.info(f"Using FIREBASE_STORAGE_BUCKET={bucket_name!r}")
 
         try:
-            if cred_path:
+            if os.environ.get("FIRESTORE_EMULATOR_HOST"):
+                project_id = os.environ.get("GOOGLE_PROJECT_ID", PROJECT_ID)
+                logging.info("Initializing firebase_admin for Firestore emulator at %r", os.environ["FIRESTORE_EMULATOR_HOST"])
+                app = firebase_admin.initialize_app(_EmulatorCredential(), options={"storageBucket": bucket_name, "projectId": project_id})
+            elif cred_path:
                 logging.info("Initializing firebase_admin with credentials at %r", cred_path)
                 cred = firebase_credentials.Certificate(cred_path)
                 app = firebase_admin.initialize_app(cred, options={"storageBucket": bucket_name})
@@ -116,24 +144,32 @@ def _ensure_firebase_app():
             # Raise a clear error so callers see the root cause instead of a later ValueError
             raise RuntimeError("Failed to initialize Firebase app") from init_exc
 
+# the camera is shared by the door-open clip and on-demand captures
+_camera_lock = threading.Lock()
+
+def _upload_to_storage(user, filename, folder, content_type):
+    """Upload `filename` to <folder>/<user>/ and return its gs:// path."""
+    app = _ensure_firebase_app()
+    bucket = storage.bucket(app=app)
+    logging.info(f"Uploading {os.path.basename(filename)!r} to Storage bucket {bucket.name!r} for user {user!r}")
+    blob = bucket.blob(f'{folder}/{user}/{os.path.basename(filename)}')
+
+    # Ensure correct content-type so browsers / console can download properly
+    blob.upload_from_filename(filename, content_type=content_type)
+
+    # Reload metadata and log useful fields
+    blob.reload()
+    logging.info("Upload complete: %r -> gs://%s/%s (size=%s content_type=%s)",
+                 filename, bucket.name, blob.name, blob.size, blob.content_type)
+    return f"gs://{bucket.name}/{blob.name}"
+
 def _firestore_upload_video_to_storage(user, filename):
     try:
-        app = _ensure_firebase_app()
-        bucket = storage.bucket(app=app)
-        logging.info(f"Uploading video {os.path.basename(filename)!r} to Storage bucket {bucket.name!r} for user {user!r}")
-        blob = bucket.blob(f'videos/{user}/{os.path.basename(filename)}')
-
-        # Ensure correct content-type so browsers / console can download properly
-        blob.upload_from_filename(filename, content_type='video/mp4')
-
-        # Reload metadata and log useful fields
-        blob.reload()
-        logging.info("Upload complete: %r -> gs://%s/%s (size=%s content_type=%s)",
-                     filename, bucket.name, blob.name, blob.size, blob.content_type)
+        _upload_to_storage(user, filename, 'videos', 'video/mp4')
     except Exception:
         logging.exception("Failed to upload video to Storage")
 
-def _firestore_add_data(state, user, temp_f=None, humidity=None):
+def _firestore_add_data(state, user, temp_f=None, humidity=None, timestamp=None):
     db = firestore.client()
 
     # [START add_data]
@@ -145,7 +181,7 @@ def _firestore_add_data(state, user, temp_f=None, humidity=None):
           "online" : True,
           "temp_f": temp_f,
           "humidity": humidity,
-          "timestamp": time.time()
+          "timestamp": timestamp if timestamp is not None else time.time()
       })
     else:
       doc_ref.set({
@@ -176,10 +212,13 @@ def _firestore_read_state(user):
     raise Exception('Document does not exist.') 
     # [END read_data]
 
+class ValidationError(ValueError):
+  """Invalid argument, the call can never succeed as is (unlike cloud and network errors)."""
+
 def _validate_user(user):
   pattern = re.compile('[A-Za-z0-9]+')
   if (pattern.match(user) == None or len(user) != 28):
-    raise ValueError('Invalid user: user must match validation pattern.') 
+    raise ValidationError('Invalid user: user must match validation pattern.') 
 
 def _validate_state(state):
   match state:
@@ -188,21 +227,21 @@ def _validate_state(state):
     case "closed":
       state = "closed"
     case _:
-      raise ValueError('Invalid state: valid states are [open|closed].')    
+      raise ValidationError('Invalid state: valid states are [open|closed].')    
 
 def _validate_temp(temp_f):
   if (type(temp_f) is not float or temp_f < -40 or temp_f > 125):
-    raise ValueError('Invalid temperature: valid range is -40 to 125 F.') 
+    raise ValidationError('Invalid temperature: valid range is -40 to 125 F.') 
 
 def _validate_humidity(humidity):
   if (type(humidity) is not float or humidity < 0.0 or humidity > 100.0):
     logging.info(f"_validate_humidity: invalid humidity value: {humidity!r}")
-    raise ValueError('Invalid humidity: valid range is 0 to 100%.]') 
+    raise ValidationError('Invalid humidity: valid range is 0 to 100%.]') 
   
 def _validate_timestamp(timestamp):
   if (type(timestamp) is not int or timestamp < 0 ):
     logging.info(f"_validate_timestamp: invalid timestamp: {timestamp!r}")
-    raise ValueError('Invalid timestamp: valid range is 0 and up.') 
+    raise ValidationError('Invalid timestamp: valid range is 0 and up.') 
 
 def _capture_video(filename_base, capture_time):
     """
@@ -214,6 +253,8 @@ def _capture_video(filename_base, capture_time):
     out_mp4 = filename_base + ".mp4"
 
     try:
+        # imported here so the module can be loaded off the Pi (tests, emulator)
+        from picamera2 import Picamera2
         picam2 = Picamera2()
         picam2.start_and_record_video(tmp_h264, duration=capture_time)
         picam2.close()
@@ -250,15 +291,31 @@ def _capture_video(filename_base, capture_time):
 
     return out_mp4
 
+def _capture_photo(filename_base):
+    """Take a still photo and return its .jpg path."""
+    out_jpg = filename_base + ".jpg"
+    try:
+        from picamera2 import Picamera2
+        picam2 = Picamera2()
+        picam2.start_and_capture_file(out_jpg, show_preview=False)
+        picam2.close()
+    except Exception:
+        logging.exception("Failed to capture photo with Picamera2")
+        raise
+    return out_jpg
+
 #
 # Send notification entry point
 # -- update_state_and_notify_user(user, state)
 # -- >>> from sensors_nhargrex_firestore import updateStateAndNotifyUser
 # -- >>> update_state_and_notify_user('2U0...', 'open', '71.0', '41.0', True)
+# -- timestamp is the original event time, delayed marks a notification replayed from the outbox
 # -- returns 0 if Ok
 # -- returns 1 if Error
-def update_state_and_notify_user(user, state, temp_f=None, humidity=None, force_notify=None):
-    logging.info(f"update_state_and_notify_user called: user={user!r}, state={state!r}, temp={temp_f!r}, humidity={humidity!r}, force={force_notify!r}")
+# -- returns 2 if Ok and the user was notified
+# -- returns 3 if rejected by validation (retrying can't help)
+def update_state_and_notify_user(user, state, temp_f=None, humidity=None, force_notify=None, timestamp=None, delayed=False, notify=True, video=True):
+    logging.info(f"update_state_and_notify_user called: user={user!r}, state={state!r}, temp={temp_f!r}, humidity={humidity!r}, force={force_notify!r}, timestamp={timestamp!r}, delayed={delayed!r}, notify={notify!r}, video={video!r}")
     logging.info(f"ENV GOOGLE_APPLICATION_CREDENTIALS={os.environ.get('GOOGLE_APPLICATION_CREDENTIALS')!r}, GOOGLE_USER_ID={os.environ.get('GOOGLE_USER_ID')!r}")
     try:
         if (temp_f is None) or (humidity is None):
@@ -277,7 +334,11 @@ def update_state_and_notify_user(user, state, temp_f=None, humidity=None, force_
         state_in_cloud = firestore_state.get("state")
 
         if (state != state_in_cloud or (force_notify is not None and force_notify == True)) and (temp_f is not None and humidity is not None):
-            if state == "open":
+            if state == "open" and not video:
+              logging.info("State changed to 'open'; video off in this mode, skipping video capture")
+            elif state == "open" and delayed:
+              logging.info("State changed to 'open' (delayed); skipping video capture")
+            elif state == "open":
               logging.info("State changed to 'open'; capturing video")
               timestr = time.strftime("%Y%m%d-%H%M%S")
               # cleanup old files
@@ -289,37 +350,106 @@ def update_state_and_notify_user(user, state, temp_f=None, humidity=None, force_
                           logging.exception("Failed to remove old tmp file")
 
               filename_base = os.path.join("/tmp", f"security-{timestr}")
-              # capture returns the final .mp4 path
-              filename_mp4 = _capture_video(filename_base, capture_time=5)
-              logging.info(f"Captured video to {filename_mp4!r}")
-
-              try:
-                  _firestore_upload_video_to_storage(user, filename_mp4)
-              except Exception:
-                  logging.exception("Video upload failed (continuing)")
+              if not _camera_lock.acquire(blocking=False):
+                  logging.info("Camera busy with an on-demand capture; skipping video capture")
+              else:
+                  try:
+                      # capture returns the final .mp4 path
+                      filename_mp4 = _capture_video(filename_base, capture_time=5)
+                  finally:
+                      _camera_lock.release()
+                  logging.info(f"Captured video to {filename_mp4!r}")
+
+                  try:
+                      _firestore_upload_video_to_storage(user, filename_mp4)
+                  except Exception:
+                      logging.exception("Video upload failed (continuing)")
+            if not notify:
+              logging.info(f"State changed from {state_in_cloud!r} to {state!r}; updating Firestore, notification off in this mode")
+              _firestore_add_data(state, user, temp_f, humidity, timestamp)
+              return 0
             if (force_notify is not None and force_notify == True):
               logging.info("Force notify is True; updating Firestore and sending notification")
             else:
               logging.info(f"State changed from {state_in_cloud!r} to {state!r}; updating Firestore and sending notification")
-            _firestore_add_data(state, user, temp_f, humidity)
+            _firestore_add_data(state, user, temp_f, humidity, timestamp)
             message_string = f"Door: {state}, Temp: {round(temp_f)}\u00B0F, Humidity: {round(humidity)}%"
+            if delayed and timestamp is not None:
+              message_string = f"(Delayed, {time.strftime('%H:%M', time.localtime(timestamp))}) {message_string}"
             _send_fcm_message(_build_message(_firestore_read_data(user)["token"], message_string))
+            return 2
         else:
           logging.info("State unchanged; skipping update and notification")
         return 0
 
+    except ValidationError:
+        logging.exception("update_state_and_notify_user rejected")
+        return 3
     except Exception as e:
         # log the full exception and re-raise so callers (Rust/pyo3) get the traceback
         logging.exception("update_state_and_notify_user error")
         raise
 
-def update_temp_and_humidity(user, temp_f, humidity):
+#
+# On-demand capture entry point
+# -- capture_media(user, kind, seconds)
+# -- kind is 'clip' (seconds long) or 'photo'
+# -- returns the Storage path if Ok, raises if the camera is busy or capture/upload failed
+def capture_media(user, kind, seconds=5):
+    logging.info(f"capture_media called: user={user!r}, kind={kind!r}, seconds={seconds!r}")
+    _validate_user(user)
+    if kind not in ("clip", "photo"):
+        raise ValueError(f"Invalid capture kind {kind!r}: valid kinds are 'clip' and 'photo'.")
+    if not _camera_lock.acquire(blocking=False):
+        raise RuntimeError("Camera busy: another capture is in progress")
+    try:
+        filename_base = os.path.join("/tmp", f"capture-{time.strftime('%Y%m%d-%H%M%S')}")
+        if kind == "clip":
+            filename = _capture_video(filename_base, capture_time=seconds)
+            folder, content_type = "videos", "video/mp4"
+        else:
+            filename = _capture_photo(filename_base)
+            folder, content_type = "photos", "image/jpeg"
+    finally:
+        _camera_lock.release()
+
+    try:
+        return _upload_to_storage(user, filename, folder, content_type)
+    finally:
+        try:
+            os.remove(filename)
+        except Exception:
+            logging.exception("Failed to remove capture file %r", filename)
+
+#
+# Notification entry point
+# -- send_notification(user, message)
+#
+def send_notification(user, message):
+    logging.info(f"send_notification called: user={user!r}, message={message!r}")
+    _validate_user(user)
+    _ensure_firebase_app()
+    _send_fcm_message(_build_message(_firestore_read_data(user)["token"], message))
+
+#
+# Diagnostics upload entry point
+# -- upload_diagnostics(user, filename)
+#
+def upload_diagnostics(user, filename):
+    logging.info(f"upload_diagnostics called: user={user!r}, filename={filename!r}")
+    _validate_user(user)
+    return _upload_to_storage(user, filename, "diagnostics", "application/gzip")
+
+def update_temp_and_humidity(user, temp_f, humidity, timestamp=None):
   try:
     _validate_user(user)
     _validate_temp(temp_f)
     _validate_humidity(humidity)
     _ensure_firebase_app()
-    _firestore_add_data(_firestore_read_state(user)["state"], user, temp_f, humidity)
+    _firestore_add_data(_firestore_read_state(user)["state"], user, temp_f, humidity, timestamp)
+  except ValidationError as e:
+    logging.exception("update_temp_and_humidity rejected")
+    return 3
   except ValueError as e:
     return 1
   except RuntimeError as e:
@@ -340,7 +470,8 @@ def publish_temp_and_humidity(user, temp_f, humidity):
         "timestamp": time.time() # Add a timestamp for time-series analysis
     }
     data_bytes = json.dumps(message_data).encode("utf-8")
-    future = PUBSUB_PUBLISHER.publish(PUBSUB_TOPIC_PATH, data_bytes)
+    publisher = pubsub_v1.PublisherClient()
+    future = publisher.publish(publisher.topic_path(PROJECT_ID, PUBSUB_TOPIC_ID), data_bytes)
     message_id = future.result() # This will block until the message is published
     logging.info(f"Published message with ID: {message_id} to topic: {PUBSUB_TOPIC_ID}")
   except ValueError as e:
@@ -0,0 +1,524 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Local HTTP REST API, so the garage can be checked from the LAN when the
+// internet (or Firebase) is down.
+//
+// Enabled by SENSOR_API_BIND (e.g. 0.0.0.0:8080). GETs are open on the LAN,
+// POSTs need "Authorization: Bearer $SENSOR_API_TOKEN" and are refused when
+// no token is configured.
+//
+//   GET  /api/v1/status              door state, latest reading (and age) per sensor
+//   GET  /api/v1/health              sensor health stats
+//   GET  /api/v1/events?limit=N      recent bus events
+//   GET  /api/v1/history?from=&to=&sensor=&resolution=raw|hourly
+//   POST /api/v1/commands/refresh    refresh command (notifies the user)
+//   POST /api/v1/commands/status     status command
+//   GET  /api/v1/stream?since=ID     live events (SSE), replayed after ID or Last-Event-ID
+//   GET  /api/v1/ws?since=ID         same events over a WebSocket
+//   GET  /metrics                    Prometheus metrics (also on SENSOR_METRICS_BIND)
+//   GET  /                           dashboard (dashboard.html, built into the binary)
+//
+use crate::events::{EventBus, SensorEvent, State, now_timestamp};
+use crate::health::{HealthStats, SharedHealth};
+use crate::journal::{JournalEntry, JournalSubscription, SharedJournal};
+use crate::metrics;
+use crate::mode::{Mode, ModeSwitch};
+use crate::pins::DoorPin;
+use crate::protocol::{Command, CommandRequest, CommandSource};
+use crate::read_shared_state;
+use crate::store::{ClimateHourly, ClimateSample, DoorEvent, SharedStore};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State as AxumState};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+const API_EVENTS_LIMIT_DEFAULT: usize = 50;
+const API_HISTORY_HOURS_DEFAULT: f64 = 24.0;
+const API_COMMAND_DOC_ID: &str = "api";
+const DASHBOARD_HTML: &str = include_str!("dashboard.html");
+
+#[derive(Clone)]
+pub struct ApiState {
+    pub bus: EventBus,
+    pub health: SharedHealth,
+    pub store: SharedStore,
+    pub journal: SharedJournal,
+    pub door_pin: DoorPin,
+    pub modes: ModeSwitch,
+    pub token: Option<String>,
+    pub started: Instant
+}
+
+#[derive(Debug, Serialize)]
+struct ReadingStatus {
+    temp_f: Option<f32>,
+    humidity: Option<f32>,
+    timestamp: Option<f64>,
+    age_secs: Option<f64>
+}
+
+#[derive(Debug, Serialize)]
+struct StatusResponse {
+    state: State,
+    mode: Mode,
+    last_door_event_timestamp: Option<f64>,
+    sensors: BTreeMap<String, ReadingStatus>,
+    uptime_secs: u64,
+    daemon_version: &'static str,
+    timestamp: f64
+}
+
+#[derive(Debug, Deserialize)]
+struct EventsQuery {
+    limit: Option<usize>
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamQuery {
+    since: Option<u64>
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Resolution {
+    Raw,
+    Hourly
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    from: Option<f64>,
+    to: Option<f64>,
+    sensor: Option<String>,
+    resolution: Option<Resolution>
+}
+
+#[derive(Debug, Serialize)]
+struct HistoryResponse {
+    from: f64,
+    to: f64,
+    resolution: Resolution,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    climate: Option<Vec<ClimateSample>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    climate_hourly: Option<Vec<ClimateHourly>>,
+    door: Vec<DoorEvent>
+}
+
+#[derive(Debug, Serialize)]
+struct CommandResponse {
+    command_id: String,
+    command: Command,
+    r_ts: u64,
+    accepted: bool
+}
+
+// JSON error body with a status code
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+/// Serve the API on `addr` until aborted.
+pub async fn spawn_api_server(addr: SocketAddr, state: ApiState) -> std::io::Result<JoinHandle<()>> {
+    if state.token.is_none() {
+        log::warn!("SENSOR_API_TOKEN not set, API commands are disabled");
+    }
+
+    let app = router(state);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    log::info!("HTTP API listening on {}", addr);
+
+    Ok(tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            log::error!("HTTP API server failed: {:?}", e);
+        }
+    }))
+}
+
+pub fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/", get(dashboard))
+        .route("/api/v1/status", get(status))
+        .route("/api/v1/health", get(health))
+        .route("/api/v1/events", get(events))
+        .route("/api/v1/history", get(history))
+        .route("/api/v1/commands/refresh", post(command_refresh))
+        .route("/api/v1/commands/status", post(command_status))
+        .route("/api/v1/stream", get(stream))
+        .route("/api/v1/ws", get(websocket))
+        .route("/metrics", get(metrics::serve))
+        .with_state(state)
+}
+
+async fn dashboard() -> Html<&'static str> {
+    Html(DASHBOARD_HTML)
+}
+
+async fn status(AxumState(state): AxumState<ApiState>) -> Json<StatusResponse> {
+    let now = now_timestamp();
+    let health = state.health.lock().unwrap().clone();
+
+    let sensors = health.sensors.iter()
+        .map(|(id, sensor)| (id.clone(), ReadingStatus {
+            temp_f: sensor.last_temp_f,
+            humidity: sensor.last_humidity,
+            timestamp: sensor.last_good_reading_timestamp,
+            age_secs: sensor.last_good_reading_timestamp.map(|ts| now - ts)
+        }))
+        .collect();
+
+    Json(StatusResponse {
+        state: read_shared_state(&state.door_pin),
+        mode: state.modes.current(),
+        last_door_event_timestamp: health.last_door_event_timestamp,
+        sensors,
+        uptime_secs: state.started.elapsed().as_secs(),
+        daemon_version: env!("CARGO_PKG_VERSION"),
+        timestamp: now
+    })
+}
+
+async fn health(AxumState(state): AxumState<ApiState>) -> Json<HealthStats> {
+    Json(state.health.lock().unwrap().clone())
+}
+
+async fn events(AxumState(state): AxumState<ApiState>, Query(query): Query<EventsQuery>) -> Json<Vec<JournalEntry>> {
+    let limit = query.limit.unwrap_or(API_EVENTS_LIMIT_DEFAULT);
+    Json(state.journal.lock().unwrap().recent(limit))
+}
+
+// door transitions, accepted samples, alerts and command results
+fn is_streamed(event: &SensorEvent) -> bool {
+    matches!(event,
+        SensorEvent::DoorChanged { .. } |
+        SensorEvent::ClimateSampled { .. } |
+        SensorEvent::AlertRaised { .. } |
+        SensorEvent::ModeChanged { .. } |
+        SensorEvent::CommandResult { .. })
+}
+
+// resume point from ?since= or the SSE Last-Event-ID header. An explicit since from another run is refused,
+// a Last-Event-ID from one (the browser reconnecting after a restart) replays this run's journal
+fn stream_subscription(journal: &SharedJournal, query: &StreamQuery, headers: &HeaderMap) -> Result<JournalSubscription, ApiError> {
+    let journal = journal.lock().unwrap();
+    if let Some(since) = query.since {
+        return journal.subscribe(Some(since)).map_err(|e| ApiError(StatusCode::CONFLICT,
+            format!("event id {} is from another run (now epoch {}), reconnect without since", e.since, e.epoch)));
+    }
+    let last_event_id = headers.get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    Ok(journal.subscribe(last_event_id).unwrap_or_else(|_| journal.subscribe_epoch()))
+}
+
+async fn stream(AxumState(state): AxumState<ApiState>, headers: HeaderMap, Query(query): Query<StreamQuery>) -> Result<Sse<impl Stream<Item = Result<SseEvent, Infallible>>>, ApiError> {
+    let subscription = stream_subscription(&state.journal, &query, &headers)?;
+
+    let events = futures_util::stream::unfold(subscription, |mut subscription| async move {
+        loop {
+            let entry = subscription.next().await?;
+            if !is_streamed(&entry.event) {
+                continue;
+            }
+            let event = SseEvent::default()
+                .id(entry.id.to_string())
+                .event(entry.event.name())
+                .json_data(&entry)
+                .unwrap_or_default();
+            return Some((Ok(event), subscription));
+        }
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+async fn websocket(AxumState(state): AxumState<ApiState>, headers: HeaderMap, Query(query): Query<StreamQuery>, upgrade: WebSocketUpgrade) -> Response {
+    let subscription = match stream_subscription(&state.journal, &query, &headers) {
+        Ok(subscription) => subscription,
+        Err(e) => return e.into_response()
+    };
+    upgrade.on_upgrade(move |socket| websocket_stream(socket, subscription))
+}
+
+async fn websocket_stream(mut socket: WebSocket, mut subscription: JournalSubscription) {
+    loop {
+        tokio::select! {
+            entry = subscription.next() => {
+                let entry = match entry {
+                    Some(entry) => entry,
+                    None => break
+                };
+                if !is_streamed(&entry.event) {
+                    continue;
+                }
+                let text = match serde_json::to_string(&entry) {
+                    Ok(text) => text,
+                    Err(_) => continue
+                };
+                if socket.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            // only watch for the client going away, nothing is accepted from it
+            message = socket.recv() => match message {
+                None | Some(Err(_)) | Some(Ok(Message::Close(_))) => break,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+}
+
+async fn history(AxumState(state): AxumState<ApiState>, Query(query): Query<HistoryQuery>) -> Result<Json<HistoryResponse>, ApiError> {
+    let to = query.to.unwrap_or_else(now_timestamp);
+    let from = query.from.unwrap_or(to - API_HISTORY_HOURS_DEFAULT * 3600.0);
+    if from > to {
+        return Err(ApiError(StatusCode::BAD_REQUEST, "from must not be after to".to_string()));
+    }
+
+    // SQLite calls block, run them on the blocking pool
+    let store = state.store.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let store = store.lock().unwrap();
+        let sensor = query.sensor.as_deref();
+        let resolution = query.resolution.unwrap_or(
+            if from >= store.raw_cutoff(now_timestamp()) { Resolution::Raw } else { Resolution::Hourly });
+
+        let (climate, climate_hourly) = match resolution {
+            Resolution::Raw => (Some(store.climate_range(sensor, from, to)?), None),
+            Resolution::Hourly => (None, Some(store.climate_hourly_range(sensor, from, to)?))
+        };
+        Ok::<_, rusqlite::Error>(HistoryResponse {
+            from,
+            to,
+            resolution,
+            climate,
+            climate_hourly,
+            door: store.door_range(from, to)?
+        })
+    }).await;
+
+    match result {
+        Ok(Ok(response)) => Ok(Json(response)),
+        Ok(Err(e)) => Err(ApiError(StatusCode::INTERNAL_SERVER_ERROR, format!("store query failed: {}", e))),
+        Err(e) => Err(ApiError(StatusCode::INTERNAL_SERVER_ERROR, format!("store query failed: {}", e)))
+    }
+}
+
+async fn command_refresh(AxumState(state): AxumState<ApiState>, headers: HeaderMap) -> Result<(StatusCode, Json<CommandResponse>), ApiError> {
+    publish_command(&state, &headers, Command::Refresh { notify: None })
+}
+
+async fn command_status(AxumState(state): AxumState<ApiState>, headers: HeaderMap) -> Result<(StatusCode, Json<CommandResponse>), ApiError> {
+    publish_command(&state, &headers, Command::Status)
+}
+
+// hand the command to the command processor, exactly as if it came from the Firestore listener
+fn publish_command(state: &ApiState, headers: &HeaderMap, command: Command) -> Result<(StatusCode, Json<CommandResponse>), ApiError> {
+    authorize(state, headers)?;
+
+    let request = CommandRequest::local(API_COMMAND_DOC_ID, command);
+    log::info!("API command received: {:?} ({})", request.command, request.command_id);
+    let response = CommandResponse {
+        command_id: request.command_id.clone(),
+        command: request.command.clone(),
+        r_ts: request.r_ts,
+        accepted: true
+    };
+    state.bus.publish(SensorEvent::CommandReceived {
+        source: CommandSource::Api,
+        doc_id: API_COMMAND_DOC_ID.to_string(),
+        request,
+        timestamp: now_timestamp()
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(response)))
+}
+
+fn authorize(state: &ApiState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let token = match &state.token {
+        Some(token) => token,
+        None => return Err(ApiError(StatusCode::FORBIDDEN, "API commands are disabled (no token configured)".to_string()))
+    };
+
+    let presented = headers.get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match presented {
+        Some(presented) if constant_time_eq(presented.as_bytes(), token.as_bytes()) => Ok(()),
+        _ => {
+            log::warn!("API command rejected: missing or invalid token");
+            Err(ApiError(StatusCode::UNAUTHORIZED, "missing or invalid token".to_string()))
+        }
+    }
+}
+
+// compare without returning early on the first differing byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::SharedHealth;
+    use crate::journal::EventJournal;
+    use crate::store::{Retention, Store};
+    use axum::body::Body;
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tower::ServiceExt;
+
+    const TOKEN: &str = "secret token";
+
+    // fresh directory per test, removed on drop
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new() -> TestDir {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!("sensor-api-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+            let _ = std::fs::remove_dir_all(&dir);
+            TestDir(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn state(dir: &TestDir, bus: &EventBus, token: Option<&str>) -> ApiState {
+        let store = Arc::new(Mutex::new(Store::open(&dir.0, Retention { raw_days: 7, hourly_days: 730 }).unwrap()));
+        ApiState {
+            bus: bus.clone(),
+            health: SharedHealth::default(),
+            modes: ModeSwitch::load(store.clone(), bus),
+            store,
+            journal: Arc::new(Mutex::new(EventJournal::new(1))),
+            door_pin: DoorPin::Simulated(Arc::new(Mutex::new(rppal::gpio::Level::Low))),
+            token: token.map(str::to_string),
+            started: Instant::now()
+        }
+    }
+
+    async fn send(state: ApiState, request: Request<Body>) -> (StatusCode, serde_json::Value) {
+        let response = router(state).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+    }
+
+    fn post(uri: &str, authorization: Option<&str>) -> Request<Body> {
+        let mut request = Request::post(uri);
+        if let Some(authorization) = authorization {
+            request = request.header("authorization", authorization);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn commands_are_refused_without_a_configured_token() {
+        let (dir, bus) = (TestDir::new(), EventBus::new());
+        let (status, body) = send(state(&dir, &bus, None), post("/api/v1/commands/refresh", Some("Bearer anything"))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(body["error"].as_str().unwrap().contains("disabled"));
+    }
+
+    #[tokio::test]
+    async fn commands_need_the_bearer_token() {
+        let (dir, bus) = (TestDir::new(), EventBus::new());
+        for authorization in [None, Some("Bearer wrong"), Some(TOKEN), Some("Basic secret token")] {
+            let (status, _) = send(state(&dir, &bus, Some(TOKEN)), post("/api/v1/commands/status", authorization)).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{:?}", authorization);
+        }
+    }
+
+    #[tokio::test]
+    async fn authorized_command_is_published() {
+        let (dir, bus) = (TestDir::new(), EventBus::new());
+        let mut events = bus.subscribe("test");
+
+        let (status, body) = send(state(&dir, &bus, Some(TOKEN)), post("/api/v1/commands/refresh", Some("Bearer secret token"))).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body["accepted"], true);
+        assert_eq!(body["command"]["type"], "refresh");
+
+        match events.recv().await {
+            Some(SensorEvent::CommandReceived { source, doc_id, request, .. }) => {
+                assert_eq!((source, doc_id.as_str()), (CommandSource::Api, API_COMMAND_DOC_ID));
+                assert_eq!(request.command, Command::Refresh { notify: None });
+                assert_eq!(body["command_id"], request.command_id);
+            }
+            other => panic!("unexpected event {:?}", other.map(|event| event.name()))
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_by_path_and_method() {
+        let (dir, bus) = (TestDir::new(), EventBus::new());
+        let (status, body) = send(state(&dir, &bus, None), get("/api/v1/status")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["state"].is_string() && body["mode"].is_string());
+
+        let (status, _) = send(state(&dir, &bus, None), get("/api/v1/events?limit=5")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(state(&dir, &bus, None), get("/api/v1/commands/refresh")).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        let (status, _) = send(state(&dir, &bus, None), get("/api/v1/nothing")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn history_parses_its_query() {
+        let (dir, bus) = (TestDir::new(), EventBus::new());
+        let state = state(&dir, &bus, None);
+        {
+            let store = state.store.lock().unwrap();
+            store.insert_climate("a", 70.0, 50.0, 100.0).unwrap();
+            store.insert_climate("b", 71.0, 51.0, 200.0).unwrap();
+        }
+
+        let (status, body) = send(state.clone(), get("/api/v1/history?from=0&to=1000&sensor=a&resolution=raw")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["resolution"], "raw");
+        assert_eq!(body["climate"].as_array().unwrap().len(), 1);
+        assert!(body.get("climate_hourly").is_none());
+
+        let (status, body) = send(state.clone(), get("/api/v1/history?from=0&to=1000&resolution=hourly")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["climate_hourly"].is_array());
+
+        let (status, body) = send(state.clone(), get("/api/v1/history?from=1000&to=0")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "from must not be after to");
+        let (status, _) = send(state.clone(), get("/api/v1/history?resolution=weekly")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = send(state, get("/api/v1/history?from=yesterday")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
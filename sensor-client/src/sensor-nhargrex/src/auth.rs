@@ -0,0 +1,212 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Authentication and permissions for Firestore commands.
+//
+// The issuer of a command (its "issuer" field, else the document id, which is
+// the owner's user id) must be on the allow-list, SENSOR_COMMAND_ISSUERS
+// ("<uid>[:admin],..."), by default only the device user, with the admin role
+// so the owner keeps every command they had before the allow-list.
+// Destructive and configuration commands (reboot, restart, set_mode) and
+// diagnostics, which expose logs and configuration, need the admin role.
+//
+// With a device secret (SENSOR_COMMAND_SECRET) the "sig" field is checked:
+// hex HMAC-SHA256 over "<command id>\n<issuer>\n<r_ts>\n<command>", the
+// issuer as above and the command in its canonical JSON form, e.g.
+// {"type":"refresh","notify":false} or {"type":"reboot"}, so a signed command
+// can't be passed off as another issuer's.
+// SENSOR_COMMAND_REQUIRE_SIGNATURE=1 also rejects unsigned commands.
+//
+// The issuer field itself is checked by the security rules (firestore.rules):
+// users can only write commands naming themselves.
+//
+// HTTP API and MQTT commands are authorized by their transport (API token,
+// local network) and are limited to refresh and status.
+//
+use crate::protocol::{Command, CommandRequest};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    User,
+    Admin
+}
+
+impl Role {
+    pub fn parse(s: &str) -> Option<Role> {
+        match s {
+            "user" => Some(Role::User),
+            "admin" => Some(Role::Admin),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Admin => "admin"
+        }
+    }
+}
+
+/// Role an issuer needs to run `command`.
+pub fn required_role(command: &Command) -> Role {
+    match command {
+        Command::Refresh { .. } | Command::Status | Command::Capture { .. } | Command::HealthSummary => Role::User,
+        Command::Reboot { .. } | Command::Restart { .. } | Command::SetMode { .. } | Command::Diagnostics { .. } => Role::Admin
+    }
+}
+
+pub struct CommandAuth {
+    issuers: HashMap<String, Role>,
+    secret: Option<Vec<u8>>,
+    require_signature: bool
+}
+
+impl CommandAuth {
+    pub fn new(issuers: HashMap<String, Role>, secret: Option<Vec<u8>>, require_signature: bool) -> CommandAuth {
+        if require_signature && secret.is_none() {
+            log::warn!("Command signatures required but no device secret configured, every command will be rejected");
+        }
+        CommandAuth { issuers, secret, require_signature }
+    }
+
+    /// Allow-list used without SENSOR_COMMAND_ISSUERS: the device user (the owner) as admin.
+    pub fn default_issuers(user: &str) -> String {
+        format!("{}:{}", user, Role::Admin.as_str())
+    }
+
+    /// Parse an allow-list, e.g. "uidA:admin,uidB" (the role defaults to user).
+    pub fn parse_issuers(s: &str) -> HashMap<String, Role> {
+        let mut issuers = HashMap::new();
+        for entry in s.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (uid, role) = entry.split_once(':').unwrap_or((entry, "user"));
+            match Role::parse(role.trim()) {
+                Some(role) => {
+                    issuers.insert(uid.trim().to_string(), role);
+                }
+                None => log::warn!("Command issuer {} ignored, unknown role {:?}", uid, role)
+            }
+        }
+        issuers
+    }
+
+    /// Check who issued `request` (written to sensorsRefreshRequest/`doc_id`), returning their role
+    /// or why the command is rejected.
+    pub fn authorize(&self, doc_id: &str, request: &CommandRequest) -> Result<Role, String> {
+        let issuer = request.issuer.as_deref().unwrap_or(doc_id);
+        let role = *self.issuers.get(issuer).ok_or_else(|| format!("issuer {} is not allowed", issuer))?;
+        let required = required_role(&request.command);
+        if role < required {
+            return Err(format!("{} requires the {} role, issuer {} has {}", request.command.name(), required.as_str(), issuer, role.as_str()));
+        }
+
+        match (&request.signature, &self.secret) {
+            (Some(signature), Some(secret)) => verify(secret, issuer, request, signature)?,
+            (Some(_), None) if self.require_signature => return Err(String::from("signature can't be verified, no device secret configured")),
+            (None, _) if self.require_signature => return Err(String::from("missing signature")),
+            _ => {}
+        }
+        Ok(role)
+    }
+}
+
+// "<command id>\n<issuer>\n<r_ts>\n<canonical command JSON>"
+fn signed_message(issuer: &str, request: &CommandRequest) -> String {
+    let command = serde_json::to_string(&request.command).unwrap_or_default();
+    format!("{}\n{}\n{}\n{}", request.command_id, issuer, request.r_ts, command)
+}
+
+fn verify(secret: &[u8], issuer: &str, request: &CommandRequest, signature: &str) -> Result<(), String> {
+    let signature = hex::decode(signature.trim()).map_err(|_| String::from("malformed signature"))?;
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(signed_message(issuer, request).as_bytes());
+    // constant time comparison
+    mac.verify_slice(&signature).map_err(|_| String::from("invalid signature"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"device secret";
+
+    fn auth(require_signature: bool) -> CommandAuth {
+        CommandAuth::new(CommandAuth::parse_issuers("alice:admin,mallory:admin,bob"), Some(SECRET.to_vec()), require_signature)
+    }
+
+    fn request(issuer: Option<&str>, command: Command) -> CommandRequest {
+        CommandRequest {
+            command_id: String::from("reboot-1"),
+            version: 2,
+            r_ts: 1_700_000_000,
+            command,
+            update_time: None,
+            issuer: issuer.map(str::to_string),
+            signature: None
+        }
+    }
+
+    fn sign(issuer: &str, request: &CommandRequest) -> String {
+        let mut mac = HmacSha256::new_from_slice(SECRET).unwrap();
+        mac.update(signed_message(issuer, request).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn signed_message_is_canonical() {
+        let request = request(Some("alice"), Command::Reboot { confirm: None });
+        assert_eq!(signed_message("alice", &request), "reboot-1\nalice\n1700000000\n{\"type\":\"reboot\"}");
+    }
+
+    #[test]
+    fn accepts_signature_by_issuer() {
+        let mut request = request(Some("alice"), Command::Reboot { confirm: None });
+        request.signature = Some(sign("alice", &request));
+        assert_eq!(auth(true).authorize("owner", &request), Ok(Role::Admin));
+    }
+
+    #[test]
+    fn rejects_signature_when_issuer_is_tampered_with() {
+        let mut request = request(Some("alice"), Command::Reboot { confirm: None });
+        request.signature = Some(sign("alice", &request));
+        request.issuer = Some(String::from("mallory"));
+        assert_eq!(auth(true).authorize("owner", &request), Err(String::from("invalid signature")));
+    }
+
+    #[test]
+    fn document_id_is_the_signed_issuer_without_an_issuer_field() {
+        let mut request = request(None, Command::Reboot { confirm: None });
+        request.signature = Some(sign("alice", &request));
+        assert_eq!(auth(true).authorize("alice", &request), Ok(Role::Admin));
+        assert_eq!(auth(true).authorize("mallory", &request), Err(String::from("invalid signature")));
+    }
+
+    #[test]
+    fn rejects_missing_signature_when_required() {
+        let request = request(Some("alice"), Command::Status);
+        assert_eq!(auth(true).authorize("owner", &request), Err(String::from("missing signature")));
+        assert_eq!(auth(false).authorize("owner", &request), Ok(Role::Admin));
+    }
+
+    #[test]
+    fn owner_is_admin_by_default() {
+        let auth = CommandAuth::new(CommandAuth::parse_issuers(&CommandAuth::default_issuers("owner")), None, false);
+        let reboot = request(None, Command::Reboot { confirm: None });
+        assert_eq!(auth.authorize("owner", &reboot), Ok(Role::Admin));
+        assert_eq!(auth.authorize("bob", &reboot), Err(String::from("issuer bob is not allowed")));
+    }
+
+    #[test]
+    fn rejects_admin_commands_from_users() {
+        let restart = request(Some("bob"), Command::Restart { confirm: None });
+        assert!(auth(false).authorize("owner", &restart).unwrap_err().contains("requires the admin role"));
+        let status = request(Some("eve"), Command::Status);
+        assert_eq!(auth(false).authorize("owner", &status), Err(String::from("issuer eve is not allowed")));
+    }
+}
@@ -0,0 +1,134 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// On-demand camera capture (capture command): a clip of N seconds or a still
+// photo, recorded and uploaded to Storage by the Python side, which returns
+// the storage path.
+//
+// Only one capture runs at a time and a new one may only start
+// SENSOR_CAPTURE_MIN_INTERVAL_SECS (default 60) after the last one started;
+// anything else is rejected. The Python side also refuses to capture while
+// the door-open clip is recording (the camera is shared).
+//
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const CAPTURE_MIN_INTERVAL_SECS_DEFAULT: u64 = 60;
+pub const CAPTURE_CLIP_SECONDS_DEFAULT: u32 = 5;
+pub const CAPTURE_CLIP_SECONDS_MAX: u32 = 30;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureKind {
+    #[default]
+    Clip,
+    Photo
+}
+
+impl CaptureKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CaptureKind::Clip => "clip",
+            CaptureKind::Photo => "photo"
+        }
+    }
+}
+
+/// Allows one capture at a time, at most one per min_interval.
+#[derive(Clone)]
+pub struct CaptureLimiter {
+    in_progress: Arc<AtomicBool>,
+    last_started: Arc<Mutex<Option<Instant>>>,
+    min_interval: Duration
+}
+
+/// A running capture, the limiter is free again once dropped.
+pub struct CaptureTicket {
+    in_progress: Arc<AtomicBool>,
+    /// Clip length (0 for a photo).
+    pub seconds: u32
+}
+
+impl Drop for CaptureTicket {
+    fn drop(&mut self) {
+        self.in_progress.store(false, Ordering::Release);
+    }
+}
+
+impl CaptureLimiter {
+    pub fn new(min_interval: Duration) -> CaptureLimiter {
+        CaptureLimiter {
+            in_progress: Arc::new(AtomicBool::new(false)),
+            last_started: Arc::new(Mutex::new(None)),
+            min_interval
+        }
+    }
+
+    /// Start a capture of `kind` (`seconds` long for a clip), or say why it can't run now.
+    pub fn try_start(&self, kind: CaptureKind, seconds: Option<u32>) -> Result<CaptureTicket, String> {
+        let seconds = match kind {
+            CaptureKind::Clip => match seconds.unwrap_or(CAPTURE_CLIP_SECONDS_DEFAULT) {
+                seconds @ 1..=CAPTURE_CLIP_SECONDS_MAX => seconds,
+                seconds => return Err(format!("clip length {}s not in 1..={}s", seconds, CAPTURE_CLIP_SECONDS_MAX))
+            },
+            CaptureKind::Photo => 0
+        };
+
+        let mut last_started = self.last_started.lock().unwrap();
+        if let Some(last) = *last_started {
+            let since = last.elapsed();
+            if since < self.min_interval {
+                return Err(format!("rate limited, next capture in {}s", (self.min_interval - since).as_secs() + 1));
+            }
+        }
+        if self.in_progress.swap(true, Ordering::AcqRel) {
+            return Err(String::from("another capture is in progress"));
+        }
+        *last_started = Some(Instant::now());
+        Ok(CaptureTicket { in_progress: self.in_progress.clone(), seconds })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clip_length_is_bounded() {
+        let limiter = CaptureLimiter::new(Duration::ZERO);
+        assert_eq!(limiter.try_start(CaptureKind::Clip, None).unwrap().seconds, CAPTURE_CLIP_SECONDS_DEFAULT);
+        assert_eq!(limiter.try_start(CaptureKind::Clip, Some(1)).unwrap().seconds, 1);
+        assert_eq!(limiter.try_start(CaptureKind::Clip, Some(CAPTURE_CLIP_SECONDS_MAX)).unwrap().seconds, CAPTURE_CLIP_SECONDS_MAX);
+        assert!(limiter.try_start(CaptureKind::Clip, Some(0)).is_err());
+        assert!(limiter.try_start(CaptureKind::Clip, Some(CAPTURE_CLIP_SECONDS_MAX + 1)).is_err());
+        // a photo has no length, whatever is asked
+        assert_eq!(limiter.try_start(CaptureKind::Photo, Some(0)).unwrap().seconds, 0);
+    }
+
+    #[test]
+    fn one_capture_at_a_time_until_the_ticket_is_dropped() {
+        let limiter = CaptureLimiter::new(Duration::ZERO);
+        let ticket = limiter.try_start(CaptureKind::Clip, None).unwrap();
+        let err = limiter.clone().try_start(CaptureKind::Photo, None).err().unwrap();
+        assert!(err.contains("in progress"), "{}", err);
+
+        drop(ticket);
+        assert!(limiter.try_start(CaptureKind::Photo, None).is_ok());
+    }
+
+    #[test]
+    fn captures_are_rate_limited() {
+        let limiter = CaptureLimiter::new(Duration::from_secs(60));
+        drop(limiter.try_start(CaptureKind::Photo, None).unwrap());
+        let err = limiter.try_start(CaptureKind::Photo, None).err().unwrap();
+        assert!(err.starts_with("rate limited"), "{}", err);
+
+        // a rejected clip length doesn't count as a capture
+        let limiter = CaptureLimiter::new(Duration::from_secs(60));
+        assert!(limiter.try_start(CaptureKind::Clip, Some(0)).is_err());
+        assert!(limiter.try_start(CaptureKind::Clip, None).is_ok());
+    }
+}
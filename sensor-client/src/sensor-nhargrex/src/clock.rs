@@ -0,0 +1,58 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Device clock skew against Firestore server time.
+//
+// Each heartbeat write stamps server_timestamp with the server's request time,
+// which falls between the device times just before and after the write. The
+// skew is measured against the midpoint (like NTP), uncertain by half of the
+// round trip. Command freshness is judged on server time (device time plus
+// skew) when the command was received against the command document's update
+// time, so neither a skewed phone (r_ts) nor a skewed Pi makes commands look
+// stale or from the future, and neither does waiting behind a slow command.
+//
+use crate::metrics;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ClockSkew {
+    /// Server time minus device time.
+    pub skew_secs: f64,
+    /// Half of the round trip it was measured over.
+    pub uncertainty_secs: f64,
+    /// Device time of the measurement.
+    pub measured_at: f64
+}
+
+impl ClockSkew {
+    /// Skew from a server timestamp set during a call made between device times `before` and `after`.
+    pub fn measure(server_time: f64, before: f64, after: f64) -> ClockSkew {
+        ClockSkew {
+            skew_secs: server_time - (before + after) / 2.0,
+            uncertainty_secs: (after - before).max(0.0) / 2.0,
+            measured_at: after
+        }
+    }
+}
+
+/// Last measured skew, shared by the heartbeat (measuring) and the command processor.
+#[derive(Clone, Default)]
+pub struct ServerClock(Arc<Mutex<Option<ClockSkew>>>);
+
+impl ServerClock {
+    pub fn record(&self, skew: ClockSkew) {
+        metrics::CLOCK_SKEW.set(skew.skew_secs);
+        *self.0.lock().unwrap() = Some(skew);
+    }
+
+    pub fn skew(&self) -> Option<ClockSkew> {
+        *self.0.lock().unwrap()
+    }
+
+    /// Estimated server time at device time `timestamp` (unchanged if no skew was measured yet).
+    pub fn at(&self, timestamp: f64) -> f64 {
+        timestamp + self.skew().map_or(0.0, |skew| skew.skew_secs)
+    }
+}
@@ -0,0 +1,478 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Command processor for CommandReceived events (Firestore listener, HTTP API, MQTT),
+// publishing a CommandResult when a command is accepted and when it is done or failed.
+// Results of Firestore commands are written to sensorsCommandResult/{doc id}.
+//
+// Firestore commands are fresh when their document was written (server update
+// time) within the last 15s of estimated server time, see clock.rs.
+// Firestore commands must pass CommandAuth (issuer allow-list, role, signature)
+// before they run. Fresh commands are recorded in the command ledger (local store) before they
+// run, so a replayed document revision is skipped. A reboot also needs a
+// command id that was never used before, and is refused if the ledger can't
+// be written. Captures are limited by CaptureLimiter (one at a time, rate limited).
+// Diagnostics bundles are collected locally and uploaded to Storage. Restarts
+// and reboots go through PowerGuard (daily limit, confirmation token). Scheduled
+// commands (scheduler.rs) come in like API and MQTT ones.
+//
+// Commands are processed from startup, before the cloud is reachable; status
+// fails and going_down results are not written until Firestore is connected.
+//
+// See protocol.rs for the command documents.
+//
+use crate::auth::CommandAuth;
+use crate::capture::{CaptureLimiter, CaptureTicket};
+use crate::clock::ServerClock;
+use crate::connectivity::CloudReady;
+use crate::diagnostics::{Diagnostics, DIAGNOSTICS_LOG_LINES_DEFAULT, DIAGNOSTICS_LOG_LINES_MAX};
+use crate::dht22::Reading;
+use crate::events::{CorrelationId, EventBus, SensorEvent, now_timestamp};
+use crate::logging::correlation_span;
+use crate::metrics;
+use crate::mode::ModeSwitch;
+use crate::pins::{DoorPin, TempPin};
+use crate::power::{PowerAction, PowerCheck, PowerGuard};
+use crate::protocol::{Command, CommandRequest, CommandResultObject, CommandSource, CommandStatus, parse_command_document, result_user, SENSORS_COMMAND_RESULT_COLLECTION};
+use crate::store::{CommandClaim, SharedStore};
+use crate::{SensorObject, SENSORS_COLLECTION, REFRESH_REQUEST_TIMEWINDOW_SECONDS};
+use crate::{read_shared_state, read_dht22_once, read_dht22_with_retry, reboot, restart_daemon, capture_media, upload_diagnostics, notify_user};
+use firestore::*;
+use firestore::gcloud_sdk::google::firestore::v1::Document;
+use serde_json::json;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+use tokio::time::Instant;
+use anyhow::Result;
+
+// how far in the (estimated server time) future a command may be written
+const COMMAND_CLOCK_TOLERANCE_SECS: f64 = 2.0;
+
+/// Publish a sensorsRefreshRequest document as CommandReceived, or its rejection as a CommandResult.
+pub fn publish_command_document(bus: &EventBus, doc_id: String, doc: &Document) {
+    match parse_command_document(doc) {
+        Ok(request) => {
+            log::info!("Received command {} ({}, v{}) r_ts={}", request.command.name(), request.command_id, request.version, request.r_ts);
+            bus.publish(SensorEvent::CommandReceived { source: CommandSource::Firestore, doc_id, request, timestamp: now_timestamp() });
+        }
+        Err(rejection) => {
+            log::warn!("Rejected command document {} ({}): {}", doc_id, rejection.command_id, rejection.error);
+            bus.publish(SensorEvent::CommandResult {
+                source: CommandSource::Firestore,
+                doc_id,
+                issuer: rejection.issuer,
+                command_id: rejection.command_id,
+                version: rejection.version,
+                command: rejection.command,
+                status: CommandStatus::Rejected,
+                error: Some(rejection.error),
+                payload: None,
+                timestamp: now_timestamp()
+            });
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_command_processor(bus: &EventBus, cloud: CloudReady, store: SharedStore, auth: CommandAuth, clock: ServerClock, capture: CaptureLimiter, power: PowerGuard, modes: ModeSwitch, diagnostics: Diagnostics, user: String, door_pin: DoorPin, temp_pin: TempPin) -> JoinHandle<()> {
+    let mut events = bus.subscribe_lossless("commands");
+    let bus = bus.clone();
+
+    tokio::spawn(async move {
+        log::info!("Command processor started");
+        while let Some((correlation_id, event)) = events.recv_correlated().await {
+            let (source, doc_id, request, received) = match event {
+                SensorEvent::CommandReceived { source, doc_id, request, timestamp } => (source, doc_id, request, timestamp),
+                SensorEvent::ShutdownRequested { .. } => break,
+                _ => continue
+            };
+            handle_command(&bus, &cloud, &store, &auth, &clock, &capture, &power, &modes, &diagnostics, &user, &door_pin, &temp_pin, source, doc_id, request, received, correlation_id)
+                .instrument(correlation_span("command", correlation_id))
+                .await;
+        }
+        log::info!("Command processor exiting");
+    })
+}
+
+// record `request` in the command ledger (SQLite calls block, run on the blocking pool)
+async fn claim_command(store: &SharedStore, doc_id: &str, request: &CommandRequest) -> Result<CommandClaim> {
+    let store = store.clone();
+    let (doc_id, revision, command_id, command) =
+        (doc_id.to_string(), request.revision(), request.command_id.clone(), request.command.name());
+    let claim = tokio::task::spawn_blocking(move || {
+        store.lock().unwrap().claim_command(&doc_id, &revision, &command_id, command, now_timestamp())
+    }).await??;
+    Ok(claim)
+}
+
+// age of a Firestore command in server time when it was received at device time `received`
+// (None for local commands, issued just now), however long it then waited for earlier commands
+fn command_age(source: CommandSource, request: &CommandRequest, clock: &ServerClock, received: f64) -> Option<f64> {
+    if source != CommandSource::Firestore {
+        return None;
+    }
+    let server_received = clock.at(received);
+    match request.update_time {
+        Some(update_time) => {
+            let written = update_time as f64 / 1e9;
+            log::info!("Command {} received {:.1}s after it was written (server time), {:.1}s ago, issuer clock {:+.1}s",
+                request.command_id, server_received - written, now_timestamp() - received, request.r_ts as f64 - written);
+            Some(server_received - written)
+        }
+        // no update time, fall back to the issuer's r_ts
+        None => Some(server_received - request.r_ts as f64)
+    }
+}
+
+// check freshness, authorization and the ledger, run the command and publish its results
+#[allow(clippy::too_many_arguments)]
+async fn handle_command(bus: &EventBus, cloud: &CloudReady, store: &SharedStore, auth: &CommandAuth, clock: &ServerClock, capture: &CaptureLimiter, power: &PowerGuard, modes: &ModeSwitch, diagnostics: &Diagnostics, user: &str, door_pin: &DoorPin, temp_pin: &TempPin, source: CommandSource, doc_id: String, request: CommandRequest, received: f64, correlation_id: CorrelationId) {
+    let publish_result = |status: CommandStatus, error: Option<String>, payload: Option<serde_json::Value>| {
+        bus.publish_correlated(correlation_id, SensorEvent::CommandResult {
+            source,
+            doc_id: doc_id.clone(),
+            issuer: request.issuer.clone(),
+            command_id: request.command_id.clone(),
+            version: request.version,
+            command: Some(request.command.name().to_string()),
+            status,
+            error,
+            payload,
+            timestamp: now_timestamp()
+        });
+    };
+
+    // only process the change if it was written recently
+    // (older ones are mostly the listener replaying the last command on connect)
+    if let Some(age) = command_age(source, &request, clock, received) {
+        let max_future = COMMAND_CLOCK_TOLERANCE_SECS + clock.skew().map_or(0.0, |skew| skew.uncertainty_secs);
+        if age < -max_future || age > -REFRESH_REQUEST_TIMEWINDOW_SECONDS as f64 {
+            // claimed too, so a replay of a processed revision stays quiet and this one expires once
+            match claim_command(store, &doc_id, &request).await {
+                Ok(CommandClaim::Replay) => log::info!("Command {} already processed, replay ignored (age {:.1}s)", request.command_id, age),
+                claim => {
+                    if let Err(e) = claim {
+                        log::warn!("Command ledger unavailable, expiring {} anyway: {:?}", request.command_id, e);
+                    }
+                    log::info!("Command {} expired, outside the {}s window (age {:.1}s)", request.command_id, -REFRESH_REQUEST_TIMEWINDOW_SECONDS, age);
+                    let error = if age < 0.0 {
+                        format!("written {:.0}s in the future (server time), check the issuer's clock", -age)
+                    } else {
+                        format!("received {:.0}s after it was written, outside the {}s window", age, -REFRESH_REQUEST_TIMEWINDOW_SECONDS)
+                    };
+                    publish_result(CommandStatus::Expired, Some(error), None);
+                }
+            }
+            return;
+        }
+    }
+
+    if source == CommandSource::Firestore {
+        match auth.authorize(&doc_id, &request) {
+            Ok(role) => log::info!("Command {} ({}) authorized, role {}", request.command.name(), request.command_id, role.as_str()),
+            Err(error) => {
+                log::warn!("Command {} ({}) from {} rejected: {}", request.command.name(), request.command_id,
+                    request.issuer.as_deref().unwrap_or(&doc_id), error);
+                publish_result(CommandStatus::Rejected, Some(error), None);
+                return;
+            }
+        }
+    }
+
+    // exactly once: skip a document revision that was already processed
+    let power_action = request.command.power_action();
+    match claim_command(store, &doc_id, &request).await {
+        Ok(CommandClaim::Replay) => {
+            log::info!("Command {} ({}) already processed, replay ignored", request.command.name(), request.command_id);
+            return;
+        }
+        Ok(CommandClaim::ReusedId) if power_action.is_some() => {
+            log::warn!("{} {} rejected, command id already used", request.command.name(), request.command_id);
+            publish_result(CommandStatus::Rejected, Some(format!("{} requires a unique command id, {} was already used", request.command.name(), request.command_id)), None);
+            return;
+        }
+        Ok(_) => {}
+        Err(e) if power_action.is_some() => {
+            log::error!("{} {} rejected, command ledger unavailable: {:?}", request.command.name(), request.command_id, e);
+            publish_result(CommandStatus::Rejected, Some(format!("command ledger unavailable: {}", e)), None);
+            return;
+        }
+        Err(e) => log::warn!("Command ledger unavailable, running {} ({}) anyway: {:?}", request.command.name(), request.command_id, e)
+    }
+
+    // held until the capture is done
+    let capture_ticket = match request.command {
+        Command::Capture { kind, seconds } => match capture.try_start(kind, seconds) {
+            Ok(ticket) => Some(ticket),
+            Err(error) => {
+                log::warn!("Capture {} rejected: {}", request.command_id, error);
+                publish_result(CommandStatus::Rejected, Some(error), None);
+                return;
+            }
+        },
+        _ => None
+    };
+
+    // restart and reboot: within the daily limit and confirmed, recorded and announced (going_down) first
+    let power_record = match power_action {
+        Some((action, confirm)) => match prepare_power_action(cloud, power, &doc_id, &request, action, confirm.map(str::to_string)).await {
+            Ok(id) => Some(id),
+            Err((error, payload)) => {
+                log::warn!("{} {} rejected: {}", request.command.name(), request.command_id, error);
+                publish_result(CommandStatus::Rejected, Some(error), payload);
+                return;
+            }
+        },
+        None => None
+    };
+
+    metrics::COMMANDS.with_label_values(&[request.command.name()]).inc();
+    if power_record.is_none() {
+        publish_result(CommandStatus::Accepted, None, None);
+    }
+    match process_command(bus, cloud, user, door_pin, temp_pin, modes, diagnostics, source, &request, capture_ticket.as_ref(), correlation_id).await {
+        // completed on the next start
+        Ok(_) if power_record.is_some() => log::info!("{} {} going down", request.command.name(), request.command_id),
+        Ok(payload) => publish_result(CommandStatus::Done, None, payload),
+        Err(error) => {
+            log::error!("Command {} ({}) failed: {:?}", request.command.name(), request.command_id, error);
+            if let Some(id) = power_record {
+                let power = power.clone();
+                match tokio::task::spawn_blocking(move || power.fail(id)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => log::error!("Failed to record {} {} as failed: {:?}", request.command.name(), request.command_id, e),
+                    Err(e) => log::error!("Failed to record {} {} as failed: {:?}", request.command.name(), request.command_id, e)
+                }
+            }
+            publish_result(CommandStatus::Failed, Some(error.to_string()), None);
+        }
+    }
+}
+
+// check a restart or reboot against the daily limit and its confirmation token, then record it and write its
+// going_down result, returning the record id or why it was rejected (with a new token to confirm it)
+async fn prepare_power_action(cloud: &CloudReady, power: &PowerGuard, doc_id: &str, request: &CommandRequest, action: PowerAction, confirm: Option<String>) -> std::result::Result<i64, (String, Option<serde_json::Value>)> {
+    // SQLite calls block, run on the blocking pool
+    let (guard, record_doc_id, issuer, command_id, version) = (power.clone(), doc_id.to_string(), request.issuer.clone(), request.command_id.clone(), request.version);
+    let challenger = result_user(&record_doc_id, issuer.as_deref());
+    let id = tokio::task::spawn_blocking(move || {
+        let check = if guard.confirmation_required(version) {
+            guard.check(action, &challenger, confirm.as_deref())
+        } else {
+            guard.check_unconfirmed()
+        };
+        match check {
+            Ok(PowerCheck::Confirmed) => guard.record(action, &record_doc_id, issuer.as_deref(), &command_id, version)
+                .map_err(|e| (format!("power action log unavailable: {}", e), None)),
+            Ok(PowerCheck::ConfirmationRequired { token, expires_in_secs }) => Err((
+                format!("confirmation required, send {} again as a new command (new id) with \"confirm\": \"{}\" within {}s", action.as_str(), token, expires_in_secs),
+                Some(json!({ "confirm": token, "expires_in_secs": expires_in_secs })))),
+            Err(error) => Err((error, None))
+        }
+    }).await.map_err(|e| (format!("power guard failed: {}", e), None))??;
+
+    let db = match cloud.db() {
+        Some(db) => db,
+        None => {
+            log::warn!("{} {} going down while offline, going_down result not written", action.as_str(), request.command_id);
+            return Ok(id);
+        }
+    };
+    let timestamp = now_timestamp();
+    write_command_result(&db, doc_id, &CommandResultObject {
+        user: result_user(doc_id, request.issuer.as_deref()),
+        command_id: request.command_id.clone(),
+        v: request.version,
+        command: Some(request.command.name().to_string()),
+        status: CommandStatus::GoingDown,
+        error: None,
+        payload: Some(json!({ "action": action })),
+        timestamp,
+        completed_at: None
+    }).await;
+    Ok(id)
+}
+
+async fn write_command_result(db: &FirestoreDb, doc_id: &str, result: &CommandResultObject) {
+    let started = Instant::now();
+    let written = db.fluent()
+        .update()
+        .in_col(SENSORS_COMMAND_RESULT_COLLECTION)
+        .document_id(doc_id)
+        .object(result)
+        .execute::<()>()
+        .await;
+    metrics::observe_cloud_call("command_result", started.elapsed().as_secs_f64(), written.is_ok());
+    if let Err(e) = written {
+        log::error!("Failed to write command result {:?} for {}: {:?}", result.status, result.command_id, e);
+    }
+}
+
+/// Write the result of every Firestore command to sensorsCommandResult/{doc id}, readable by its user.
+pub fn spawn_command_result_sink(bus: &EventBus, db: FirestoreDb) -> JoinHandle<()> {
+    let mut events = bus.subscribe_lossless("command-results");
+
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            let (doc_id, result) = match event {
+                SensorEvent::CommandResult { source: CommandSource::Firestore, doc_id, issuer, command_id, version, command, status, error, payload, timestamp } => {
+                    (doc_id.clone(), CommandResultObject {
+                        user: result_user(&doc_id, issuer.as_deref()),
+                        command_id,
+                        v: version,
+                        command,
+                        status,
+                        error,
+                        payload,
+                        timestamp,
+                        completed_at: status.is_final().then_some(timestamp)
+                    })
+                }
+                SensorEvent::ShutdownRequested { .. } => break,
+                _ => continue
+            };
+            write_command_result(&db, &doc_id, &result).await;
+        }
+    })
+}
+
+// run `command`, returning its result payload
+#[allow(clippy::too_many_arguments)]
+async fn process_command(bus: &EventBus, cloud: &CloudReady, user: &str, door_pin: &DoorPin, temp_pin: &TempPin, modes: &ModeSwitch, diagnostics: &Diagnostics, source: CommandSource, request: &CommandRequest, capture: Option<&CaptureTicket>, correlation_id: CorrelationId) -> Result<Option<serde_json::Value>> {
+    match &request.command {
+        Command::Refresh { notify } => {
+            log::info!("Command: refresh");
+
+            // get gpio pin as input and read state
+            let state = read_shared_state(door_pin);
+
+            // get temp and humidity, a failed read fails the command (the cloud skips zero readings)
+            let Reading { temperature: t, humidity: h } = read_dht22_once(temp_pin)
+                .map_err(|e| anyhow::anyhow!("DHT22 read failed: {:?}", e))?;
+
+            log::info!("State {:?}, Temp: {:.2}°F, Humidity: {:.2}%", state, t, h);
+
+            // (force) update (cloud) state and notify (Android) user, delivered from the outbox
+            let force_notify = notify.unwrap_or(true);
+            bus.publish_correlated(correlation_id, SensorEvent::StateReported { state, temp_f: t, humidity: h, force_notify, timestamp: now_timestamp() });
+            Ok(Some(json!({ "state": state, "temp_f": t, "humidity": h, "queued": true, "notify": force_notify })))
+        }
+        Command::Status => {
+            log::info!("Command: status");
+
+            // read temp and humidity
+            let t : f32;
+            let h : f32;
+
+            // Use f64 to match Python's double-precision float
+            let timestamp: f64 = now_timestamp();
+
+            match read_dht22_with_retry(temp_pin).await {
+                Ok(Reading {temperature, humidity}) => {
+                    t = temperature;
+                    h = humidity;    
+                }
+                Err(_) => {
+                    t = 0.0;
+                    h = 0.0;                                                  
+                }
+            }
+
+            log::info!("Temp: {:.2}°F, Humidity: {:.2}%, Timestamp: {}", t, h, timestamp);
+
+            // Update sensor document with current status
+            let db = cloud.db().ok_or_else(|| anyhow::anyhow!("not connected to Firestore yet"))?;
+            let state = read_shared_state(door_pin);
+            let started = Instant::now();
+            let result = db.fluent()
+            .update()
+            .in_col(SENSORS_COLLECTION)
+            .document_id(user)
+            .object(&SensorObject {
+                online: true,
+                state: state.as_str().to_string(),
+                temp_f: t,
+                humidity: h,
+                timestamp
+            })
+            .execute::<()>()
+            .await;
+            metrics::observe_cloud_call("status", started.elapsed().as_secs_f64(), result.is_ok());
+            result?;
+            log::info!("Status and temperature updated to current");
+            Ok(Some(json!({ "state": state, "temp_f": t, "humidity": h, "timestamp": timestamp })))
+        }
+        Command::Restart { .. } => {
+            log::info!("Command: restart");
+
+            match restart_daemon() {
+                Ok(()) => {
+                    log::info!("Restart requested - OK");
+                    Ok(None)
+                }
+                Err(e) => Err(anyhow::anyhow!("restart failed: {}", e))
+            }
+        }
+        Command::Reboot { .. } => {
+            log::info!("Command: reboot");
+
+            match reboot() {
+                Ok(()) => {
+                    log::info!("Reboot requested - OK");
+                    Ok(None)
+                }
+                Err(e) => {
+                    log::info!("Reboot requested - Failed");
+                    Err(anyhow::anyhow!("reboot failed: {}", e))
+                }
+            }
+        }
+        Command::Capture { kind, .. } => {
+            let seconds = capture.map_or(0, |ticket| ticket.seconds);
+            log::info!("Command: capture {} ({}s)", kind.as_str(), seconds);
+
+            // records for `seconds` and uploads, off the async workers
+            let (user, kind) = (user.to_string(), *kind);
+            let path = tokio::task::spawn_blocking(move || capture_media(user, kind, seconds)).await??;
+            log::info!("Capture uploaded to {}", path);
+            Ok(Some(json!({ "kind": kind, "seconds": seconds, "path": path })))
+        }
+        Command::SetMode { mode } => {
+            log::info!("Command: set_mode {}", mode.as_str());
+
+            // audited in the local store, off the async workers
+            let audit_source = if source == CommandSource::Schedule { "schedule" } else { "command" };
+            let (switch, mode, issuer, command_id) = (modes.clone(), *mode, request.issuer.clone().unwrap_or_else(|| user.to_string()), request.command_id.clone());
+            let change = tokio::task::spawn_blocking(move || switch.set(mode, audit_source, Some(issuer), Some(command_id))).await??;
+            Ok(Some(json!({ "mode": mode, "previous": change.as_ref().map(|change| change.previous).unwrap_or(mode), "changed": change.is_some() })))
+        }
+        Command::Diagnostics { lines } => {
+            let lines = lines.unwrap_or(DIAGNOSTICS_LOG_LINES_DEFAULT).clamp(1, DIAGNOSTICS_LOG_LINES_MAX);
+            log::info!("Command: diagnostics ({} log lines)", lines);
+
+            // reads files and uploads, off the async workers
+            let (diagnostics, user) = (diagnostics.clone(), user.to_string());
+            let path = tokio::task::spawn_blocking(move || -> Result<String> {
+                let bundle = diagnostics.write_bundle(lines)?;
+                let uploaded = upload_diagnostics(user, &bundle);
+                if let Err(e) = std::fs::remove_file(&bundle) {
+                    log::warn!("Failed to remove diagnostics bundle {:?}: {:?}", bundle, e);
+                }
+                Ok(uploaded?)
+            }).await??;
+            log::info!("Diagnostics uploaded to {}", path);
+            Ok(Some(json!({ "lines": lines, "path": path })))
+        }
+        Command::HealthSummary => {
+            log::info!("Command: health_summary");
+
+            let uptime_days = diagnostics.started.elapsed().as_secs_f64() / 86400.0;
+            let message = format!("Health: {}, up {:.1} days", diagnostics.health.lock().unwrap().summary(), uptime_days);
+            let (notify_user_id, notify_message) = (user.to_string(), message.clone());
+            tokio::task::spawn_blocking(move || notify_user(notify_user_id, notify_message)).await??;
+            metrics::NOTIFICATIONS.inc();
+            Ok(Some(json!({ "message": message })))
+        }
+    }
+}
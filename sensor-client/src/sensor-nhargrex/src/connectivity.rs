@@ -0,0 +1,103 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Cloud connectivity probing at startup.
+//
+// GPIO, the local store and local command processing run from the start; the
+// cloud side connects in the background once Firestore resolves (DNS) and
+// accepts a connection, retrying with backoff. Until then the cloud sink only
+// queues updates in the outbox and commands needing Firestore fail; the gate
+// opening (with the connected Firestore) starts delivery.
+//
+use crate::systemd::Pulse;
+use firestore::FirestoreDb;
+use tokio::net::{lookup_host, TcpStream};
+use tokio::sync::watch;
+use tokio::time::{sleep, timeout, Instant, Duration};
+
+pub const FIRESTORE_ENDPOINT: &str = "firestore.googleapis.com:443";
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+const PROBE_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const PROBE_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Opened with the connected Firestore once the cloud is reachable.
+pub struct CloudGate(watch::Sender<Option<FirestoreDb>>);
+
+/// Whether the cloud is reachable yet (and its Firestore), as seen by the cloud sink and command processor.
+#[derive(Clone)]
+pub struct CloudReady(watch::Receiver<Option<FirestoreDb>>);
+
+pub fn cloud_gate() -> (CloudGate, CloudReady) {
+    let (tx, rx) = watch::channel(None);
+    (CloudGate(tx), CloudReady(rx))
+}
+
+impl CloudGate {
+    pub fn open(&self, db: FirestoreDb) {
+        self.0.send_replace(Some(db));
+    }
+}
+
+impl CloudReady {
+    pub fn is_ready(&self) -> bool {
+        self.0.borrow().is_some()
+    }
+
+    /// Firestore, None until connected.
+    pub fn db(&self) -> Option<FirestoreDb> {
+        self.0.borrow().clone()
+    }
+
+    /// Wait until the gate is opened.
+    pub async fn wait(&mut self) {
+        if self.0.wait_for(|db| db.is_some()).await.is_err() {
+            // the cloud side was abandoned (shutdown before connecting), never ready
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Firestore endpoint to probe: the emulator when FIRESTORE_EMULATOR_HOST is set.
+pub fn firestore_endpoint() -> String {
+    std::env::var("FIRESTORE_EMULATOR_HOST").unwrap_or_else(|_| FIRESTORE_ENDPOINT.to_string())
+}
+
+/// Resolve `endpoint` (host:port) and open a TCP connection to the first address.
+pub async fn probe(endpoint: &str) -> Result<(), String> {
+    let addr = timeout(PROBE_TIMEOUT, lookup_host(endpoint)).await
+        .map_err(|_| String::from("DNS lookup timed out"))?
+        .map_err(|e| format!("DNS lookup failed: {}", e))?
+        .next()
+        .ok_or_else(|| String::from("DNS lookup returned no addresses"))?;
+
+    timeout(PROBE_TIMEOUT, TcpStream::connect(addr)).await
+        .map_err(|_| format!("connect to {} timed out", addr))?
+        .map_err(|e| format!("connect to {} failed: {}", addr, e))?;
+    Ok(())
+}
+
+/// Probe `endpoint` with exponential backoff until it is reachable, returning how long it took.
+pub async fn wait_for_connectivity(endpoint: &str, pulse: &Pulse) -> Duration {
+    let started = Instant::now();
+    let mut delay = PROBE_INITIAL_DELAY;
+    let mut attempt = 1;
+
+    loop {
+        pulse.beat();
+        match probe(endpoint).await {
+            Ok(()) => {
+                let took = started.elapsed();
+                log::info!("{} reachable after {:.1}s ({} attempts)", endpoint, took.as_secs_f64(), attempt);
+                return took;
+            }
+            Err(e) => {
+                log::warn!("{} not reachable (attempt {}), retrying in {:?}: {}", endpoint, attempt, delay, e);
+            }
+        }
+
+        sleep(delay).await;
+        delay = (delay * 2).min(PROBE_MAX_DELAY);
+        attempt += 1;
+    }
+}
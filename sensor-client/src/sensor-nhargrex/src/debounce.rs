@@ -0,0 +1,239 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Stable-state door debouncer.
+//
+// A level is only emitted once the pin has had no edges for the debounce
+// window, and the emitted level is the settled pin level (not the level
+// reported with the last edge). The final edge of a burst is therefore never
+// dropped, and bursts that end where they started emit nothing.
+//
+use crate::events::{CorrelationId, EventBus, SensorEvent, State, now_timestamp};
+use crate::logging::correlation_span;
+use crate::metrics;
+use crate::pins::DoorPin;
+use rppal::gpio::{Level, Trigger};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+pub struct Debouncer {
+    window: Duration,
+    stable: Level,
+    last_edge: Option<Instant>,
+    pending_edges: u64,
+    suppressed: u64
+}
+
+impl Debouncer {
+    pub fn new(window: Duration, initial: Level) -> Debouncer {
+        Debouncer {
+            window,
+            stable: initial,
+            last_edge: None,
+            pending_edges: 0,
+            suppressed: 0
+        }
+    }
+
+    /// Record an edge seen at `at`, restarting the stability window.
+    pub fn edge(&mut self, at: Instant) {
+        self.last_edge = Some(at);
+        self.pending_edges += 1;
+    }
+
+    /// When the pending edge (if any) will have been stable for the whole window.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.last_edge.map(|at| at + self.window)
+    }
+
+    /// Settle the pending edge if the window has passed, using the current pin `level`.
+    /// Returns the new level if it differs from the last settled level.
+    pub fn poll(&mut self, now: Instant, level: Level) -> Option<Level> {
+        let deadline = self.deadline()?;
+        if now < deadline {
+            return None;
+        }
+
+        let edges = self.pending_edges;
+        self.last_edge = None;
+        self.pending_edges = 0;
+
+        if level == self.stable {
+            // bounced back to where it started, every edge was noise
+            self.suppressed += edges;
+            return None;
+        }
+
+        // one edge explains the change, the rest were bounces
+        self.suppressed += edges - 1;
+        self.stable = level;
+        Some(level)
+    }
+
+    /// Last settled level.
+    pub fn stable(&self) -> Level {
+        self.stable
+    }
+
+    /// Total edges that did not result in an emitted level.
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed
+    }
+}
+
+/// Install the door interrupt and a debounce thread that publishes settled DoorChanged events.
+pub fn spawn_door_debouncer(bus: &EventBus, door_pin: &DoorPin, window: Duration) -> Result<JoinHandle<()>, rppal::gpio::Error> {
+    let (tx, rx) = channel::<Instant>();
+    let bus = bus.clone();
+    let pin = door_pin.clone();
+
+    door_pin.set_async_interrupt(Trigger::Both, move |level| {
+        log::debug!("GPIO interrupt callback fired: level={:?}", level);
+        if let Err(e) = tx.send(Instant::now()) {
+            log::error!("Failed to send GPIO edge to debouncer: {:?}", e);
+        }
+    })?;
+
+    let handle = std::thread::spawn(move || {
+        let mut debouncer = Debouncer::new(window, pin.read());
+        log::info!("Door debouncer started: window={:?} initial={:?}", window, State::from_level(debouncer.stable()));
+
+        loop {
+            // wait for the next edge, or until the pending edge has been stable for the window
+            let received = match debouncer.deadline() {
+                Some(deadline) => rx.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            };
+
+            match received {
+                Ok(at) => debouncer.edge(at),
+                Err(RecvTimeoutError::Timeout) => {
+                    let suppressed_before = debouncer.suppressed();
+                    if let Some(level) = debouncer.poll(Instant::now(), pin.read()) {
+                        let state = State::from_level(level);
+                        // a door event starts here, its id follows the read, cloud write and notification
+                        let correlation_id = CorrelationId::new();
+                        let _span = correlation_span("door", correlation_id).entered();
+                        log::info!("Door settled {:?} (suppressed bounces so far: {})", state, debouncer.suppressed());
+                        metrics::DOOR_INTERRUPTS.with_label_values(&["debounced"]).inc();
+                        bus.publish_correlated(correlation_id, SensorEvent::DoorChanged { state, timestamp: now_timestamp() });
+                    } else {
+                        log::debug!("Door bounce suppressed (total {})", debouncer.suppressed());
+                    }
+                    metrics::DOOR_INTERRUPTS.with_label_values(&["suppressed"]).inc_by(debouncer.suppressed() - suppressed_before);
+                }
+                Err(RecvTimeoutError::Disconnected) => break
+            }
+        }
+        log::info!("Door debouncer exiting");
+    });
+
+    Ok(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(500);
+
+    fn ms(base: Instant, millis: u64) -> Instant {
+        base + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn no_edges_emits_nothing() {
+        let base = Instant::now();
+        let mut debouncer = Debouncer::new(WINDOW, Level::Low);
+
+        assert_eq!(debouncer.deadline(), None);
+        assert_eq!(debouncer.poll(ms(base, 10_000), Level::High), None);
+        assert_eq!(debouncer.suppressed(), 0);
+    }
+
+    #[test]
+    fn single_clean_edge_emits_after_window() {
+        let base = Instant::now();
+        let mut debouncer = Debouncer::new(WINDOW, Level::Low);
+
+        debouncer.edge(base);
+        assert_eq!(debouncer.poll(ms(base, 499), Level::High), None);
+        assert_eq!(debouncer.poll(ms(base, 500), Level::High), Some(Level::High));
+        assert_eq!(debouncer.stable(), Level::High);
+        assert_eq!(debouncer.suppressed(), 0);
+    }
+
+    #[test]
+    fn bouncy_open_followed_by_nothing_emits_final_state() {
+        let base = Instant::now();
+        let mut debouncer = Debouncer::new(WINDOW, Level::Low);
+
+        // open with bounces: high, low, high, low, high inside 40ms
+        for t in [0, 10, 20, 30, 40] {
+            debouncer.edge(ms(base, t));
+        }
+
+        assert_eq!(debouncer.poll(ms(base, 539), Level::High), None);
+        assert_eq!(debouncer.poll(ms(base, 540), Level::High), Some(Level::High));
+        assert_eq!(debouncer.suppressed(), 4);
+        assert_eq!(debouncer.deadline(), None);
+    }
+
+    #[test]
+    fn burst_ending_in_start_state_is_suppressed() {
+        let base = Instant::now();
+        let mut debouncer = Debouncer::new(WINDOW, Level::Low);
+
+        debouncer.edge(ms(base, 0));
+        debouncer.edge(ms(base, 15));
+
+        assert_eq!(debouncer.poll(ms(base, 600), Level::Low), None);
+        assert_eq!(debouncer.stable(), Level::Low);
+        assert_eq!(debouncer.suppressed(), 2);
+    }
+
+    #[test]
+    fn settled_level_comes_from_pin_not_edge_count() {
+        let base = Instant::now();
+        let mut debouncer = Debouncer::new(WINDOW, Level::Low);
+
+        // an even number of edges but the pin reads high (an edge was missed)
+        debouncer.edge(ms(base, 0));
+        debouncer.edge(ms(base, 5));
+
+        assert_eq!(debouncer.poll(ms(base, 505), Level::High), Some(Level::High));
+        assert_eq!(debouncer.suppressed(), 1);
+    }
+
+    #[test]
+    fn late_edge_restarts_window() {
+        let base = Instant::now();
+        let mut debouncer = Debouncer::new(WINDOW, Level::Low);
+
+        debouncer.edge(ms(base, 0));
+        debouncer.edge(ms(base, 400));
+
+        assert_eq!(debouncer.deadline(), Some(ms(base, 900)));
+        assert_eq!(debouncer.poll(ms(base, 600), Level::High), None);
+        assert_eq!(debouncer.poll(ms(base, 900), Level::High), Some(Level::High));
+    }
+
+    #[test]
+    fn open_then_close_emits_both_transitions() {
+        let base = Instant::now();
+        let mut debouncer = Debouncer::new(WINDOW, Level::Low);
+
+        debouncer.edge(ms(base, 0));
+        debouncer.edge(ms(base, 20));
+        debouncer.edge(ms(base, 30));
+        assert_eq!(debouncer.poll(ms(base, 530), Level::High), Some(Level::High));
+
+        debouncer.edge(ms(base, 5_000));
+        debouncer.edge(ms(base, 5_010));
+        debouncer.edge(ms(base, 5_012));
+        assert_eq!(debouncer.poll(ms(base, 5_512), Level::Low), Some(Level::Low));
+        assert_eq!(debouncer.suppressed(), 4);
+    }
+}
@@ -0,0 +1,220 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Diagnostics bundle (diagnostics command), so a misbehaving unit can be
+// looked at without SSH: the last N log lines, sensor health stats, the
+// configuration (secrets redacted), uptime, Pi model, CPU temperature, disk
+// free, outbox depth and the current metrics, written as gzipped JSON and
+// uploaded to Storage (diagnostics/{user}/) by the Python side.
+//
+use crate::events::now_timestamp;
+use crate::health::{HealthStats, SharedHealth};
+use crate::metrics;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use tokio::time::Instant;
+
+pub const DIAGNOSTICS_LOG_LINES_DEFAULT: usize = 200;
+pub const DIAGNOSTICS_LOG_LINES_MAX: usize = 5000;
+
+// environment variables worth reporting, and the ones whose value is a secret
+const CONFIG_PREFIXES: [&str; 4] = ["SENSOR_", "GOOGLE_", "FIRESTORE_", "PUBSUB_"];
+const SECRET_MARKERS: [&str; 3] = ["SECRET", "TOKEN", "PASSWORD"];
+
+const PI_MODEL_PATH: &str = "/proc/device-tree/model";
+const CPU_TEMP_PATH: &str = "/sys/class/thermal/thermal_zone0/temp";
+const UPTIME_PATH: &str = "/proc/uptime";
+
+/// What the bundle is collected from, shared with the command processor.
+#[derive(Clone)]
+pub struct Diagnostics {
+    pub health: SharedHealth,
+    pub log_path: PathBuf,
+    pub data_dir: PathBuf,
+    pub started: Instant
+}
+
+#[derive(Debug, Serialize)]
+struct DiskFree {
+    path: PathBuf,
+    free_bytes: u64,
+    total_bytes: u64
+}
+
+#[derive(Debug, Serialize)]
+struct DiagnosticsBundle {
+    generated_at: f64,
+    version: &'static str,
+    pi_model: Option<String>,
+    cpu_temp_c: Option<f64>,
+    system_uptime_secs: Option<f64>,
+    daemon_uptime_secs: f64,
+    disk: Option<DiskFree>,
+    outbox_depth: i64,
+    health: HealthStats,
+    config: BTreeMap<String, String>,
+    metrics: String,
+    log_path: PathBuf,
+    log_lines: Vec<String>,
+    log_error: Option<String>
+}
+
+impl Diagnostics {
+    /// Collect the bundle with the last `lines` log lines and write it, gzipped, to a temporary file,
+    /// returning its path. Reads files, call from the blocking pool.
+    pub fn write_bundle(&self, lines: usize) -> io::Result<PathBuf> {
+        let (log_lines, log_error) = match tail_log(&self.log_path, lines) {
+            Ok(log_lines) => (log_lines, None),
+            Err(e) => (Vec::new(), Some(format!("{:?}: {}", self.log_path, e)))
+        };
+        let bundle = DiagnosticsBundle {
+            generated_at: now_timestamp(),
+            version: env!("CARGO_PKG_VERSION"),
+            pi_model: read_trimmed(PI_MODEL_PATH),
+            cpu_temp_c: read_trimmed(CPU_TEMP_PATH)
+                .and_then(|millis| millis.parse::<f64>().ok())
+                .map(|millis| millis / 1000.0),
+            system_uptime_secs: read_trimmed(UPTIME_PATH)
+                .and_then(|uptime| uptime.split_whitespace().next()?.parse().ok()),
+            daemon_uptime_secs: self.started.elapsed().as_secs_f64(),
+            disk: disk_free(&self.data_dir),
+            outbox_depth: metrics::OUTBOX_DEPTH.get(),
+            health: self.health.lock().unwrap().clone(),
+            config: redacted_config(),
+            metrics: metrics::render(),
+            log_path: self.log_path.clone(),
+            log_lines,
+            log_error
+        };
+
+        let path = std::env::temp_dir().join(format!("diagnostics-{}.json.gz", chrono::Local::now().format("%Y%m%d-%H%M%S")));
+        let mut encoder = GzEncoder::new(File::create(&path)?, Compression::default());
+        serde_json::to_writer_pretty(&mut encoder, &bundle)?;
+        encoder.finish()?.flush()?;
+        Ok(path)
+    }
+}
+
+// trimmed contents of a small system file (NUL terminated on the device tree)
+fn read_trimmed(path: &str) -> Option<String> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    Some(contents.trim_matches(|c: char| c == '\0' || c.is_whitespace()).to_string())
+}
+
+// last `lines` lines of the log, continuing into the previous rotated file when the current one is short
+fn tail_log(path: &Path, lines: usize) -> io::Result<Vec<String>> {
+    let mut tail = tail_file(path, lines)?;
+    if tail.len() < lines {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(".1");
+        if let Ok(mut older) = tail_file(Path::new(&rotated), lines - tail.len()) {
+            older.extend(tail);
+            tail = older;
+        }
+    }
+    Ok(tail.into())
+}
+
+fn tail_file(path: &Path, lines: usize) -> io::Result<VecDeque<String>> {
+    let mut tail = VecDeque::with_capacity(lines);
+    for line in BufReader::new(File::open(path)?).lines() {
+        if tail.len() == lines {
+            tail.pop_front();
+        }
+        tail.push_back(line?);
+    }
+    Ok(tail)
+}
+
+// daemon configuration from the environment, values of secrets replaced
+fn redacted_config() -> BTreeMap<String, String> {
+    std::env::vars()
+        .filter(|(name, _)| CONFIG_PREFIXES.iter().any(|prefix| name.starts_with(prefix)))
+        .map(|(name, value)| {
+            let value = if SECRET_MARKERS.iter().any(|marker| name.contains(marker)) { String::from("<redacted>") } else { value };
+            (name, value)
+        })
+        .collect()
+}
+
+fn disk_free(path: &Path) -> Option<DiskFree> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: c_path is NUL terminated and stat is a valid, writable statvfs
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(DiskFree {
+        path: path.to_path_buf(),
+        free_bytes: stat.f_bavail as u64 * stat.f_frsize as u64,
+        total_bytes: stat.f_blocks as u64 * stat.f_frsize as u64
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new() -> TestDir {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!("sensor-diagnostics-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TestDir(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn numbered_lines(from: usize, to: usize) -> String {
+        (from..=to).map(|n| format!("line {}\n", n)).collect()
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        std::env::set_var("SENSOR_COMMAND_SECRET", "command-secret");
+        std::env::set_var("SENSOR_API_TOKEN", "api-token");
+        std::env::set_var("SENSOR_MQTT_PASSWORD", "mqtt-password");
+        std::env::set_var("SENSOR_DIAGNOSTICS_TEST_PLAIN", "plain");
+
+        let config = redacted_config();
+        assert_eq!(config["SENSOR_COMMAND_SECRET"], "<redacted>");
+        assert_eq!(config["SENSOR_API_TOKEN"], "<redacted>");
+        assert_eq!(config["SENSOR_MQTT_PASSWORD"], "<redacted>");
+        assert_eq!(config["SENSOR_DIAGNOSTICS_TEST_PLAIN"], "plain");
+    }
+
+    #[test]
+    fn short_log_continues_into_the_rotated_file() {
+        let dir = TestDir::new();
+        let log = dir.0.join("sensor.log");
+        std::fs::write(dir.0.join("sensor.log.1"), numbered_lines(1, 10)).unwrap();
+        std::fs::write(&log, numbered_lines(11, 13)).unwrap();
+
+        let tail = tail_log(&log, 5).unwrap();
+        assert_eq!(tail, ["line 9", "line 10", "line 11", "line 12", "line 13"]);
+
+        // long enough on its own, the rotated file is not read
+        assert_eq!(tail_log(&log, 2).unwrap(), ["line 12", "line 13"]);
+
+        // no rotated file, as many lines as there are
+        std::fs::remove_file(dir.0.join("sensor.log.1")).unwrap();
+        assert_eq!(tail_log(&log, 5).unwrap(), ["line 11", "line 12", "line 13"]);
+    }
+}
@@ -0,0 +1,87 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// `sensor-nhargrex doctor`: checks the configuration, the local store, cloud
+// connectivity and the last heartbeat (including the clock skew the daemon
+// measured), printing one line per check.
+//
+use crate::connectivity::{firestore_endpoint, probe};
+use crate::events::now_timestamp;
+use crate::heartbeat::read_heartbeat;
+use crate::store::Store;
+use crate::{config_env_var, connect_firestore, data_dir, store_retention};
+
+fn report(name: &str, result: Result<String, String>) -> bool {
+    match &result {
+        Ok(detail) => println!("ok    {:<12} {}", name, detail),
+        Err(detail) => println!("FAIL  {:<12} {}", name, detail)
+    }
+    result.is_ok()
+}
+
+/// Run every check, returning whether all passed.
+pub async fn run_doctor() -> bool {
+    let mut healthy = true;
+
+    let user = config_env_var("GOOGLE_USER_ID");
+    healthy &= report("user", user.clone());
+    let project_id = config_env_var("GOOGLE_PROJECT_ID");
+    healthy &= report("project", project_id.clone());
+    let key_file = config_env_var("GOOGLE_APPLICATION_CREDENTIALS");
+    if std::env::var("FIRESTORE_EMULATOR_HOST").is_err() {
+        healthy &= report("credentials", key_file.clone().and_then(|path| match std::fs::metadata(&path) {
+            Ok(_) => Ok(path),
+            Err(e) => Err(format!("{}: {}", path, e))
+        }));
+    }
+
+    let dir = data_dir();
+    healthy &= report("store", Store::open(&dir, store_retention())
+        .map(|_| format!("{:?}", dir))
+        .map_err(|e| format!("{:?}: {}", dir, e)));
+
+    let endpoint = firestore_endpoint();
+    let reachable = report("network", probe(&endpoint).await.map(|()| endpoint.clone()));
+    healthy &= reachable;
+
+    let (Ok(user), Ok(project_id), true) = (user, project_id, reachable) else {
+        println!("skip  firestore    (needs user, project and network)");
+        return false;
+    };
+    let db = match connect_firestore(project_id, key_file.unwrap_or_default()).await {
+        Ok(db) => db,
+        Err(e) => {
+            report("firestore", Err(e.to_string()));
+            return false;
+        }
+    };
+
+    let heartbeat = match read_heartbeat(&db, &user).await {
+        Ok(Some(heartbeat)) => heartbeat,
+        Ok(None) => {
+            report("heartbeat", Err(String::from("no heartbeat written yet")));
+            return false;
+        }
+        Err(e) => {
+            report("firestore", Err(e.to_string()));
+            return false;
+        }
+    };
+
+    // device time, corrected by the skew the daemon measured
+    let skew = heartbeat.clock_skew_secs.unwrap_or(0.0);
+    let server_now = now_timestamp() + skew;
+    let beat = heartbeat.server_time().unwrap_or(heartbeat.timestamp);
+    healthy &= report("heartbeat", if heartbeat.online {
+        Ok(format!("online, last beat {:.0}s ago", server_now - beat))
+    } else {
+        Err(format!("offline since {:.0}s ago", server_now - beat))
+    });
+    healthy &= report("clock skew", match heartbeat.clock_skew_secs {
+        Some(skew) => Ok(format!("device clock {:+.2}s (±{:.2}s) off Firestore server time",
+            -skew, heartbeat.clock_skew_uncertainty_secs.unwrap_or(0.0))),
+        None => Err(String::from("not measured yet"))
+    });
+    healthy
+}
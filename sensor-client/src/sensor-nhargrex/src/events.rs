@@ -0,0 +1,247 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Typed internal event bus.
+//
+// Inputs (door interrupt, DHT22 samplers, Firestore listener) publish
+// SensorEvents; processors and sinks (commands, cloud, alerts, telemetry)
+// subscribe independently. Each subscriber gets every event published after
+// it subscribed, so subscribe before starting the inputs.
+//
+// Most subscribers share a bounded broadcast channel and skip events when they
+// fall too far behind (logged and counted in sensor_event_bus_lagged_total).
+// Door and command handling (door worker, cloud sink, store, command processor
+// and results) subscribe losslessly instead, each with its own unbounded queue,
+// so a slow cloud or a long command never drops a door change or a command.
+//
+// Every event carries a correlation id: inputs start a new one, processors
+// publish follow-up events (readings, cloud updates, results) with the id of
+// the event they are handling.
+//
+use crate::metrics;
+use crate::mode::ModeChange;
+use crate::protocol::{CommandRequest, CommandSource, CommandStatus};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+
+const EVENT_BUS_CAPACITY: usize = 256;
+
+type BusMessage = (CorrelationId, SensorEvent);
+
+/// Id shared by an input event and everything done because of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CorrelationId(u64);
+
+impl CorrelationId {
+    /// New id, unique across restarts (start time in the high bits, a counter below).
+    pub fn new() -> CorrelationId {
+        lazy_static::lazy_static! {
+            static ref NEXT: AtomicU64 = AtomicU64::new((now_timestamp() as u64) << 20);
+        }
+        CorrelationId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for CorrelationId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:012x}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Open,
+    Closed
+}
+
+impl State {
+    pub fn from_level(level: rppal::gpio::Level) -> State {
+        if level == rppal::gpio::Level::High {
+            State::Open
+        } else {
+            State::Closed
+        }
+    }
+
+    /// Door state as stored in Firestore ("open" or "closed").
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::Open => "open",
+            State::Closed => "closed"
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    LowTemperature
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum SensorEvent {
+    /// Door sensor changed state (debounced interrupt).
+    DoorChanged { state: State, timestamp: f64 },
+
+    /// Accepted (range checked) DHT22 reading.
+    ClimateSampled { sensor_id: &'static str, temp_f: f32, humidity: f32, timestamp: f64 },
+
+    /// DHT22 read failed after any retries.
+    SensorReadFailed { sensor_id: &'static str, error: String, timestamp: f64 },
+
+    /// Door state with temp/humidity to write to the cloud, optionally forcing a notification.
+    StateReported { state: State, temp_f: f32, humidity: f32, force_notify: bool, timestamp: f64 },
+
+    /// Valid command received (Firestore listener, HTTP API or MQTT).
+    CommandReceived { source: CommandSource, doc_id: String, request: CommandRequest, timestamp: f64 },
+
+    /// Command accepted, rejected, done or failed; error is set when rejected or failed.
+    /// issuer is the issuer the command document named, if any.
+    CommandResult {
+        source: CommandSource,
+        doc_id: String,
+        issuer: Option<String>,
+        command_id: String,
+        version: u32,
+        command: Option<String>,
+        status: CommandStatus,
+        error: Option<String>,
+        payload: Option<serde_json::Value>,
+        timestamp: f64
+    },
+
+    /// Security mode changed (command or schedule).
+    ModeChanged { change: ModeChange },
+
+    /// Alert condition detected and the user notified.
+    AlertRaised { kind: AlertKind, sensor_id: &'static str, temp_f: f32, humidity: f32, timestamp: f64 },
+
+    /// Daemon is stopping, subscribers finish the events before this one and exit.
+    ShutdownRequested { reason: String, timestamp: f64 }
+}
+
+impl SensorEvent {
+    /// Variant name, as used in the serialized "type" field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SensorEvent::DoorChanged { .. } => "DoorChanged",
+            SensorEvent::ClimateSampled { .. } => "ClimateSampled",
+            SensorEvent::SensorReadFailed { .. } => "SensorReadFailed",
+            SensorEvent::StateReported { .. } => "StateReported",
+            SensorEvent::CommandReceived { .. } => "CommandReceived",
+            SensorEvent::CommandResult { .. } => "CommandResult",
+            SensorEvent::ModeChanged { .. } => "ModeChanged",
+            SensorEvent::AlertRaised { .. } => "AlertRaised",
+            SensorEvent::ShutdownRequested { .. } => "ShutdownRequested"
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<BusMessage>,
+    // queues of the lossless subscribers
+    queues: Arc<Mutex<Vec<mpsc::UnboundedSender<BusMessage>>>>
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        let (tx, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+        EventBus { tx, queues: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    /// Publish an input event, starting a new correlation id.
+    pub fn publish(&self, event: SensorEvent) -> CorrelationId {
+        let correlation_id = CorrelationId::new();
+        self.publish_correlated(correlation_id, event);
+        correlation_id
+    }
+
+    /// Publish an event caused by the one with `correlation_id`.
+    pub fn publish_correlated(&self, correlation_id: CorrelationId, event: SensorEvent) {
+        log::debug!("Event {}: {:?}", correlation_id, event);
+        // under the lock so every subscriber sees events in the same order
+        let mut queues = self.queues.lock().unwrap();
+        queues.retain(|queue| queue.send((correlation_id, event.clone())).is_ok());
+        // no subscribers is not an error, the event is simply dropped
+        let _ = self.tx.send((correlation_id, event));
+    }
+
+    /// Subscribe to every event published from now on, `name` is used in lag warnings.
+    pub fn subscribe(&self, name: &'static str) -> EventReceiver {
+        EventReceiver { name, rx: Subscription::Shared(self.tx.subscribe()) }
+    }
+
+    /// Subscribe to every event published from now on without ever skipping any (queued without bound).
+    pub fn subscribe_lossless(&self, name: &'static str) -> EventReceiver {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.queues.lock().unwrap().push(tx);
+        EventReceiver { name, rx: Subscription::Lossless(rx) }
+    }
+}
+
+enum Subscription {
+    Shared(broadcast::Receiver<BusMessage>),
+    Lossless(mpsc::UnboundedReceiver<BusMessage>)
+}
+
+// Bus subscription that ends when the bus closes, shared ones skip over lagged events (with a warning)
+pub struct EventReceiver {
+    name: &'static str,
+    rx: Subscription
+}
+
+impl EventReceiver {
+    pub async fn recv(&mut self) -> Option<SensorEvent> {
+        self.recv_correlated().await.map(|(_, event)| event)
+    }
+
+    /// Next event with its correlation id.
+    pub async fn recv_correlated(&mut self) -> Option<(CorrelationId, SensorEvent)> {
+        let rx = match &mut self.rx {
+            Subscription::Shared(rx) => rx,
+            Subscription::Lossless(rx) => return rx.recv().await
+        };
+        loop {
+            match rx.recv().await {
+                Ok(event) => return Some(event),
+                Err(RecvError::Lagged(skipped)) => lagged(self.name, skipped),
+                Err(RecvError::Closed) => return None
+            }
+        }
+    }
+
+    /// Blocking receive (with correlation id) for std::thread subscribers (must not be called from async code).
+    pub fn blocking_recv(&mut self) -> Option<(CorrelationId, SensorEvent)> {
+        let rx = match &mut self.rx {
+            Subscription::Shared(rx) => rx,
+            Subscription::Lossless(rx) => return rx.blocking_recv()
+        };
+        loop {
+            match rx.blocking_recv() {
+                Ok(event) => return Some(event),
+                Err(RecvError::Lagged(skipped)) => lagged(self.name, skipped),
+                Err(RecvError::Closed) => return None
+            }
+        }
+    }
+}
+
+fn lagged(name: &'static str, skipped: u64) {
+    log::warn!("Event bus {} lagged, skipped {} events", name, skipped);
+    metrics::EVENT_BUS_LAGGED.with_label_values(&[name]).inc_by(skipped);
+}
+
+pub fn now_timestamp() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0)
+}
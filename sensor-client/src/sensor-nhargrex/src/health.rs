@@ -0,0 +1,113 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Sensor health stats, kept up to date from the event bus.
+//
+// Components that are running but not working as they should (e.g. the
+// Firestore listener while it is being recreated) are listed in degraded,
+// with the reason, until they recover.
+//
+use crate::events::{EventBus, SensorEvent, State};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use tokio::task::JoinHandle;
+
+// Per DHT22 read outcomes
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SensorHealth {
+    pub reads_ok: u64,
+    pub read_errors: u64,
+    pub last_error: Option<String>,
+    pub last_temp_f: Option<f32>,
+    pub last_humidity: Option<f32>,
+    pub last_good_reading_timestamp: Option<f64>
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HealthStats {
+    pub door_state: Option<State>,
+    pub last_door_event_timestamp: Option<f64>,
+    pub last_good_reading_timestamp: Option<f64>,
+    pub sensors: BTreeMap<String, SensorHealth>,
+    pub degraded: BTreeMap<String, String>
+}
+
+pub type SharedHealth = Arc<Mutex<HealthStats>>;
+
+impl HealthStats {
+    fn apply(&mut self, event: &SensorEvent) {
+        match event {
+            SensorEvent::DoorChanged { state, timestamp } => {
+                self.door_state = Some(*state);
+                self.last_door_event_timestamp = Some(*timestamp);
+            }
+            SensorEvent::StateReported { state, .. } => {
+                self.door_state = Some(*state);
+            }
+            SensorEvent::ClimateSampled { sensor_id, temp_f, humidity, timestamp } => {
+                let sensor = self.sensors.entry(sensor_id.to_string()).or_default();
+                sensor.reads_ok += 1;
+                sensor.last_temp_f = Some(*temp_f);
+                sensor.last_humidity = Some(*humidity);
+                sensor.last_good_reading_timestamp = Some(*timestamp);
+                self.last_good_reading_timestamp = Some(*timestamp);
+                // reading again
+                self.degraded.remove(*sensor_id);
+            }
+            SensorEvent::SensorReadFailed { sensor_id, error, .. } => {
+                let sensor = self.sensors.entry(sensor_id.to_string()).or_default();
+                sensor.read_errors += 1;
+                sensor.last_error = Some(error.clone());
+            }
+            _ => {}
+        }
+    }
+
+    /// Mark `component` degraded for `reason` (replacing an earlier reason).
+    pub fn degrade(&mut self, component: &str, reason: String) {
+        self.degraded.insert(component.to_string(), reason);
+    }
+
+    /// Clear `component`'s degraded state, true if it was degraded.
+    pub fn recover(&mut self, component: &str) -> bool {
+        self.degraded.remove(component).is_some()
+    }
+
+    /// One line summary for a notification, e.g. "Door closed, dht22-gpio18: 71°F 40% (1200 reads, 2 errors)".
+    pub fn summary(&self) -> String {
+        let mut parts = vec![format!("Door {}", self.door_state.map_or("unknown", |state| state.as_str()))];
+        for (sensor_id, sensor) in &self.sensors {
+            let reading = match (sensor.last_temp_f, sensor.last_humidity) {
+                (Some(temp_f), Some(humidity)) => format!("{}\u{00B0}F {}%", temp_f.round(), humidity.round()),
+                _ => String::from("no reading")
+            };
+            parts.push(format!("{}: {} ({} reads, {} errors)", sensor_id, reading, sensor.reads_ok, sensor.read_errors));
+        }
+        for (component, reason) in &self.degraded {
+            parts.push(format!("{} degraded: {}", component, reason));
+        }
+        parts.join(", ")
+    }
+
+    /// Read error count per sensor id.
+    pub fn sensor_errors(&self) -> BTreeMap<String, u64> {
+        self.sensors.iter().map(|(id, sensor)| (id.clone(), sensor.read_errors)).collect()
+    }
+}
+
+/// Track health stats from bus events until shutdown.
+pub fn spawn_health_tracker(bus: &EventBus, health: SharedHealth) -> JoinHandle<()> {
+    let mut events = bus.subscribe("health");
+
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            if let SensorEvent::ShutdownRequested { .. } = event {
+                break;
+            }
+            health.lock().unwrap().apply(&event);
+        }
+    })
+}
@@ -0,0 +1,174 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Periodic heartbeat written to sensorsHeartbeat/{user}.
+//
+// Each beat carries a server timestamp (server_timestamp), so the app or a
+// watcher can flag the device offline once server time is more than
+// offline_after_secs (interval * missed beats) past the last beat.
+//
+// The server timestamp is read back after each beat to measure the device
+// clock skew (see clock.rs), written with the next beat as clock_skew_secs.
+//
+use crate::clock::{ClockSkew, ServerClock};
+use crate::health::SharedHealth;
+use crate::events::now_timestamp;
+use crate::metrics;
+use firestore::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Duration, Instant, MissedTickBehavior};
+
+pub const SENSORS_HEARTBEAT_COLLECTION: &str = "sensorsHeartbeat";
+pub const HEARTBEAT_INTERVAL_SECS_DEFAULT: u64 = 60;
+pub const HEARTBEAT_MISSED_BEATS_DEFAULT: u32 = 3;
+const CLOCK_SKEW_WARN_SECS: f64 = 5.0;
+
+// Sensor Heartbeat Firestore Document
+#[derive(Debug, Clone, Serialize)]
+struct HeartbeatObject {
+    online: bool,
+    daemon_version: String,
+    uptime_secs: u64,
+    heartbeat_interval_secs: u64,
+    offline_after_missed_beats: u32,
+    offline_after_secs: u64,
+    last_door_state: Option<String>,
+    last_door_event_timestamp: Option<f64>,
+    last_good_reading_timestamp: Option<f64>,
+    sensor_errors: BTreeMap<String, u64>,
+    degraded: BTreeMap<String, String>,
+    clock_skew_secs: Option<f64>,
+    clock_skew_uncertainty_secs: Option<f64>,
+    timestamp: f64
+}
+
+/// Heartbeat fields read back (server time, skew), e.g. by `doctor`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HeartbeatStatus {
+    pub online: bool,
+    pub timestamp: f64,
+    #[serde(default)]
+    pub server_timestamp: Option<FirestoreTimestamp>,
+    #[serde(default)]
+    pub clock_skew_secs: Option<f64>,
+    #[serde(default)]
+    pub clock_skew_uncertainty_secs: Option<f64>
+}
+
+impl HeartbeatStatus {
+    /// Server time of the last beat (unix seconds).
+    pub fn server_time(&self) -> Option<f64> {
+        self.server_timestamp.as_ref().map(|ts| ts.0.timestamp_micros() as f64 / 1e6)
+    }
+}
+
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub missed_beats: u32
+}
+
+/// Write a heartbeat every `config.interval` until aborted.
+pub fn spawn_heartbeat(db: FirestoreDb, user: String, health: SharedHealth, clock: ServerClock, config: HeartbeatConfig) -> JoinHandle<()> {
+    let started = Instant::now();
+
+    tokio::spawn(async move {
+        log::info!("Heartbeat started: every {:?}, offline after {} missed beats", config.interval, config.missed_beats);
+        let mut iv = interval(config.interval);
+        iv.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            iv.tick().await;
+
+            let skew = clock.skew();
+            let heartbeat = {
+                let health = health.lock().unwrap();
+                HeartbeatObject {
+                    online: true,
+                    daemon_version: env!("CARGO_PKG_VERSION").to_string(),
+                    uptime_secs: started.elapsed().as_secs(),
+                    heartbeat_interval_secs: config.interval.as_secs(),
+                    offline_after_missed_beats: config.missed_beats,
+                    offline_after_secs: config.interval.as_secs() * config.missed_beats as u64,
+                    last_door_state: health.door_state.map(|state| state.as_str().to_string()),
+                    last_door_event_timestamp: health.last_door_event_timestamp,
+                    last_good_reading_timestamp: health.last_good_reading_timestamp,
+                    sensor_errors: health.sensor_errors(),
+                    degraded: health.degraded.clone(),
+                    clock_skew_secs: skew.map(|skew| skew.skew_secs),
+                    clock_skew_uncertainty_secs: skew.map(|skew| skew.uncertainty_secs),
+                    timestamp: now_timestamp()
+                }
+            };
+
+            let write_started = Instant::now();
+            let before = now_timestamp();
+            let result = write_heartbeat(&db, &user, &heartbeat).await;
+            let after = now_timestamp();
+            metrics::observe_cloud_call("heartbeat", write_started.elapsed().as_secs_f64(), result.is_ok());
+            if let Err(e) = result {
+                log::warn!("Heartbeat write failed: {:?}", e);
+                continue;
+            }
+            log::debug!("Heartbeat written: uptime={}s", heartbeat.uptime_secs);
+
+            match read_heartbeat(&db, &user).await {
+                Ok(Some(status)) => if let Some(server_time) = status.server_time() {
+                    let skew = ClockSkew::measure(server_time, before, after);
+                    if skew.skew_secs.abs() > CLOCK_SKEW_WARN_SECS && skew.skew_secs.abs() > skew.uncertainty_secs {
+                        log::warn!("Device clock is {:+.1}s (±{:.1}s) off Firestore server time", -skew.skew_secs, skew.uncertainty_secs);
+                    }
+                    clock.record(skew);
+                },
+                Ok(None) => log::warn!("Heartbeat missing right after it was written"),
+                Err(e) => log::warn!("Heartbeat read back failed, clock skew not measured: {:?}", e)
+            }
+        }
+    })
+}
+
+async fn write_heartbeat(db: &FirestoreDb, user: &str, heartbeat: &HeartbeatObject) -> FirestoreResult<()> {
+    db.fluent()
+        .update()
+        .in_col(SENSORS_HEARTBEAT_COLLECTION)
+        .document_id(user)
+        .object(heartbeat)
+        .transforms(|t| t.fields([t.field("server_timestamp").server_request_time()]))
+        .execute::<()>()
+        .await
+}
+
+/// Read sensorsHeartbeat/{user}.
+pub async fn read_heartbeat(db: &FirestoreDb, user: &str) -> FirestoreResult<Option<HeartbeatStatus>> {
+    db.fluent()
+        .select()
+        .by_id_in(SENSORS_HEARTBEAT_COLLECTION)
+        .obj::<HeartbeatStatus>()
+        .one(user)
+        .await
+}
+
+// Heartbeat fields changed on shutdown
+#[derive(Debug, Clone, Serialize)]
+struct HeartbeatOfflineObject {
+    online: bool,
+    timestamp: f64
+}
+
+/// Write online: false (with a final server timestamp), leaving the other heartbeat fields as they are
+pub async fn mark_heartbeat_offline(db: &FirestoreDb, user: &str) -> FirestoreResult<()> {
+    db.fluent()
+        .update()
+        .fields(["online", "timestamp"])
+        .in_col(SENSORS_HEARTBEAT_COLLECTION)
+        .document_id(user)
+        .object(&HeartbeatOfflineObject {
+            online: false,
+            timestamp: now_timestamp()
+        })
+        .transforms(|t| t.fields([t.field("server_timestamp").server_request_time()]))
+        .execute::<()>()
+        .await
+}
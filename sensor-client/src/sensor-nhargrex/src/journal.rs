@@ -0,0 +1,209 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// In-memory journal of recent bus events, each with an increasing id.
+//
+// Subscribers can resume after a given id: entries still in the journal are
+// replayed first, then new entries follow without gaps or duplicates.
+//
+// The journal starts empty in every process, so ids carry the process epoch
+// (persisted in the store, one more on every start) in their upper 32 bits:
+// an id from an earlier run is never mistaken for one of this run, and
+// resuming after it is refused.
+//
+use crate::events::{EventBus, SensorEvent};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+
+pub const JOURNAL_CAPACITY: usize = 1000;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JournalEntry {
+    pub id: u64,
+    #[serde(flatten)]
+    pub event: SensorEvent
+}
+
+pub struct EventJournal {
+    epoch: u32,
+    next_id: u64,
+    entries: VecDeque<JournalEntry>,
+    tx: broadcast::Sender<JournalEntry>
+}
+
+// Replay of the entries after a given id, followed by live entries
+pub struct JournalSubscription {
+    backlog: VecDeque<JournalEntry>,
+    rx: broadcast::Receiver<JournalEntry>,
+    last_id: u64
+}
+
+pub type SharedJournal = Arc<Mutex<EventJournal>>;
+
+/// Resume id from another process epoch (an earlier run, or a later one after the store was reset).
+#[derive(Debug)]
+pub struct ForeignEpoch {
+    pub since: u64,
+    pub epoch: u32
+}
+
+/// Process epoch an id was issued in.
+pub fn id_epoch(id: u64) -> u32 {
+    (id >> 32) as u32
+}
+
+impl EventJournal {
+    /// Empty journal issuing ids in `epoch`.
+    pub fn new(epoch: u32) -> EventJournal {
+        let (tx, _) = broadcast::channel(JOURNAL_CAPACITY);
+        EventJournal { epoch, next_id: ((epoch as u64) << 32) + 1, entries: VecDeque::with_capacity(JOURNAL_CAPACITY), tx }
+    }
+
+    fn push(&mut self, event: SensorEvent) {
+        if self.entries.len() == JOURNAL_CAPACITY {
+            self.entries.pop_front();
+        }
+        let entry = JournalEntry { id: self.next_id, event };
+        self.entries.push_back(entry.clone());
+        self.next_id += 1;
+        // no live subscribers is fine
+        let _ = self.tx.send(entry);
+    }
+
+    /// Up to `limit` most recent entries, oldest first.
+    pub fn recent(&self, limit: usize) -> Vec<JournalEntry> {
+        let skip = self.entries.len().saturating_sub(limit);
+        self.entries.iter().skip(skip).cloned().collect()
+    }
+
+    /// Subscribe to entries after `since` (replayed while still in the journal), or only new ones.
+    /// Refused when `since` was issued in another epoch.
+    pub fn subscribe(&self, since: Option<u64>) -> Result<JournalSubscription, ForeignEpoch> {
+        if let Some(since) = since.filter(|since| id_epoch(*since) != self.epoch) {
+            return Err(ForeignEpoch { since, epoch: self.epoch });
+        }
+        let last_id = since.unwrap_or(self.next_id - 1);
+        let backlog: VecDeque<JournalEntry> = self.entries.iter().filter(|entry| entry.id > last_id).cloned().collect();
+        if let (Some(since), Some(first)) = (since, backlog.front()) {
+            if first.id > since + 1 {
+                log::warn!("Journal replay from {} starts at {}, older entries are gone", since, first.id);
+            }
+        }
+        // subscribed under the journal lock, so nothing is pushed between the backlog and the receiver
+        Ok(JournalSubscription { backlog, rx: self.tx.subscribe(), last_id })
+    }
+
+    /// Subscribe to every entry of this epoch still in the journal, then new ones.
+    pub fn subscribe_epoch(&self) -> JournalSubscription {
+        let (backlog, rx) = (self.entries.iter().cloned().collect(), self.tx.subscribe());
+        JournalSubscription { backlog, rx, last_id: (self.epoch as u64) << 32 }
+    }
+}
+
+impl JournalSubscription {
+    /// Next entry, None once the journal is gone.
+    pub async fn next(&mut self) -> Option<JournalEntry> {
+        if let Some(entry) = self.backlog.pop_front() {
+            self.last_id = entry.id;
+            return Some(entry);
+        }
+        loop {
+            match self.rx.recv().await {
+                Ok(entry) if entry.id <= self.last_id => continue,
+                Ok(entry) => {
+                    self.last_id = entry.id;
+                    return Some(entry);
+                }
+                Err(RecvError::Lagged(skipped)) => log::warn!("Journal subscriber lagged, skipped {} entries", skipped),
+                Err(RecvError::Closed) => return None
+            }
+        }
+    }
+}
+
+/// Record every bus event in `journal` until shutdown, never skipping one (ids have no gaps).
+pub fn spawn_journal(bus: &EventBus, journal: SharedJournal) -> JoinHandle<()> {
+    let mut events = bus.subscribe_lossless("journal");
+
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            if let SensorEvent::ShutdownRequested { .. } = event {
+                break;
+            }
+            journal.lock().unwrap().push(event);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::State;
+
+    fn door(timestamp: f64) -> SensorEvent {
+        SensorEvent::DoorChanged { state: State::Open, timestamp }
+    }
+
+    fn journal_with(epoch: u32, events: usize) -> EventJournal {
+        let mut journal = EventJournal::new(epoch);
+        for i in 0..events {
+            journal.push(door(i as f64));
+        }
+        journal
+    }
+
+    #[test]
+    fn ids_carry_the_epoch() {
+        let journal = journal_with(3, 2);
+        let ids: Vec<u64> = journal.recent(10).iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, vec![(3 << 32) + 1, (3 << 32) + 2]);
+        assert!(ids.iter().all(|id| id_epoch(*id) == 3));
+        assert_eq!(id_epoch(u64::MAX), u32::MAX);
+    }
+
+    #[test]
+    fn refuses_to_resume_from_another_epoch() {
+        let earlier = journal_with(2, 5).recent(1)[0].id;
+        let journal = journal_with(3, 1);
+
+        let refused = journal.subscribe(Some(earlier)).err().unwrap();
+        assert_eq!((refused.since, refused.epoch), (earlier, 3));
+        // a later epoch too (the store was reset)
+        assert!(journal.subscribe(Some((4 << 32) + 1)).is_err());
+        assert!(journal.subscribe(None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn replays_after_the_resume_id_then_follows_live_entries() {
+        let mut journal = journal_with(1, 3);
+        let ids: Vec<u64> = journal.recent(3).iter().map(|entry| entry.id).collect();
+
+        let mut subscription = journal.subscribe(Some(ids[0])).unwrap();
+        journal.push(door(3.0));
+
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            received.push(subscription.next().await.unwrap().id);
+        }
+        // the backlog, then the live entry, without duplicates
+        assert_eq!(received, vec![ids[1], ids[2], ids[2] + 1]);
+
+        // only new entries without a resume id
+        let mut subscription = journal.subscribe(None).unwrap();
+        journal.push(door(4.0));
+        assert_eq!(subscription.next().await.unwrap().id, ids[2] + 2);
+    }
+
+    #[tokio::test]
+    async fn epoch_subscription_replays_the_whole_journal() {
+        let journal = journal_with(1, 2);
+        let mut subscription = journal.subscribe_epoch();
+        assert_eq!(subscription.next().await.unwrap().id, (1 << 32) + 1);
+        assert_eq!(subscription.next().await.unwrap().id, (1 << 32) + 2);
+    }
+}
@@ -0,0 +1,172 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Supervised Firestore listener for command documents (sensorsRefreshRequest).
+//
+// The listener runs inside the firestore crate, which gives no way to tell it
+// has died, so every listen response is timed. Commands can be days apart, so
+// the stream also listens to the device's own heartbeat document
+// (sensorsHeartbeat/{user}), which changes every heartbeat: a live stream
+// delivers a response at least that often, however quiet the commands are.
+// When the listener fails to start, or nothing arrives for
+// SENSOR_LISTENER_MAX_SILENCE_SECS (default: the heartbeat interval times the
+// missed beats plus one), "listener" is marked degraded in the health stats
+// and the listener is recreated, with backoff. A new listener replays the
+// current documents; the command ledger keeps them from running twice.
+//
+// The first listen response marks the listener established (READY for
+// systemd), and the supervisor beats the cloud watchdog pulse.
+//
+use crate::commands::publish_command_document;
+use crate::events::EventBus;
+use crate::health::SharedHealth;
+use crate::heartbeat::SENSORS_HEARTBEAT_COLLECTION;
+use crate::metrics;
+use crate::protocol::document_id;
+use crate::systemd::Pulse;
+use firestore::*;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
+
+const SENSORS_REFRESH_REQUEST_COLLECTION: &str = "sensorsRefreshRequest";
+const SENSORS_REFRESH_REQUEST_TARGET_ID: u32 = 17;
+const SENSORS_REFRESH_REQUEST_TARGET: FirestoreListenerTarget = FirestoreListenerTarget::new(SENSORS_REFRESH_REQUEST_TARGET_ID);
+const SENSORS_HEARTBEAT_TARGET: FirestoreListenerTarget = FirestoreListenerTarget::new(18_u32);
+const LISTENER_COMPONENT: &str = "listener";
+const LISTENER_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const LISTENER_RESTART_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const LISTENER_RESTART_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Running listener supervisor, stopped with `shutdown`.
+pub struct CommandListener {
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>
+}
+
+impl CommandListener {
+    /// Stop listening, once the listener is shut down.
+    pub async fn shutdown(self) {
+        self.stop.send_replace(true);
+        if let Err(e) = self.task.await {
+            log::error!("Firestore listener supervisor failed: {:?}", e);
+        }
+    }
+}
+
+// create the listener with its targets (commands and `user`'s heartbeat) and start it, timing every listen
+// response in `last_response` and marking the listener `established` on the first one
+async fn start_listener(db: &FirestoreDb, bus: &EventBus, user: &str, last_response: Arc<Mutex<Instant>>, established: Arc<watch::Sender<bool>>) -> BoxedErrResult<FirestoreListener<FirestoreMemListenStateStorage>> {
+    let mut listener = db.create_listener(FirestoreMemListenStateStorage::new()).await?;
+    db.fluent()
+        .select()
+        .from(SENSORS_REFRESH_REQUEST_COLLECTION)
+        .listen()
+        .add_target(SENSORS_REFRESH_REQUEST_TARGET, &mut listener)?;
+    db.fluent()
+        .select()
+        .by_id_in(SENSORS_HEARTBEAT_COLLECTION)
+        .batch_listen([user])
+        .add_target(SENSORS_HEARTBEAT_TARGET, &mut listener)?;
+
+    // publishes received commands to the event bus
+    let bus = bus.clone();
+    listener
+        .start(move |event| {
+            // clone again for each invocation (cheap) so the inner async block owns it
+            let bus = bus.clone();
+            *last_response.lock().unwrap() = Instant::now();
+            established.send_if_modified(|established| !std::mem::replace(established, true));
+            async move {
+                match event {
+                    // heartbeat changes only show the stream is alive
+                    FirestoreListenEvent::DocumentChange(ref doc_change) if !doc_change.target_ids.contains(&(SENSORS_REFRESH_REQUEST_TARGET_ID as i32)) => {
+                        log::debug!("Firestore DB listener heartbeat received");
+                    }
+                    FirestoreListenEvent::DocumentChange(ref doc_change) => {
+                        log::info!("Firestore DB listener event received");
+                        if let Some(doc) = &doc_change.document {
+                            publish_command_document(&bus, document_id(doc), doc);
+                        }
+                    }
+                    _ => {
+                        log::info!("Received a listen response - dropped");
+                    }
+                }
+                Ok(())
+            }
+        })
+        .await?;
+    Ok(listener)
+}
+
+/// Listen for command documents, recreating the listener when it fails to start or goes
+/// silent for `max_silence`, until shut down. `established` is set on the first listen response.
+pub fn spawn_command_listener(db: FirestoreDb, bus: &EventBus, user: String, health: SharedHealth, max_silence: Duration, pulse: Pulse, established: watch::Sender<bool>) -> CommandListener {
+    let (stop, mut stopped) = watch::channel(false);
+    let bus = bus.clone();
+    let established = Arc::new(established);
+
+    let task = tokio::spawn(async move {
+        let mut delay = LISTENER_RESTART_INITIAL_DELAY;
+
+        loop {
+            pulse.beat();
+            let started = Instant::now();
+            let last_response = Arc::new(Mutex::new(started));
+            match start_listener(&db, &bus, &user, last_response.clone(), established.clone()).await {
+                Ok(mut listener) => {
+                    log::info!("Firestore DB listener started");
+                    delay = LISTENER_RESTART_INITIAL_DELAY;
+
+                    // None once shut down, the reason it is recreated otherwise
+                    let restart = loop {
+                        tokio::select! {
+                            _ = stopped.changed() => break None,
+                            _ = sleep(LISTENER_CHECK_INTERVAL) => {
+                                pulse.beat();
+                                let last_response = *last_response.lock().unwrap();
+                                if last_response > started {
+                                    // responding again after a restart
+                                    if health.lock().unwrap().recover(LISTENER_COMPONENT) {
+                                        log::info!("Firestore DB listener responding");
+                                    }
+                                }
+                                let silence = last_response.elapsed();
+                                if silence > max_silence {
+                                    break Some(format!("no listen response for {}s", silence.as_secs()));
+                                }
+                            }
+                        }
+                    };
+
+                    if let Err(e) = listener.shutdown().await {
+                        log::warn!("Failed to stop Firestore listener: {:?}", e);
+                    }
+                    match restart {
+                        None => return,
+                        Some(reason) => {
+                            log::error!("Firestore DB listener {}, recreating it", reason);
+                            health.lock().unwrap().degrade(LISTENER_COMPONENT, reason);
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::error!("Firestore DB listener failed to start, retrying in {:?}: {:?}", delay, e);
+                    health.lock().unwrap().degrade(LISTENER_COMPONENT, format!("failed to start: {}", e));
+                }
+            }
+            metrics::LISTENER_RESTARTS.inc();
+
+            tokio::select! {
+                _ = stopped.changed() => return,
+                _ = sleep(delay) => {}
+            }
+            delay = (delay * 2).min(LISTENER_RESTART_MAX_DELAY);
+        }
+    });
+
+    CommandListener { stop, task }
+}
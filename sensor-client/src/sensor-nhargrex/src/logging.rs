@@ -0,0 +1,181 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Structured logging (tracing), to a rotating file and optionally journald.
+//
+// The existing log:: macros are bridged into tracing, so every line carries
+// the span it was written in. Work done for one door event or command runs
+// in a span with its correlation id (see events::CorrelationId), so a single
+// id follows interrupt -> sensor read -> cloud write -> notification.
+//
+// The file is rotated when it would grow past max_bytes or a new day/hour
+// starts: <path> is renamed to <path>.1, <path>.1 to <path>.2 and so on, and
+// only max_files old files are kept.
+//
+use crate::events::CorrelationId;
+use chrono::Utc;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::Span;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, EnvFilter};
+
+pub const LOG_LEVEL_DEFAULT: &str = "info";
+pub const LOG_FILE: &str = "log/sensor-nhargrex.log";
+pub const LOG_MAX_BYTES_DEFAULT: u64 = 10 * 1024 * 1024;
+pub const LOG_MAX_FILES_DEFAULT: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Hourly,
+    Daily,
+    Never
+}
+
+impl Rotation {
+    pub fn parse(s: &str) -> Option<Rotation> {
+        match s {
+            "hourly" => Some(Rotation::Hourly),
+            "daily" => Some(Rotation::Daily),
+            "never" => Some(Rotation::Never),
+            _ => None
+        }
+    }
+
+    // index of the current period (UTC), None if only size based
+    fn period(&self) -> Option<i64> {
+        match self {
+            Rotation::Hourly => Some(Utc::now().timestamp() / 3600),
+            Rotation::Daily => Some(Utc::now().timestamp() / 86400),
+            Rotation::Never => None
+        }
+    }
+}
+
+pub struct LogConfig {
+    /// EnvFilter directives, e.g. "info" or "info,sensor_nhargrex::mqtt=debug".
+    pub level: String,
+    pub path: PathBuf,
+    /// JSON lines (default) or plain text.
+    pub json: bool,
+    pub max_bytes: u64,
+    pub max_files: usize,
+    pub rotation: Rotation,
+    pub journald: bool
+}
+
+/// File that rotates by size and by period.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    period: Option<i64>
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_bytes: u64, max_files: usize, rotation: Rotation) -> io::Result<RotatingFile> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile { path: path.to_path_buf(), max_bytes, max_files, rotation, file, size, period: rotation.period() })
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            // nothing kept, start over
+            self.file = File::create(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(self.rotated_path(self.max_files));
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    std::fs::rename(&from, self.rotated_path(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let period = self.rotation.period();
+        let too_big = self.size > 0 && self.size + buf.len() as u64 > self.max_bytes;
+        if too_big || period != self.period {
+            self.period = period;
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Install the global subscriber (and the log:: bridge). Falls back to stderr
+/// if the log file can't be opened.
+pub fn init(config: &LogConfig) {
+    let filter = EnvFilter::try_new(&config.level).unwrap_or_else(|e| {
+        eprintln!("Invalid log level {:?} ({}), using {}", config.level, e, LOG_LEVEL_DEFAULT);
+        EnvFilter::new(LOG_LEVEL_DEFAULT)
+    });
+
+    let file = RotatingFile::open(&config.path, config.max_bytes, config.max_files, config.rotation);
+    let (writer, file_error) = match file {
+        Ok(file) => (fmt::writer::BoxMakeWriter::new(Mutex::new(file)), None),
+        Err(e) => (fmt::writer::BoxMakeWriter::new(io::stderr), Some(e))
+    };
+    let output = if config.json {
+        fmt::layer().json().with_current_span(true).with_span_list(false).with_writer(writer).boxed()
+    } else {
+        fmt::layer().with_ansi(false).with_writer(writer).boxed()
+    };
+
+    let journald = if config.journald {
+        match tracing_journald::layer() {
+            Ok(layer) => Some(layer.with_syslog_identifier("sensor-nhargrex".to_string())),
+            Err(e) => {
+                eprintln!("journald logging unavailable: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    tracing_subscriber::registry()
+        .with(output)
+        .with(journald)
+        .with(filter)
+        .init();
+
+    if let Some(e) = file_error {
+        log::error!("Failed to open log file {:?}, logging to stderr: {:?}", config.path, e);
+    }
+}
+
+/// Span for the work done on behalf of one correlated event or command.
+pub fn correlation_span(name: &'static str, correlation_id: CorrelationId) -> Span {
+    tracing::info_span!("correlated", kind = name, correlation_id = %correlation_id)
+}
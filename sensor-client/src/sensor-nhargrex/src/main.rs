@@ -4,44 +4,78 @@
 //
 // See README.md for details.
 //
+mod api;
+mod auth;
+mod capture;
+mod clock;
+mod commands;
+mod connectivity;
+mod debounce;
 mod dht22;
-use crate::dht22::{Reading, ReadingError, read_dht22};
-use log::LevelFilter;
-use simple_logging::{log_to_file};
+mod diagnostics;
+mod doctor;
+mod events;
+mod health;
+mod heartbeat;
+mod journal;
+mod listener;
+mod logging;
+mod metrics;
+mod mode;
+mod mqtt;
+mod outbox;
+mod pins;
+mod power;
+mod protocol;
+mod scheduler;
+mod shutdown;
+mod sinks;
+mod store;
+mod systemd;
+mod telemetry;
+use crate::api::{ApiState, spawn_api_server};
+use crate::auth::CommandAuth;
+use crate::capture::{CaptureKind, CaptureLimiter, CAPTURE_MIN_INTERVAL_SECS_DEFAULT};
+use crate::diagnostics::Diagnostics;
+use crate::power::{PowerGuard, report_power_actions, POWER_MAX_PER_DAY_DEFAULT};
+use crate::scheduler::{MissedPolicy, parse_mode_schedule, parse_schedules, schedule_timezone, spawn_scheduler};
+use crate::clock::ServerClock;
+use crate::commands::{spawn_command_processor, spawn_command_result_sink};
+use crate::connectivity::{CloudGate, cloud_gate, firestore_endpoint, wait_for_connectivity};
+use crate::debounce::spawn_door_debouncer;
+use crate::dht22::{Reading, ReadingError};
+use crate::events::{EventBus, SensorEvent, State, now_timestamp};
+use crate::health::{SharedHealth, spawn_health_tracker};
+use crate::heartbeat::{HeartbeatConfig, spawn_heartbeat, mark_heartbeat_offline, HEARTBEAT_INTERVAL_SECS_DEFAULT, HEARTBEAT_MISSED_BEATS_DEFAULT};
+use crate::metrics::spawn_metrics_sink;
+use crate::mode::{DoorActions, ModeSwitch, spawn_mode_mirror};
+use crate::journal::{EventJournal, spawn_journal};
+use crate::listener::{CommandListener, spawn_command_listener};
+use crate::logging::{LogConfig, Rotation, correlation_span, LOG_FILE, LOG_LEVEL_DEFAULT, LOG_MAX_BYTES_DEFAULT, LOG_MAX_FILES_DEFAULT};
+use crate::mqtt::{MqttConfig, spawn_mqtt, MQTT_PORT_DEFAULT, MQTT_PREFIX_DEFAULT, MQTT_DISCOVERY_PREFIX_DEFAULT, MQTT_NODE_ID_DEFAULT};
+use crate::outbox::{Outbox, OUTBOX_MAX_ENTRIES_DEFAULT};
+use crate::pins::{DoorPin, TempPin, plausible};
+use crate::shutdown::{Shutdown, install_signal_handler};
+use crate::sinks::{CloudWrites, spawn_cloud_sink, spawn_alert_sink, spawn_telemetry_sink};
+use crate::store::{Store, SharedStore, Retention, spawn_store_sink, spawn_store_maintenance, print_history, STORE_RAW_DAYS_DEFAULT, STORE_HOURLY_DAYS_DEFAULT};
+use crate::systemd::{Pulse, Watchdog, spawn_watchdog, notify_ready, notify_stopping};
+use crate::telemetry::{TelemetryPublisher, PUBSUB_TOPIC_ID_DEFAULT};
 use firestore::*;
 use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
 use tokio::time::{sleep, interval, Instant, Duration};
 use lazy_static::lazy_static;
-use std::sync::Arc;
-use std::sync::Mutex;
-use std::time::SystemTime;
-use std::time::UNIX_EPOCH;
-use std::thread;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::process::Command;
-use chrono::{Utc, TimeZone};
-use rppal::gpio::{Gpio, Trigger, InputPin, Mode, IoPin};
-use pyo3::exceptions::PyValueError;
+use chrono::Utc;
+use pyo3::exceptions::{PyException, PyRuntimeError};
 use pyo3::types::PyModule;
 use pyo3::prelude::PyAnyMethods;
 use pyo3::Python;
 use pyo3::PyResult;
 use anyhow::{Result};
 
-#[derive(Debug)]
-pub enum State {
-    Open,
-    Closed
-}
-
-// Sensor Request Firestore Document
-#[derive(Debug, Clone, Deserialize, Serialize)]
-struct SensorRefreshRequestObject {
-    #[serde(alias = "_firestore_id")]
-    doc_id: String,
-    r_ts: u64,
-    r_cmd: i32
-}
-
 // Sensor Request Firestore Document
 #[derive(Debug, Clone, Deserialize, Serialize)]
 struct SensorObject {
@@ -52,6 +86,14 @@ struct SensorObject {
     timestamp: f64
 }
 
+// Sensor Firestore Document fields written on shutdown
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct SensorOfflineObject {
+    online: bool,
+    shutdown_reason: String,
+    shutdown_timestamp: f64
+}
+
 lazy_static! {
     static ref USER_ID_ERROR: String = String::from("Couldn't get GOOGLE_USER_ID");
 }
@@ -60,351 +102,244 @@ lazy_static! {
 const GPIO_PIN_17 : u8 = 17; // door sensor
 const GPIO_PIN_18 : u8 = 18; // primary dht22
 const GPIO_PIN_27 : u8 = 27; // secondary dht22
+const SENSOR_ID_PRIMARY : &str = "dht22-gpio18";
+const SENSOR_ID_SECONDARY : &str = "dht22-gpio27";
+const SENSOR_ID_DOOR : &str = "door-gpio17";
 const SHOW_STATE : bool = false;
 const DEBOUNCE_TIME : Duration = Duration::from_millis(500);
 const POLLING_DURATION : Duration = Duration::from_millis(5000);
-const SENSORS_REFRESH_REQUEST_COLLECTION: &str = "sensorsRefreshRequest";
 const SENSORS_COLLECTION: &str = "sensors";
-const SENSORS_REFRESH_REQUEST_DOCUMENT_ID: FirestoreListenerTarget = FirestoreListenerTarget::new(17_u32);
 const REFRESH_REQUEST_TIMEWINDOW_SECONDS : i64 = -15;
 const DHT22_TEMP_WARNING_F : f32 = 36.0;
 const UPDATE_TEMP_HUMIDITY_FREQUENCY_SECONDS: u64 = 1 * 60; // 1 minute
+const SHUTDOWN_DRAIN_TIMEOUT : Duration = Duration::from_secs(10);
+const SHUTDOWN_OFFLINE_TIMEOUT : Duration = Duration::from_secs(5);
+const SHUTDOWN_LISTENER_TIMEOUT : Duration = Duration::from_secs(5);
+const SHUTDOWN_CLOUD_WRITES_TIMEOUT : Duration = Duration::from_secs(5);
+const FIRESTORE_INIT_MAX_DELAY : Duration = Duration::from_secs(5 * 60);
+const SENSOR_DATA_DIR_DEFAULT: &str = "/var/lib/sensor-nhargrex";
+const SYSTEMD_UNIT_DEFAULT: &str = "sensor-nhargrex";
+const MONITOR_MAX_SILENCE : Duration = Duration::from_secs(90);
+const MAIN_LOOP_MAX_SILENCE : Duration = Duration::from_secs(30);
+// longest retry backoff (Firestore init, listener restarts) plus a stalled attempt
+const CLOUD_MAX_SILENCE : Duration = Duration::from_secs(7 * 60);
+const READY_TIMEOUT_SECS_DEFAULT: u64 = 120;
 
 // Main
 #[tokio::main]
 #[allow(dependency_on_unit_never_type_fallback)]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    
+
+    // sensor-nhargrex history [hours]: print the local history and exit
+    if std::env::args().nth(1).as_deref() == Some("history") {
+        let hours = std::env::args().nth(2).and_then(|h| h.parse().ok()).unwrap_or(24.0);
+        let store = Store::open(&data_dir(), store_retention())?;
+        print_history(&store, hours, now_timestamp())?;
+        return Ok(());
+    }
+
+    // sensor-nhargrex doctor: check the setup, connectivity and clock skew, exit 1 on failure
+    if std::env::args().nth(1).as_deref() == Some("doctor") {
+        if !doctor::run_doctor().await {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     // sendor door pin
-    let sensor_door_pin = Arc::new(Mutex::new(Gpio::new()?.get(GPIO_PIN_17)?.into_input_pullup()));
+    let sensor_door_pin = DoorPin::new(GPIO_PIN_17)?;
 
     // temp sensor pin
-    let sensor_primary_temp_pin = Arc::new(Mutex::new(Gpio::new()?.get(GPIO_PIN_18)?.into_io(Mode::Output)));
+    let sensor_primary_temp_pin = TempPin::new(GPIO_PIN_18)?;
 
     // temp sensor pin
-    let sensor_secondary_temp_pin = Arc::new(Mutex::new(Gpio::new()?.get(GPIO_PIN_27)?.into_io(Mode::Output)));
-
-    // clone for worker/polling threads
-    let sensor_door_pin_for_callback = sensor_door_pin.clone();
-    let sensor_door_pin_for_command = sensor_door_pin.clone();
-    let sendor_door_pin_for_startup = sensor_door_pin.clone();
-    let worker_sensor_state_pin = sensor_door_pin.clone();
-    let worker_sensor_primary_temp_pin = sensor_primary_temp_pin.clone();
-    let init_sensor_primary_temp_pin = sensor_primary_temp_pin.clone();
-    let sensor_primary_temp_pin_for_command = sensor_primary_temp_pin.clone();
-    let sensor_secondary_temp_pin_for_startup = sensor_secondary_temp_pin.clone();
-    let sensor_pin_for_command = sensor_door_pin_for_command.clone();
-    let sensor_pin_for_temp_monitor = sensor_door_pin.clone();
+    let sensor_secondary_temp_pin = TempPin::new(GPIO_PIN_27)?;
+
+    // event bus connecting inputs (interrupt, samplers, listener) to processors and sinks
+    let bus = EventBus::new();
 
     // statup log
-    log_to_file("/tmp/sensor-nhargrex.log", LevelFilter::Info).unwrap();
+    let log_path = std::env::var("SENSOR_LOG_PATH").map(PathBuf::from).unwrap_or_else(|_| data_dir().join(LOG_FILE));
+    logging::init(&LogConfig {
+        level: config_env_var_or("SENSOR_LOG_LEVEL", LOG_LEVEL_DEFAULT),
+        path: log_path.clone(),
+        json: config_env_var_or("SENSOR_LOG_FORMAT", "json") != "text",
+        max_bytes: config_env_var_or("SENSOR_LOG_MAX_BYTES", &LOG_MAX_BYTES_DEFAULT.to_string())
+            .parse()
+            .unwrap_or(LOG_MAX_BYTES_DEFAULT),
+        max_files: config_env_var_or("SENSOR_LOG_MAX_FILES", &LOG_MAX_FILES_DEFAULT.to_string())
+            .parse()
+            .unwrap_or(LOG_MAX_FILES_DEFAULT),
+        rotation: Rotation::parse(&config_env_var_or("SENSOR_LOG_ROTATION", "daily")).unwrap_or(Rotation::Daily),
+        journald: matches!(config_env_var_or("SENSOR_LOG_JOURNALD", "0").as_str(), "1" | "true")
+    });
     log::info!("Normal start");
+    let started = Instant::now();
+    metrics::init();
 
-    log::info!("Wait to start (for network)");
-    thread::sleep(POLLING_DURATION * 6);
-    log::info!("Continuing");
+    // SIGINT/SIGTERM shut down gracefully from here on, also while waiting for sensors or the network
+    let mut shutdown = Shutdown::new();
+    install_signal_handler(&shutdown)?;
 
-    // initialize firestore
+    // check user environment variable is set
+    let user = config_env_var("GOOGLE_USER_ID")?.to_string();
     let project_id = config_env_var("GOOGLE_PROJECT_ID")?.to_string();
-    let key_file = config_env_var("GOOGLE_APPLICATION_CREDENTIALS")?.to_string().into();
-    let db = init_firestore_with_retry(project_id, key_file, 10)
-        .await
-        .map_err(|e| {
-            log::error!("Firestore initialization failed: {:?}", e);
-            e
-        })?;
-
-    log::info!("Firestore DB initialized");
+    let key_file = config_env_var("GOOGLE_APPLICATION_CREDENTIALS")?.to_string();
+
+    // cloud updates not delivered before the last shutdown (or power cut) are sent first
+    let outbox = Outbox::open(
+        &data_dir(),
+        config_env_var_or("SENSOR_OUTBOX_MAX_ENTRIES", &OUTBOX_MAX_ENTRIES_DEFAULT.to_string())
+            .parse()
+            .unwrap_or(OUTBOX_MAX_ENTRIES_DEFAULT))?;
+
+    // local history of every reading and door transition
+    let store = Arc::new(Mutex::new(Store::open(&data_dir(), store_retention())?));
+
+    // security mode, deciding what door changes trigger
+    let modes = ModeSwitch::load(store.clone(), &bus);
+
+    // tasks that must stay alive for the systemd watchdog to be pinged
+    let mut watchdog = Watchdog::new();
+
+    // local processors and sinks subscribe before any input starts publishing
+    let health = SharedHealth::default();
+    let journal_epoch = store.lock().unwrap().next_journal_epoch()?;
+    let journal = Arc::new(Mutex::new(EventJournal::new(journal_epoch)));
+    // cloud updates are queued in the outbox until the cloud is reachable
+    let (gate, cloud_ready) = cloud_gate();
+    let cloud_writes = CloudWrites::default();
+    let cloud_sink = spawn_cloud_sink(&bus, user.clone(), outbox, modes.clone(), cloud_ready.clone(), cloud_writes.clone());
+    watchdog.task("cloud-sink", &cloud_sink);
+    let mut subscribers = vec![
+        spawn_health_tracker(&bus, health.clone()),
+        spawn_journal(&bus, journal.clone()),
+        spawn_metrics_sink(&bus),
+        cloud_sink,
+        spawn_alert_sink(&bus, sensor_door_pin.clone()),
+        spawn_store_sink(&bus, store.clone(), SENSOR_ID_DOOR),
+        spawn_store_maintenance(&bus, store.clone()),
+    ];
+    // worker thread that handles debounced sensor door changes
+    // read door state and dht22 and report to cloud
+    {
+        let mut events = bus.subscribe_lossless("door-worker");
+        let bus = bus.clone();
+        let worker_sensor_state_pin = sensor_door_pin.clone();
+        let worker_sensor_primary_temp_pin = sensor_primary_temp_pin.clone();
+
+        let worker = std::thread::spawn(move || {
+            log::info!("GPIO worker thread started");
+            // until the initial reading (or a door change reading) arrives
+            let mut last_good_temp_f: f32 = 0.0;
+            let mut last_good_humidity: f32 = 0.0;
+
+            while let Some((correlation_id, event)) = events.blocking_recv() {
+                let state = match event {
+                    SensorEvent::DoorChanged { state, .. } => state,
+                    // the initial reading is taken in the background, keep it for failed reads
+                    SensorEvent::ClimateSampled { sensor_id: SENSOR_ID_PRIMARY, temp_f, humidity, .. } => {
+                        last_good_temp_f = temp_f;
+                        last_good_humidity = humidity;
+                        continue;
+                    }
+                    SensorEvent::ShutdownRequested { .. } => break,
+                    _ => continue
+                };
+                let _span = correlation_span("door", correlation_id).entered();
 
-    let mut listener = db
-    .create_listener(
-        FirestoreMemListenStateStorage::new(),
-    )
-    .await?;
-    log::info!("Firestore DB listener created");
+                // immediate visibility that worker got the event
+                log::info!("GPIO worker received event: {:?}", state);
 
-    // add target for listener
-    db.fluent()
-    .select()
-    .from(SENSORS_REFRESH_REQUEST_COLLECTION)
-    .listen()
-    .add_target(SENSORS_REFRESH_REQUEST_DOCUMENT_ID, &mut listener)?;
-    
-    // initialize atomic reference count mutex to now()
-    let interrupt_counter = Arc::new(Mutex::new(SystemTime::now().duration_since(UNIX_EPOCH)?));
+                match worker_sensor_primary_temp_pin.read() {
+                    Ok(Reading {temperature, humidity}) => {
+                        let temp_f = temperature * 9.0 / 5.0 + 32.0;
+                        log::info!("GPIO worker DHT22 Reading: Temp: {:.2} °F, Humidity: {:.2} %", temp_f, humidity);
 
-    // check user environment variable is set
-    let user = config_env_var("GOOGLE_USER_ID")?.to_string();
-    let monitor_user = user.clone();
-    let startup_user = user.clone();
-    let command_user = user.clone();
+                        // Quick sanity check before reporting
+                        if !( (-40.0..=125.0).contains(&temp_f) && (0.0..=100.0).contains(&humidity) ) {
+                            log::warn!("GPIO worker DHT22 reading out of range, skipping update: {:.2}°F, {:.2}%", temp_f, humidity);
+                            continue;
+                        }
 
-    // used to hold initial temp/humidity reading moved into worker thread
-    let mut inital_temp_f: f32 = 0.0;
-    let mut inital_humidity: f32 = 0.0;
+                        // Skip if reading is obviously invalid
+                        if temp_f == 32.0 && humidity == 0.0 {
+                            log::warn!("GPIO worker DHT22 invalid, skipping update");
+                            continue;
+                        }
 
-    // initialize temp sensor and get initial reading
-    const MAX_RETRIES: u8 = 10;
-    const INITIAL_DELAY_SECS: u64 = 1;
+                        let timestamp = now_timestamp();
+                        bus.publish_correlated(correlation_id, SensorEvent::ClimateSampled { sensor_id: SENSOR_ID_PRIMARY, temp_f, humidity, timestamp });
+                        bus.publish_correlated(correlation_id, SensorEvent::StateReported { state: read_shared_state(&worker_sensor_state_pin), temp_f, humidity, force_notify: false, timestamp });
 
-    for attempt in 1..=MAX_RETRIES {
-        match read_dht22(&init_sensor_primary_temp_pin) {
-            Ok(Reading { temperature, humidity }) => {
-                let temp_f = temperature * 9.0 / 5.0 + 32.0;
-                log::info!("Initial DHT22 Reading: Temp: {:.2} °F, Humidity: {:.2} %", temp_f, humidity);
-                inital_temp_f = temp_f;
-                inital_humidity = humidity;
-                break;
-            },
-            Err(e) => {
-                log::warn!("Firestore/DHT sync failed, attempt {}/{}: {:?}", attempt, MAX_RETRIES, e);
-                
-                if attempt == MAX_RETRIES {
-                    log::error!("Failed to initialize after {} attempts", MAX_RETRIES);
-                    panic!("Initialization failed");
+                        // cache last know good reading for use if reading fails next time
+                        last_good_temp_f = temp_f;
+                        last_good_humidity = humidity;
+                    },
+                    Err(e) => {
+                        log::warn!("GPIO worker DHT22 reading failed, sending state update previous temp/humidity: {:.2}°F, {:.2}%", last_good_temp_f, last_good_humidity);
+                        let timestamp = now_timestamp();
+                        bus.publish_correlated(correlation_id, SensorEvent::SensorReadFailed { sensor_id: SENSOR_ID_PRIMARY, error: format!("{:?}", e), timestamp });
+                        bus.publish_correlated(correlation_id, SensorEvent::StateReported { state: read_shared_state(&worker_sensor_state_pin), temp_f: last_good_temp_f, humidity: last_good_humidity, force_notify: false, timestamp });
+                    }
                 }
-
-                // Exponential Backoff: 1s, 2s, 4s, 8s, 16s...
-                let backoff_duration = Duration::from_secs(INITIAL_DELAY_SECS * 2u64.pow(attempt as u32 - 1));
-                
-                log::info!("Waiting {:?} before next retry...", backoff_duration);
-                sleep(backoff_duration).await;
             }
-        }
+            log::info!("GPIO worker thread exiting");
+        });
+        watchdog.thread("door-worker", worker);
     }
 
-    // create a channel and worker thread to handle potentially blocking work
-    let (tx, rx) = std::sync::mpsc::channel::<rppal::gpio::Level>();
-
-    // worker thread that handles debounced sensor door pin async interrupt
-    // read door state and dht22 and send to cloud 
-    std::thread::spawn(move || {
-        log::info!("GPIO worker thread started");
-        let mut last_good_temp_f: f32 = inital_temp_f.clone();
-        let mut last_good_humidity: f32 = inital_humidity.clone();
-
-        for level in rx {
-            let state: State = if level == rppal::gpio::Level::High {
-                State::Open
-            } else {
-                State::Closed
-            };
-
-            // immediate visibility that worker got the event
-            log::info!("GPIO worker received event: {:?}", state);
-
-            // read temp from temp_sensor_secondary_pin
-            let worker_sensor_primary_temp_pin = Arc::clone(&worker_sensor_primary_temp_pin);
-            let worker_user = user.clone();
-
-            match read_dht22(&worker_sensor_primary_temp_pin) {
-                Ok(Reading {temperature, humidity}) => {
-                    let temp_f = temperature * 9.0 / 5.0 + 32.0;
-                    log::info!("GPIO worker DHT22 Reading: Temp: {:.2} °F, Humidity: {:.2} %", temp_f, humidity);
-
-                    // Quick sanity check before calling the Python updater
-                    if !( (-40.0..=125.0).contains(&temp_f) && (0.0..=100.0).contains(&humidity) ) {
-                        log::warn!("GPIO worker DHT22 reading out of range, skipping update: {:.2}°F, {:.2}%", temp_f, humidity);
-                        continue;
-                    }
-
-                    // Skip if reading is obviously invalid
-                    if temp_f == 32.0 && humidity == 0.0 {
-                        log::warn!("GPIO worker DHT22 invalid, skipping update");
-                        continue;
-                    }
+    // async interrupt on GPIO sensor door pin
+    // publishes debounced (settled) door changes to the event bus
+    let debounce_time = std::env::var("SENSOR_DEBOUNCE_MS").ok()
+        .and_then(|ms| ms.parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(DEBOUNCE_TIME);
+    let debouncer = spawn_door_debouncer(&bus, &sensor_door_pin, debounce_time)?;
+    watchdog.thread("door-debouncer", debouncer);
+    log::info!("GPIO sensor door interrupt installed OK ({:.1}s after start)", started.elapsed().as_secs_f64());
+
+    // initial primary dht22 reading, in the background once the door is armed; without one the
+    // sensor is marked degraded (until a reading succeeds) rather than stopping the daemon
+    let initial_reading = tokio::spawn({
+        let sensor_primary_temp_pin = sensor_primary_temp_pin.clone();
+        let bus = bus.clone();
+        let health = health.clone();
 
-                    if let Err(error) = update_state_temp_f_humidity_and_notify_user(worker_user, read_shared_state(&worker_sensor_state_pin), Some(temp_f), Some(humidity), Some(false)) {
-                        log::error!("update_state_temp_f_humidity_and_notify_user {:?}", error);
+        async move {
+            const MAX_RETRIES: u8 = 10;
+            const INITIAL_DELAY_SECS: u64 = 1;
+
+            for attempt in 1..=MAX_RETRIES {
+                match sensor_primary_temp_pin.read() {
+                    Ok(reading) if plausible(&reading) => {
+                        let temp_f = reading.temperature * 9.0 / 5.0 + 32.0;
+                        log::info!("Initial DHT22 Reading: Temp: {:.2} °F, Humidity: {:.2} %", temp_f, reading.humidity);
+                        bus.publish(SensorEvent::ClimateSampled { sensor_id: SENSOR_ID_PRIMARY, temp_f, humidity: reading.humidity, timestamp: now_timestamp() });
+                        return;
                     }
+                    Ok(reading) => log::warn!("Initial DHT22 reading out of range, attempt {}/{}: {:?}", attempt, MAX_RETRIES, reading),
+                    Err(e) => log::warn!("Initial DHT22 reading failed, attempt {}/{}: {:?}", attempt, MAX_RETRIES, e)
+                }
 
-                    // cache last know good reading for use if reading fails next time
-                    last_good_temp_f = temp_f;
-                    last_good_humidity = humidity;
-                },
-                Err(_) => {
-                    log::warn!("GPIO worker DHT22 reading failed, sending state update previous temp/humidity: {:.2}°F, {:.2}%", last_good_temp_f, last_good_humidity);
-                    if let Err(error) = update_state_temp_f_humidity_and_notify_user(worker_user, read_shared_state(&worker_sensor_state_pin), Some(last_good_temp_f), Some(last_good_humidity), Some(false)) {
-                        log::error!("update_state_temp_f_humidity_and_notify_user {:?}", error);
-                    }
+                if attempt < MAX_RETRIES {
+                    // Exponential Backoff: 1s, 2s, 4s, 8s, 16s...
+                    let backoff_duration = Duration::from_secs(INITIAL_DELAY_SECS * 2u64.pow(attempt as u32 - 1));
+                    log::info!("Waiting {:?} before next retry...", backoff_duration);
+                    sleep(backoff_duration).await;
                 }
             }
+            log::error!("No initial DHT22 reading after {} attempts, {} degraded", MAX_RETRIES, SENSOR_ID_PRIMARY);
+            health.lock().unwrap().degrade(SENSOR_ID_PRIMARY, format!("no reading after {} attempts at startup", MAX_RETRIES));
         }
-        log::info!("GPIO worker thread exiting");
     });
-
-    // async interrupt on GPIO sensor door pin
-    // sends mspc message to worker thread.
-    {
-        let tx_int = tx.clone();
-        let interrupt_counter = interrupt_counter.clone();
-        let pin_for_cb = sensor_door_pin_for_callback.clone();
-
-        // Note: set_async_interrupt needs &mut InputPin. We can lock the mutex to get a &mut guard,
-        // then call set_async_interrupt on that guarded mutable reference.
-        let mut guard = pin_for_cb.lock().unwrap();
-        guard.set_async_interrupt(Trigger::Both, move |level| {
-            log::debug!("GPIO interrupt callback fired: level={:?}", level);
-
-            let mut last_interrupt_time = interrupt_counter.lock().unwrap();
-            let time_of_interrupt = SystemTime::now().duration_since(UNIX_EPOCH).expect("REASON");
-            let time_since_last_interrupt = time_of_interrupt.checked_sub(*last_interrupt_time).expect("REASON");
-
-            if time_since_last_interrupt > DEBOUNCE_TIME {
-                log::debug!("GPIO interrupt callback: level={:?} debounce_ok distance={:?}", level, time_since_last_interrupt);
-                if let Err(e) = tx_int.send(level) {
-                    log::error!("Failed to send GPIO event to worker: {:?}", e);
-                }
-            }
-
-            *last_interrupt_time = time_of_interrupt;
-        })?;
-        // guard is dropped here (releases lock)
-    }
-    log::info!("GPIO sensor door interrupt installed OK");
     
-    // start listener thread for document change (refresh request)
-    let fs_listener = listener
-        .start(move |event| {
-            // clone again for each invocation (cheap) so the inner async block owns its Arc
-            let door_pin = sensor_pin_for_command.clone();
-            let temp_pin = sensor_primary_temp_pin_for_command.clone();
-            let v_user = command_user.clone();
-            async move {
-                log::info!("Firestore DB listener event received");
-                match event {
-                    FirestoreListenEvent::DocumentChange(ref doc_change) => {
-                        if let Some(doc) = &doc_change.document {
-                            let sensor_refresh_request: SensorRefreshRequestObject = FirestoreDb::deserialize_doc_to::<SensorRefreshRequestObject>(doc).expect("Deserialized object");
-                            log::info!("Recevied: {sensor_refresh_request:?} r_ts={}, r_cmd={}", sensor_refresh_request.r_ts, sensor_refresh_request.r_cmd);
-                            let delta_ts = (Utc.timestamp_opt(sensor_refresh_request.r_ts as i64, 0).unwrap() - Utc::now()).num_seconds();
-                            log::info!("Time delta of refresh request: delta={}s", delta_ts);
-                            // only process the change if it was recently in the past or now
-                            if delta_ts <= 0 && delta_ts > REFRESH_REQUEST_TIMEWINDOW_SECONDS {
-                                let command = sensor_refresh_request.r_cmd;
-                                match command {
-                                    0 => {
-                                        // cmd => refresh
-                                        log::info!("Command: refresh");
-
-                                        // get gpio pin as input and read state
-                                        let state = read_shared_state(&door_pin);
-
-                                        // get temp and humidity
-                                        let t : f32;
-                                        let h : f32;
-
-                                        match read_dht22_once(&temp_pin) {
-                                            Ok(Reading {  temperature, humidity }) => {
-                                                t = temperature;
-                                                h = humidity;    
-                                            }
-                                            Err(_) => {
-                                                t = 0.0;
-                                                h = 0.0;                                                  
-                                            }
-                                        }
-
-                                        log::info!("State {:?}, Temp: {:.2}°F, Humidity: {:.2}%", state, t, h);
-
-                                        // (force) update (cloud) state and notify (Android) user
-                                        if let Err(error) = update_state_temp_f_humidity_and_notify_user(v_user, state, Some(t), Some(h), Some(true)) {
-                                            log::error!("Error on update_state_temp_f_humidity_and_notify_user {:?} - continuing", error);
-                                            // continue without this update
-                                        }
-                                    }
-                                    1 => {
-                                        // cmd => status
-                                        log::info!("Command: status");
-
-                                        // setup firestore
-                                        let db = FirestoreDb::with_options_service_account_key_file(
-                                            FirestoreDbOptions::new(config_env_var("GOOGLE_PROJECT_ID")?.to_string()),
-                                            config_env_var("GOOGLE_APPLICATION_CREDENTIALS")?.to_string().into()
-                                        ).await?;
-                                        log::info!("Firestore DB initialized");
-
-                                        // read temp and humidity
-                                        let t : f32;
-                                        let h : f32;
-
-                                        // Get the duration since Unix Epoch
-                                        let now = SystemTime::now().duration_since(UNIX_EPOCH)
-                                            .expect("Time went backwards"); // Handle clock drift safely
-
-                                        // Use f64 to match Python's double-precision float
-                                        let timestamp: f64 = now.as_secs_f64();
-
-                                        match read_dht22_with_retry(&temp_pin).await {
-                                            Ok(Reading {temperature, humidity}) => {
-                                                t = temperature;
-                                                h = humidity;    
-                                            }
-                                            Err(_) => {
-                                                t = 0.0;
-                                                h = 0.0;                                                  
-                                            }
-                                        }
-
-                                        log::info!("Temp: {:.2}°F, Humidity: {:.2}%, Timestamp: {}", t, h, timestamp);
-
-                                        // Update sensor document with current status
-                                        db.fluent()
-                                        .update()
-                                        .in_col(SENSORS_COLLECTION)
-                                        .document_id(config_env_var("GOOGLE_USER_ID")?.to_string().clone())
-                                        .object(&SensorObject {
-                                            online: true,
-                                            state: match read_shared_state(&door_pin) {
-                                                State::Open => "OPEN".to_lowercase().to_string(),
-                                                State::Closed => "CLOSED".to_lowercase().to_string(),
-                                            },
-                                            temp_f: t,
-                                            humidity: h,
-                                            timestamp: timestamp
-                                        })
-                                        .execute::<()>()
-                                        .await?;
-                                        log::info!("Status and temperature updated to current");
-                                    },
-                                    3 => {
-                                        // cmd => status
-                                        log::info!("Command: reboot");
-
-                                        match reboot() {
-                                            Ok(()) => {
-                                                log::info!("Reboot requested - OK");    
-                                            }
-                                            Err(_) => {
-                                                log::info!("Reboot requested - Failed");
-                                            }
-                                        }
-                                    } 
-                                    _ => {
-                                        // not recognized
-                                        log::info!("Unknown command recevied!");
-                                    }    
-                                }
-                            }
-                        }
-                    }
-                    _ => {
-                        log::info!("Received a listen response - dropped");
-                    }
-                }
-                Ok(())
-            }
-        });
-
-    // listen for refresh requests
-    fs_listener.await?;
-
     // secondary dht22 reading (using rust lib)
-    // poll and alert on low temp
-
-    tokio::spawn({
-        let sensor_secondary_temp_pin = Arc::clone(&sensor_secondary_temp_pin);
-        let monitor_user = monitor_user.clone();
+    // periodic sampler, alerts and cloud updates are handled by the sinks
+    let monitor = tokio::spawn({
+        let sensor_secondary_temp_pin = sensor_secondary_temp_pin.clone();
+        let bus = bus.clone();
+        let pulse = watchdog.pulse("monitor", MONITOR_MAX_SILENCE);
         
         async move {
             // Initial delay to let system settle
@@ -412,116 +347,437 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             log::info!("Starting Low Temp Warning Monitor periodic DHT22 read task");
             
             let mut iv = interval(Duration::from_secs(10)); 
-            
-            // Trackers for our two different schedules
-            let mut last_publish_time: Option<Instant> = None;
-            let mut last_warning_time: Option<Instant> = None;
-
-            let publish_interval = Duration::from_secs(UPDATE_TEMP_HUMIDITY_FREQUENCY_SECONDS);
-            let warning_cooldown = Duration::from_secs(8 * 60 * 60); // 8 hours
 
             loop {
                 iv.tick().await;
+                pulse.beat();
                 
                 match read_dht22_with_retry(&sensor_secondary_temp_pin).await {
                     Ok(Reading {temperature, humidity}) => {
-                        // 1. Sanity Checks
+                        // Sanity Checks
                         let temp_f = temperature; // temperature is already in °F from read_dht22_with_retry
                         if !( (-40.0..=125.0).contains(&temp_f) && (0.0..=100.0).contains(&humidity) ) || (temp_f == 32.0 && humidity == 0.0) {
                             log::warn!("DHT22 reading out of range or invalid, skipping this tick: {:.2}°F, {:.2}%", temp_f, humidity);
                             continue;
                         }
 
-                        // 2. Warning Logic (Non-blocking)
-                        if temp_f < DHT22_TEMP_WARNING_F && temperature != 0.0 {
-                            let can_warn = match last_warning_time {
-                                None => true,
-                                Some(last) => last.elapsed() >= warning_cooldown,
-                            };
-
-                            if can_warn {
-                                log::warn!("(Secondary) Temp below warning level: {:.2} °F", temp_f);
-                                if let Err(e) = update_state_temp_f_humidity_and_notify_user(
-                                    monitor_user.clone(), 
-                                    read_shared_state(&sensor_pin_for_temp_monitor), 
-                                    Some(temp_f), Some(humidity), Some(true)
-                                ) {
-                                    log::error!("Warning notification failed: {:?}", e);
-                                } else {
-                                    // Only set the cooldown if notification actually succeeded
-                                    last_warning_time = Some(Instant::now());
-                                    log::info!("Warning sent. Cooldown active for 8 hours.");
-                                }
-                            }
-                        }
-
-                        // 3. Periodic Cloud Update (Robust)
-                        let should_publish = match last_publish_time {
-                            None => true,
-                            Some(last) => last.elapsed() >= publish_interval,
-                        };
-
-                        if should_publish {
-                            if let Err(error) = update_temp_and_humidity(monitor_user.clone(), Some(temp_f), Some(humidity)) {
-                                log::error!("Cloud update failed: {:?}", error);
-                                // We don't update last_publish_time here, so it tries again next time
-                            } else {
-                                last_publish_time = Some(Instant::now());
-                                log::info!("Cloud update success: {}°F, {}%", temp_f, humidity);
-                            }
-                        }
+                        bus.publish(SensorEvent::ClimateSampled { sensor_id: SENSOR_ID_SECONDARY, temp_f, humidity, timestamp: now_timestamp() });
                     },
-                    Err(_e) => log::debug!("Sensor read error"),
+                    Err(e) => {
+                        log::debug!("Sensor read error");
+                        bus.publish(SensorEvent::SensorReadFailed { sensor_id: SENSOR_ID_SECONDARY, error: format!("{:?}", e), timestamp: now_timestamp() });
+                    }
                 }
             }
         }
     });
 
     // since we are starting up, and sensor state may have changed on device power-off
-    // make a one time update and notify
-    start_update_sensor_read_and_user_update_and_notitfy(
-        startup_user.clone(),
-        &sensor_secondary_temp_pin_for_startup,
-        &sendor_door_pin_for_startup)
-        .await;
+    // make a one time update and notify (queued in the outbox until the cloud is reachable)
+    let startup_update = tokio::spawn({
+        let (bus, sensor_secondary_temp_pin, sensor_door_pin) = (bus.clone(), sensor_secondary_temp_pin.clone(), sensor_door_pin.clone());
+        async move {
+            start_update_sensor_read_and_user_update_and_notitfy(
+                &bus,
+                &sensor_secondary_temp_pin,
+                &sensor_door_pin)
+                .await;
+        }
+    });
+
+    // server time estimate, measured by the heartbeat, for command freshness
+    let clock = ServerClock::default();
+
+    // the cloud side connects in the background, everything else runs (offline) meanwhile
+    let (listener_established, mut listening) = watch::channel(false);
+    let cloud_startup = tokio::spawn(start_cloud(CloudConfig {
+        bus: bus.clone(),
+        gate,
+        store: store.clone(),
+        health: health.clone(),
+        modes: modes.clone(),
+        clock: clock.clone(),
+        user: user.clone(),
+        project_id,
+        key_file,
+        started,
+        pulse: watchdog.pulse("cloud", CLOUD_MAX_SILENCE),
+        listener_established
+    }));
+
+    // command processing runs offline too (API, MQTT and scheduled commands)
+    let command_processor = spawn_command_processor(&bus, cloud_ready, store.clone(), command_auth(&user), clock.clone(),
+        CaptureLimiter::new(Duration::from_secs(config_env_var_or("SENSOR_CAPTURE_MIN_INTERVAL_SECS", &CAPTURE_MIN_INTERVAL_SECS_DEFAULT.to_string())
+            .parse()
+            .unwrap_or(CAPTURE_MIN_INTERVAL_SECS_DEFAULT))),
+        PowerGuard::new(store.clone(), config_env_var_or("SENSOR_POWER_MAX_PER_DAY", &POWER_MAX_PER_DAY_DEFAULT.to_string())
+            .parse()
+            .unwrap_or(POWER_MAX_PER_DAY_DEFAULT),
+            matches!(config_env_var_or("SENSOR_POWER_CONFIRM_V1", "0").as_str(), "1" | "true")),
+        modes.clone(), Diagnostics { health: health.clone(), log_path, data_dir: data_dir(), started },
+        user.clone(), sensor_door_pin.clone(), sensor_primary_temp_pin.clone());
+    watchdog.task("command-processor", &command_processor);
+    subscribers.push(command_processor);
+    // scheduled commands, once the command processor listens
+    let missed = MissedPolicy::parse(&config_env_var_or("SENSOR_SCHEDULE_MISSED", "skip")).unwrap_or(MissedPolicy::Skip);
+    let mut tasks = std::env::var("SENSOR_SCHEDULES").map_or_else(|_| Vec::new(), |schedules| parse_schedules(&schedules, missed));
+    if let Ok(schedule) = std::env::var("SENSOR_MODE_SCHEDULE") {
+        tasks.extend(parse_mode_schedule(&schedule, missed));
+    }
+    if !tasks.is_empty() {
+        let tz = schedule_timezone(std::env::var("SENSOR_SCHEDULE_TZ").ok().as_deref());
+        subscribers.push(spawn_scheduler(&bus, store.clone(), tasks, tz));
+    }
+    if let Ok(host) = std::env::var("SENSOR_MQTT_HOST") {
+        subscribers.push(spawn_mqtt(&bus, MqttConfig {
+            host,
+            port: config_env_var_or("SENSOR_MQTT_PORT", &MQTT_PORT_DEFAULT.to_string()).parse().unwrap_or(MQTT_PORT_DEFAULT),
+            username: std::env::var("SENSOR_MQTT_USERNAME").ok(),
+            password: std::env::var("SENSOR_MQTT_PASSWORD").ok(),
+            prefix: config_env_var_or("SENSOR_MQTT_PREFIX", MQTT_PREFIX_DEFAULT),
+            discovery_prefix: config_env_var_or("SENSOR_MQTT_DISCOVERY_PREFIX", MQTT_DISCOVERY_PREFIX_DEFAULT),
+            node_id: config_env_var_or("SENSOR_MQTT_NODE_ID", MQTT_NODE_ID_DEFAULT),
+            climate_sensors: vec![SENSOR_ID_PRIMARY, SENSOR_ID_SECONDARY]
+        }));
+    }
 
-    // main loop to keep everything alive, should never exit
+    // Prometheus metrics on their own listener, so they are scraped whether or not the API is enabled
+    // (empty SENSOR_METRICS_BIND disables it, the API serves /metrics too)
+    let metrics_bind = config_env_var_or("SENSOR_METRICS_BIND", metrics::METRICS_BIND_DEFAULT);
+    let metrics_server = if metrics_bind.is_empty() || std::env::var("SENSOR_API_BIND").ok().as_deref() == Some(metrics_bind.as_str()) {
+        None
+    } else {
+        match metrics_bind.parse() {
+            Err(e) => {
+                log::error!("Invalid SENSOR_METRICS_BIND, continuing without the metrics listener: {:?}", e);
+                None
+            }
+            Ok(addr) => match metrics::spawn_metrics_server(addr).await {
+                Ok(metrics_server) => Some(metrics_server),
+                Err(e) => {
+                    log::error!("Metrics listener failed to start, continuing without it: {:?}", e);
+                    None
+                }
+            }
+        }
+    };
+
+    // local HTTP API (optional, for the LAN when the internet is down)
+    let api = match std::env::var("SENSOR_API_BIND").ok().map(|bind| bind.parse()) {
+        None => None,
+        Some(Err(e)) => {
+            log::error!("Invalid SENSOR_API_BIND, continuing without the HTTP API: {:?}", e);
+            None
+        }
+        Some(Ok(addr)) => {
+            let state = ApiState {
+                bus: bus.clone(),
+                health: health.clone(),
+                store: store.clone(),
+                journal: journal.clone(),
+                door_pin: sensor_door_pin.clone(),
+                modes: modes.clone(),
+                token: std::env::var("SENSOR_API_TOKEN").ok().filter(|token| !token.is_empty()),
+                started: tokio::time::Instant::now()
+            };
+            match spawn_api_server(addr, state).await {
+                Ok(api) => Some(api),
+                Err(e) => {
+                    log::error!("HTTP API failed to start, continuing without it: {:?}", e);
+                    None
+                }
+            }
+        }
+    };
+
+    // main loop to keep everything alive until SIGINT/SIGTERM
     log::info!("Monitoring pin {} (Press <ctrl-c> to exit):", GPIO_PIN_17.to_string());
-    loop {
-        thread::sleep(POLLING_DURATION);
-        if SHOW_STATE == true {
-            log::info!("{} State {:?}", Utc::now().timestamp(), read_shared_state(&sensor_door_pin));
+
+    // READY once the command listener is established, or after SENSOR_READY_TIMEOUT_SECS without the cloud
+    // (GPIO, the local store and local processing are up, commands come from the API, MQTT and the scheduler)
+    let pulse = watchdog.pulse("main-loop", MAIN_LOOP_MAX_SILENCE);
+    let systemd_watchdog = spawn_watchdog(watchdog, health.clone());
+    let ready_timeout = sleep(Duration::from_secs(config_env_var_or("SENSOR_READY_TIMEOUT_SECS", &READY_TIMEOUT_SECS_DEFAULT.to_string())
+        .parse()
+        .unwrap_or(READY_TIMEOUT_SECS_DEFAULT)));
+    tokio::pin!(ready_timeout);
+    let mut ready = false;
+
+    let mut iv = interval(POLLING_DURATION);
+    let reason = loop {
+        tokio::select! {
+            reason = shutdown.wait() => break reason,
+            established = listening.wait_for(|established| *established), if !ready => {
+                ready = true;
+                match established {
+                    Ok(_) => notify_ready(&format!("Monitoring pin {}, listening for commands", GPIO_PIN_17)),
+                    // the cloud side is gone
+                    Err(_) => notify_ready(&format!("Monitoring pin {}, cloud not connected", GPIO_PIN_17))
+                }
+            }
+            _ = &mut ready_timeout, if !ready => {
+                ready = true;
+                notify_ready(&format!("Monitoring pin {}, cloud not connected yet", GPIO_PIN_17));
+            }
+            _ = iv.tick() => {
+                pulse.beat();
+                if SHOW_STATE == true {
+                    log::info!("{} State {:?}", Utc::now().timestamp(), read_shared_state(&sensor_door_pin));
+                }
+            }
+        }
+    };
+
+    // stop inputs so nothing new is published
+    log::info!("Shutting down: {}", reason);
+    notify_stopping(&reason);
+    systemd_watchdog.abort();
+    if let Err(e) = sensor_door_pin.clear_async_interrupt() {
+        log::warn!("Failed to clear GPIO interrupt: {:?}", e);
+    }
+    // the cloud side is stopped too, or abandoned if it never connected
+    let cloud = if cloud_startup.is_finished() {
+        cloud_startup.await.ok()
+    } else {
+        log::info!("Cloud side never connected, shutting down offline");
+        cloud_startup.abort();
+        None
+    };
+    let db = match cloud {
+        Some(CloudServices { db, listener, heartbeat, subscribers: cloud_subscribers }) => {
+            if tokio::time::timeout(SHUTDOWN_LISTENER_TIMEOUT, listener.shutdown()).await.is_err() {
+                log::warn!("Stopping the Firestore listener timed out after {:?}", SHUTDOWN_LISTENER_TIMEOUT);
+            }
+            heartbeat.abort();
+            subscribers.extend(cloud_subscribers);
+            Some(db)
         }
+        None => None
+    };
+    initial_reading.abort();
+    startup_update.abort();
+    monitor.abort();
+    if let Some(api) = api {
+        api.abort();
+    }
+    if let Some(metrics_server) = metrics_server {
+        metrics_server.abort();
     }
+
+    // subscribers finish everything published before the shutdown event (pending cloud writes, telemetry)
+    bus.publish(SensorEvent::ShutdownRequested { reason: reason.clone(), timestamp: now_timestamp() });
+    if tokio::time::timeout(SHUTDOWN_DRAIN_TIMEOUT, join_subscribers(subscribers)).await.is_err() {
+        log::warn!("Pending events not flushed within {:?}, continuing shutdown", SHUTDOWN_DRAIN_TIMEOUT);
+    }
+
+    // a Python write still in flight (online: true) must not land after online: false, wait for it and refuse new ones
+    let writes = cloud_writes.clone();
+    if tokio::time::timeout(SHUTDOWN_CLOUD_WRITES_TIMEOUT, tokio::task::spawn_blocking(move || writes.close())).await.is_err() {
+        log::warn!("Cloud write still in flight after {:?}, it may land after online: false", SHUTDOWN_CLOUD_WRITES_TIMEOUT);
+    }
+    if let Some(db) = db {
+        mark_offline_on_shutdown(&db, &user, &reason).await;
+    }
+    log::info!("Shutdown complete");
+    std::process::exit(0);
+}
+
+// Cloud side settings and shared state, see start_cloud
+struct CloudConfig {
+    bus: EventBus,
+    gate: CloudGate,
+    store: SharedStore,
+    health: SharedHealth,
+    modes: ModeSwitch,
+    clock: ServerClock,
+    user: String,
+    project_id: String,
+    key_file: String,
+    started: Instant,
+    // beaten while connecting and then by the listener supervisor
+    pulse: Pulse,
+    listener_established: watch::Sender<bool>
+}
+
+// Cloud side, once connected
+struct CloudServices {
+    db: FirestoreDb,
+    listener: CommandListener,
+    heartbeat: tokio::task::JoinHandle<()>,
+    subscribers: Vec<tokio::task::JoinHandle<()>>
+}
+
+// wait for the network and Firestore (retrying until they are reachable), then start the cloud processors and
+// sinks, the heartbeat and the command listener, and open the gate so the outbox is delivered
+async fn start_cloud(config: CloudConfig) -> CloudServices {
+    let CloudConfig { bus, gate, store, health, modes, clock, user, project_id, key_file, started, pulse, listener_established } = config;
+
+    // no fixed boot delay, probe until reachable
+    let took = wait_for_connectivity(&firestore_endpoint(), &pulse).await;
+    log::info!("Cloud connectivity took {:.1}s ({:.1}s after start)", took.as_secs_f64(), started.elapsed().as_secs_f64());
+
+    let db = init_firestore_with_retry(project_id.clone(), key_file, &pulse).await;
+    log::info!("Firestore DB initialized ({:.1}s after start)", started.elapsed().as_secs_f64());
+
+    // initialize pub/sub telemetry (optional, daemon keeps running without it)
+    let telemetry = match TelemetryPublisher::new(
+        project_id,
+        config_env_var_or("PUBSUB_TOPIC_ID", PUBSUB_TOPIC_ID_DEFAULT),
+        user.clone()).await {
+        Ok(telemetry) => Some(telemetry),
+        Err(e) => {
+            log::error!("Pub/Sub telemetry initialization failed, continuing without telemetry: {:?}", e);
+            None
+        }
+    };
+
+    let mut subscribers = vec![spawn_command_result_sink(&bus, db.clone())];
+    // restarts and reboots requested before this start are complete
+    report_power_actions(&bus, store.clone()).await;
+    subscribers.push(spawn_mode_mirror(&bus, db.clone(), user.clone(), modes));
+    if let Some(telemetry) = telemetry {
+        subscribers.push(spawn_telemetry_sink(&bus, telemetry));
+    }
+
+    // periodic heartbeat so the app can tell a dead daemon from a quiet door
+    let heartbeat_config = HeartbeatConfig {
+        interval: Duration::from_secs(config_env_var_or("SENSOR_HEARTBEAT_SECS", &HEARTBEAT_INTERVAL_SECS_DEFAULT.to_string())
+            .parse()
+            .unwrap_or(HEARTBEAT_INTERVAL_SECS_DEFAULT)
+            .max(1)),
+        missed_beats: config_env_var_or("SENSOR_HEARTBEAT_MISSED_BEATS", &HEARTBEAT_MISSED_BEATS_DEFAULT.to_string())
+            .parse()
+            .unwrap_or(HEARTBEAT_MISSED_BEATS_DEFAULT)
+            .max(1)
+    };
+    // the listener also sees every heartbeat, silent past the missed beats once its stream is dead
+    let listener_max_silence_secs = heartbeat_config.interval.as_secs() * (heartbeat_config.missed_beats as u64 + 1);
+    let heartbeat = spawn_heartbeat(db.clone(), user.clone(), health.clone(), clock, heartbeat_config);
+
+    // listen for refresh requests (commands), recreating the listener if it dies
+    let listener = spawn_command_listener(db.clone(), &bus, user, health, Duration::from_secs(
+        config_env_var_or("SENSOR_LISTENER_MAX_SILENCE_SECS", &listener_max_silence_secs.to_string())
+            .parse()
+            .unwrap_or(listener_max_silence_secs)
+            .max(60)), pulse, listener_established);
+
+    // deliver everything queued while offline
+    gate.open(db.clone());
+    log::info!("Cloud side up ({:.1}s after start)", started.elapsed().as_secs_f64());
+
+    CloudServices { db, listener, heartbeat, subscribers }
+}
+
+// write online: false to the sensor and heartbeat documents, bounded so a dead network can't hold up the exit
+async fn mark_offline_on_shutdown(db: &FirestoreDb, user: &str, reason: &str) {
+    match tokio::time::timeout(SHUTDOWN_OFFLINE_TIMEOUT, mark_offline(db, user, reason)).await {
+        Ok(Ok(())) => log::info!("Marked offline"),
+        Ok(Err(e)) => log::error!("Failed to mark offline: {:?}", e),
+        Err(_) => log::error!("Marking offline timed out after {:?}", SHUTDOWN_OFFLINE_TIMEOUT)
+    }
+    match tokio::time::timeout(SHUTDOWN_OFFLINE_TIMEOUT, mark_heartbeat_offline(db, user)).await {
+        Ok(Ok(())) => log::info!("Heartbeat marked offline"),
+        Ok(Err(e)) => log::error!("Failed to mark heartbeat offline: {:?}", e),
+        Err(_) => log::error!("Marking heartbeat offline timed out after {:?}", SHUTDOWN_OFFLINE_TIMEOUT)
+    }
+}
+
+// wait for every task, ignoring panics/cancellation (they are logged by the tasks)
+async fn join_subscribers(handles: Vec<tokio::task::JoinHandle<()>>) {
+    for handle in handles {
+        let _ = handle.await;
+    }
+}
+
+/// Write online: false with the shutdown reason and time, leaving the other sensor fields as they are
+pub async fn mark_offline(db: &FirestoreDb, user: &str, reason: &str) -> FirestoreResult<()> {
+    db.fluent()
+        .update()
+        .fields(["online", "shutdown_reason", "shutdown_timestamp"])
+        .in_col(SENSORS_COLLECTION)
+        .document_id(user)
+        .object(&SensorOfflineObject {
+            online: false,
+            shutdown_reason: reason.to_string(),
+            shutdown_timestamp: now_timestamp()
+        })
+        .execute::<()>()
+        .await
 }
 
 pub fn config_env_var(name: &str) -> Result<String, String> {
     std::env::var(name).map_err(|e| format!("{}: {}", name, e))
 }
 
+/// Directory for state that must survive reboots (outbox, local store).
+pub fn data_dir() -> PathBuf {
+    PathBuf::from(config_env_var_or("SENSOR_DATA_DIR", SENSOR_DATA_DIR_DEFAULT))
+}
+
+/// Local store retention from SENSOR_STORE_RAW_DAYS and SENSOR_STORE_HOURLY_DAYS.
+pub fn store_retention() -> Retention {
+    Retention {
+        raw_days: config_env_var_or("SENSOR_STORE_RAW_DAYS", &STORE_RAW_DAYS_DEFAULT.to_string())
+            .parse()
+            .unwrap_or(STORE_RAW_DAYS_DEFAULT),
+        hourly_days: config_env_var_or("SENSOR_STORE_HOURLY_DAYS", &STORE_HOURLY_DAYS_DEFAULT.to_string())
+            .parse()
+            .unwrap_or(STORE_HOURLY_DAYS_DEFAULT)
+    }
+}
+
+/// Command authentication from SENSOR_COMMAND_ISSUERS (default: `user` with the admin role),
+/// SENSOR_COMMAND_SECRET and SENSOR_COMMAND_REQUIRE_SIGNATURE.
+pub fn command_auth(user: &str) -> CommandAuth {
+    CommandAuth::new(
+        CommandAuth::parse_issuers(&config_env_var_or("SENSOR_COMMAND_ISSUERS", &CommandAuth::default_issuers(user))),
+        std::env::var("SENSOR_COMMAND_SECRET").ok().filter(|secret| !secret.is_empty()).map(String::into_bytes),
+        matches!(config_env_var_or("SENSOR_COMMAND_REQUIRE_SIGNATURE", "0").as_str(), "1" | "true"))
+}
+
+pub fn config_env_var_or(name: &str, default: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+/// Ask systemd to restart the daemon's unit (SENSOR_SYSTEMD_UNIT), without waiting for it.
+pub fn restart_daemon() -> std::io::Result<()> {
+    let status = Command::new("systemctl")
+        .arg("--no-block")
+        .arg("restart")
+        .arg(config_env_var_or("SENSOR_SYSTEMD_UNIT", SYSTEMD_UNIT_DEFAULT))
+        .status()?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!("systemctl {}", status)));
+    }
+    Ok(())
+}
+
 pub fn reboot() -> std::io::Result<()> {
-    Command::new("systemctl")
+    let status = Command::new("systemctl")
         .arg("reboot")
         .status()?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!("systemctl {}", status)));
+    }
     Ok(())
 }
 
 // helper to read shared pin state for door open/closed sensor
-pub fn read_shared_state(pin: &Arc<Mutex<InputPin>>) -> State {
-    let guard = pin.lock().unwrap();
-    if guard.read() == rppal::gpio::Level::High {
-        State::Open
-    } else {
-        State::Closed
-    }
+pub fn read_shared_state(pin: &DoorPin) -> State {
+    State::from_level(pin.read())
 }
 
-pub fn update_state_temp_f_humidity_and_notify_user(user: String, state: State, temp_f: Option<f32>, humidity: Option<f32>, force_notify: Option<bool>) -> PyResult<()> {
+// Cloud update rejected by the Python side's validation (return code 3), retrying can't help.
+// Every other failure (return code 1, exceptions) may be transient.
+pyo3::create_exception!(sensor_nhargrex, UpdateRejected, PyException);
 
-    let s : String = match state {
-        State::Open => "OPEN".to_lowercase().to_string(),
-        State::Closed => "CLOSED".to_lowercase().to_string(),
-    };
+/// Write state/temp/humidity with the original event `timestamp`, notifying on change (or `force_notify`).
+/// `actions` (from the mode) say whether to notify and record video, `delayed` marks a notification
+/// replayed from the outbox well after the event.
+#[allow(clippy::too_many_arguments)]
+pub fn update_state_temp_f_humidity_and_notify_user(user: String, state: State, temp_f: Option<f32>, humidity: Option<f32>, force_notify: Option<bool>, actions: DoorActions, timestamp: f64, delayed: bool) -> PyResult<()> {
+
+    let s : String = state.as_str().to_string();
 
     let t = match temp_f {
         Some(t) => t,
@@ -548,16 +804,21 @@ pub fn update_state_temp_f_humidity_and_notify_user(user: String, state: State,
         //  update_state_and_notify_user
         let result: i32 = firebase
             .getattr("update_state_and_notify_user")?
-            .call1((user, s, t, h, f,))?
+            .call1((user, s, t, h, f, timestamp, delayed, actions.notify, actions.video,))?
             .extract()?;
 
-        if result > 0 { return Err(PyValueError::new_err("Unexpected error")) };
+        if result == 1 { return Err(PyRuntimeError::new_err("Unexpected error")) };
+        if result == 3 { return Err(UpdateRejected::new_err("update_state_and_notify_user rejected the update")) };
+        if result == 2 {
+            log::info!("User notified: {}", state.as_str());
+            metrics::NOTIFICATIONS.inc();
+        }
         
         Ok(())
     })
 }
 
-pub fn publish_temp_and_humidity(user: String, temp_f: Option<f32>, humidity: Option<f32>) -> PyResult<()> {
+pub fn update_temp_and_humidity(user: String, temp_f: Option<f32>, humidity: Option<f32>, timestamp: f64) -> PyResult<()> {
 
     let t = match temp_f {
         Some(t) => t,
@@ -570,7 +831,7 @@ pub fn publish_temp_and_humidity(user: String, temp_f: Option<f32>, humidity: Op
     };
 
     if (t == 0.0) || (h == 0.0) {
-        log::warn!("publish_temp_and_humidity called with invalid temp/humidity: t={}, h={}", t, h);
+        log::warn!("update_temp_and_humidity called with invalid temp/humidity: t={}, h={}", t, h);
         return Ok(());
     }
 
@@ -578,52 +839,55 @@ pub fn publish_temp_and_humidity(user: String, temp_f: Option<f32>, humidity: Op
         let firebase = PyModule::import_bound(py, "sensors_nhargrex_firestore")?;
         //  update_state_and_notify_user
         let result: i32 = firebase
-            .getattr("publish_temp_and_humidity")?
-            .call1((user, t, h,))?
+            .getattr("update_temp_and_humidity")?
+            .call1((user, t, h, timestamp,))?
             .extract()?;
 
-        if result > 0 { return Err(PyValueError::new_err("Unexpected error")) };
+        if result == 3 { return Err(UpdateRejected::new_err("update_temp_and_humidity rejected the update")) };
+        if result > 0 { return Err(PyRuntimeError::new_err("Unexpected error")) };
         
         Ok(())
     })
 }
 
-pub fn update_temp_and_humidity(user: String, temp_f: Option<f32>, humidity: Option<f32>) -> PyResult<()> {
-
-    let t = match temp_f {
-        Some(t) => t,
-        None => 0.0
-    };
-
-    let h = match humidity {
-        Some(h) => h,
-        None => 0.0
-    };
-
-    if (t == 0.0) || (h == 0.0) {
-        log::warn!("update_temp_and_humidity called with invalid temp/humidity: t={}, h={}", t, h);
-        return Ok(());
-    }
-
+/// Record a clip (`seconds` long) or a photo and upload it to Storage, returning the storage path.
+pub fn capture_media(user: String, kind: CaptureKind, seconds: u32) -> PyResult<String> {
     Python::with_gil(|py| {
         let firebase = PyModule::import_bound(py, "sensors_nhargrex_firestore")?;
-        //  update_state_and_notify_user
-        let result: i32 = firebase
-            .getattr("update_temp_and_humidity")?
-            .call1((user, t, h,))?
-            .extract()?;
+        firebase
+            .getattr("capture_media")?
+            .call1((user, kind.as_str(), seconds,))?
+            .extract()
+    })
+}
 
-        if result > 0 { return Err(PyValueError::new_err("Unexpected error")) };
-        
+/// Send the user a notification with `message`.
+pub fn notify_user(user: String, message: String) -> PyResult<()> {
+    Python::with_gil(|py| {
+        let firebase = PyModule::import_bound(py, "sensors_nhargrex_firestore")?;
+        firebase
+            .getattr("send_notification")?
+            .call1((user, message,))?;
         Ok(())
     })
 }
 
-pub async fn read_dht22_with_retry(sensor_temp_pin: &Arc<Mutex<IoPin>>) -> Result<Reading, ReadingError> {
+/// Upload a diagnostics bundle to Storage, returning its path.
+pub fn upload_diagnostics(user: String, bundle: &Path) -> PyResult<String> {
+    Python::with_gil(|py| {
+        let firebase = PyModule::import_bound(py, "sensors_nhargrex_firestore")?;
+        firebase
+            .getattr("upload_diagnostics")?
+            .call1((user, bundle.to_string_lossy().to_string(),))?
+            .extract()
+    })
+}
+
+pub async fn read_dht22_with_retry(sensor_temp_pin: &TempPin) -> Result<Reading, ReadingError> {
     const MAX_RETRIES: u8 = 5;
     const RETRY_DELAY: Duration = Duration::from_secs(5);
     for attempt in 1..=MAX_RETRIES {
-        match read_dht22(sensor_temp_pin) {
+        match sensor_temp_pin.read() {
             Ok(Reading { temperature, humidity }) => {
                 let temp_f = temperature * 9.0 / 5.0 + 32.0;
                 return Result::Ok(Reading {
@@ -644,8 +908,8 @@ pub async fn read_dht22_with_retry(sensor_temp_pin: &Arc<Mutex<IoPin>>) -> Resul
     return Result::Err(ReadingError::Timeout);
 }
 
-pub fn read_dht22_once(sensor_temp_pin: &Arc<Mutex<IoPin>>) -> Result<Reading, ReadingError> {
-    match read_dht22(sensor_temp_pin) {
+pub fn read_dht22_once(sensor_temp_pin: &TempPin) -> Result<Reading, ReadingError> {
+    match sensor_temp_pin.read() {
         Ok(Reading { temperature, humidity }) => {
             let temp_f = temperature * 9.0 / 5.0 + 32.0;
             return Result::Ok(Reading {
@@ -660,33 +924,48 @@ pub fn read_dht22_once(sensor_temp_pin: &Arc<Mutex<IoPin>>) -> Result<Reading, R
     }
 }
 
-/// Initialize Firestore with retries and exponential backoff
+/// Connect to Firestore, or to the emulator when FIRESTORE_EMULATOR_HOST is set
+pub async fn connect_firestore(project_id: String, key_file: String) -> FirestoreResult<FirestoreDb> {
+    match std::env::var("FIRESTORE_EMULATOR_HOST") {
+        Ok(host) => {
+            let url = if host.starts_with("http") { host } else { format!("http://{}", host) };
+            log::info!("Using Firestore emulator at {}", url);
+            FirestoreDb::with_options(FirestoreDbOptions::new(project_id).with_firebase_api_url(url)).await
+        }
+        Err(_) => {
+            FirestoreDb::with_options_service_account_key_file(
+                FirestoreDbOptions::new(project_id),
+                key_file.into(),
+            ).await
+        }
+    }
+}
+
+/// Connect to Firestore, retrying with backoff (2s doubling to 5 minutes) until it succeeds.
 pub async fn init_firestore_with_retry(
     project_id: String,
     key_file: String,
-    max_attempts: usize,
-) -> Result<FirestoreDb> {
+    pulse: &Pulse
+) -> FirestoreDb {
     let mut attempt = 0;
     let mut delay = Duration::from_secs(2);
 
     loop {
+        pulse.beat();
         attempt += 1;
         log::info!("Attempt {} to initialize Firestore DB", attempt);
 
         // Wrap in timeout so we don't hang forever
         let result = tokio::time::timeout(
             Duration::from_secs(20),
-            FirestoreDb::with_options_service_account_key_file(
-                FirestoreDbOptions::new(project_id.clone()),
-                key_file.clone().into(),
-            ),
+            connect_firestore(project_id.clone(), key_file.clone()),
         )
         .await;
 
         match result {
             Ok(Ok(db)) => {
                 log::info!("Firestore DB initialized successfully on attempt {}", attempt);
-                return Ok(db);
+                return db;
             }
             Ok(Err(e)) => {
                 log::warn!("Firestore init failed: {:?}", e);
@@ -696,30 +975,23 @@ pub async fn init_firestore_with_retry(
             }
         }
 
-        if attempt >= max_attempts {
-            return Err(anyhow::anyhow!(
-                "Failed to initialize Firestore after {} attempts",
-                attempt
-            ));
-        }
-
         log::info!("Retrying in {:?}...", delay);
         sleep(delay).await;
-        delay *= 2; // exponential backoff
+        delay = (delay * 2).min(FIRESTORE_INIT_MAX_DELAY); // exponential backoff
     }
 }
 
 
 pub async fn start_update_sensor_read_and_user_update_and_notitfy(
-    startup_user: String,
-    sensor_secondary_temp_pin: &Arc<Mutex<IoPin>>,
-    sendor_door_pin_for_startup: &Arc<Mutex<InputPin>>
+    bus: &EventBus,
+    sensor_secondary_temp_pin: &TempPin,
+    sendor_door_pin_for_startup: &DoorPin
 ) {
     const MAX_RETRIES: u8 = 3;
     const RETRY_DELAY: Duration = Duration::from_secs(5);
 
     for attempt in 1..=MAX_RETRIES {
-        match read_dht22(sensor_secondary_temp_pin) {
+        match sensor_secondary_temp_pin.read() {
             Ok(Reading { temperature, humidity }) => {
                 let temp_f = temperature * 9.0 / 5.0 + 32.0;
                 log::info!(
@@ -742,18 +1014,20 @@ pub async fn start_update_sensor_read_and_user_update_and_notitfy(
                     );  
                 }
                 else {
-                    if let Err(error) = update_state_temp_f_humidity_and_notify_user(
-                        startup_user.clone(),
-                        read_shared_state(&sendor_door_pin_for_startup),
-                        Some(temp_f),
-                        Some(humidity),
-                        Some(false)
-                    ) {
-                        log::error!(
-                            "Error on update_state_temp_f_humidity_and_notify_user {:?}",
-                            error
-                        );
-                    }
+                    let timestamp = now_timestamp();
+                    let correlation_id = bus.publish(SensorEvent::ClimateSampled {
+                        sensor_id: SENSOR_ID_SECONDARY,
+                        temp_f,
+                        humidity,
+                        timestamp
+                    });
+                    bus.publish_correlated(correlation_id, SensorEvent::StateReported {
+                        state: read_shared_state(&sendor_door_pin_for_startup),
+                        temp_f,
+                        humidity,
+                        force_notify: false,
+                        timestamp
+                    });
                 }
                 break;
             }
@@ -0,0 +1,144 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Prometheus metrics, served at /metrics on their own listener (SENSOR_METRICS_BIND,
+// default 0.0.0.0:9464) so they can be scraped without enabling the HTTP API,
+// and by the local HTTP API when it is enabled.
+//
+// Gauges are kept up to date from the event bus, counters and histograms are
+// updated where the work happens (DHT reads, debouncer, commands, cloud calls).
+//
+use crate::events::{EventBus, SensorEvent, State};
+use lazy_static::lazy_static;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use prometheus::{Encoder, GaugeVec, Gauge, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use std::net::SocketAddr;
+use tokio::task::JoinHandle;
+
+pub const METRICS_BIND_DEFAULT: &str = "0.0.0.0:9464";
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new();
+
+    pub static ref TEMPERATURE: GaugeVec = register(GaugeVec::new(
+        Opts::new("sensor_temperature_fahrenheit", "Last accepted temperature reading"), &["sensor_id"]).unwrap());
+    pub static ref HUMIDITY: GaugeVec = register(GaugeVec::new(
+        Opts::new("sensor_humidity_percent", "Last accepted humidity reading"), &["sensor_id"]).unwrap());
+    pub static ref DOOR_OPEN: Gauge = register(Gauge::new(
+        "sensor_door_open", "Door state (1 = open, 0 = closed)").unwrap());
+    pub static ref DOOR_LAST_CHANGE: Gauge = register(Gauge::new(
+        "sensor_door_last_change_timestamp_seconds", "Unix time of the last debounced door change").unwrap());
+
+    pub static ref DHT_READS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("sensor_dht_reads_total", "DHT22 read attempts by outcome (ok, timeout, checksum, gpio, out_of_range)"), &["sensor_id", "outcome"]).unwrap());
+    pub static ref DHT_READ_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("sensor_dht_read_duration_seconds", "DHT22 single read latency")
+            .buckets(vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]), &["sensor_id"]).unwrap());
+    pub static ref DOOR_INTERRUPTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("sensor_door_interrupts_total", "Door interrupt edges, debounced (reported) or suppressed"), &["result"]).unwrap());
+    pub static ref COMMANDS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("sensor_commands_total", "Commands run by command type"), &["command"]).unwrap());
+    pub static ref CLOUD_WRITES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("sensor_cloud_writes_total", "Cloud writes by call and result (success, failure)"), &["call", "result"]).unwrap());
+    pub static ref CLOUD_CALL_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("sensor_cloud_call_duration_seconds", "Cloud call latency")
+            .buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]), &["call"]).unwrap());
+    pub static ref NOTIFICATIONS: IntCounter = register(IntCounter::new(
+        "sensor_notifications_total", "Notifications sent to the user").unwrap());
+    pub static ref OUTBOX_DEPTH: IntGauge = register(IntGauge::new(
+        "sensor_outbox_entries", "Cloud updates queued in the outbox").unwrap());
+    pub static ref MODE: GaugeVec = register(GaugeVec::new(
+        Opts::new("sensor_mode", "Security mode (1 = current)"), &["mode"]).unwrap());
+    pub static ref CLOCK_SKEW: Gauge = register(Gauge::new(
+        "sensor_clock_skew_seconds", "Firestore server time minus device time, measured by the heartbeat").unwrap());
+    pub static ref EVENT_BUS_LAGGED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("sensor_event_bus_lagged_total", "Events skipped by bus subscribers that fell behind"), &["subscriber"]).unwrap());
+    pub static ref LISTENER_RESTARTS: IntCounter = register(IntCounter::new(
+        "sensor_listener_restarts_total", "Firestore command listener recreated after failing to start or going silent").unwrap());
+}
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: M) -> M {
+    REGISTRY.register(Box::new(metric.clone())).expect("metric registered once");
+    metric
+}
+
+/// Register every metric up front, so they are exported before their first update.
+pub fn init() {
+    lazy_static::initialize(&TEMPERATURE);
+    lazy_static::initialize(&HUMIDITY);
+    lazy_static::initialize(&DOOR_OPEN);
+    lazy_static::initialize(&DOOR_LAST_CHANGE);
+    lazy_static::initialize(&DHT_READS);
+    lazy_static::initialize(&DHT_READ_DURATION);
+    lazy_static::initialize(&DOOR_INTERRUPTS);
+    lazy_static::initialize(&COMMANDS);
+    lazy_static::initialize(&CLOUD_WRITES);
+    lazy_static::initialize(&CLOUD_CALL_DURATION);
+    lazy_static::initialize(&NOTIFICATIONS);
+    lazy_static::initialize(&OUTBOX_DEPTH);
+    lazy_static::initialize(&MODE);
+    lazy_static::initialize(&CLOCK_SKEW);
+    lazy_static::initialize(&EVENT_BUS_LAGGED);
+    lazy_static::initialize(&LISTENER_RESTARTS);
+}
+
+/// Record the outcome of a cloud call taking `seconds`.
+pub fn observe_cloud_call(call: &str, seconds: f64, success: bool) {
+    CLOUD_CALL_DURATION.with_label_values(&[call]).observe(seconds);
+    CLOUD_WRITES.with_label_values(&[call, if success { "success" } else { "failure" }]).inc();
+}
+
+/// Text exposition of every registered metric.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        log::error!("Metrics encoding failed: {:?}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+/// Response for GET /metrics.
+pub async fn serve() -> impl IntoResponse {
+    ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], render())
+}
+
+/// Serve /metrics (only) on `addr` until aborted.
+pub async fn spawn_metrics_server(addr: SocketAddr) -> std::io::Result<JoinHandle<()>> {
+    let app = Router::new().route("/metrics", get(serve));
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    log::info!("Metrics listening on {}", addr);
+
+    Ok(tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            log::error!("Metrics server failed: {:?}", e);
+        }
+    }))
+}
+
+/// Keep the reading and door gauges up to date until shutdown.
+pub fn spawn_metrics_sink(bus: &EventBus) -> JoinHandle<()> {
+    let mut events = bus.subscribe("metrics");
+
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            match event {
+                SensorEvent::ClimateSampled { sensor_id, temp_f, humidity, .. } => {
+                    TEMPERATURE.with_label_values(&[sensor_id]).set(temp_f as f64);
+                    HUMIDITY.with_label_values(&[sensor_id]).set(humidity as f64);
+                }
+                SensorEvent::DoorChanged { state, timestamp } => {
+                    DOOR_OPEN.set(if state == State::Open { 1.0 } else { 0.0 });
+                    DOOR_LAST_CHANGE.set(timestamp);
+                }
+                SensorEvent::StateReported { state, .. } => {
+                    DOOR_OPEN.set(if state == State::Open { 1.0 } else { 0.0 });
+                }
+                SensorEvent::ShutdownRequested { .. } => break,
+                _ => {}
+            }
+        }
+    })
+}
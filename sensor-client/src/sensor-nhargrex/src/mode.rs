@@ -0,0 +1,187 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Security mode (armed, home, disarmed): what a door change triggers.
+//
+//   armed     notification and video (the original behaviour, default)
+//   home      notification only
+//   disarmed  silent, the state is still written to the cloud and logged
+//
+// Forced notifications (refresh command, low temperature warning) are sent in
+// every mode, video follows the mode. The mode is persisted in the local store,
+// where every change is audited (mode_changes), and mirrored to
+// sensorsMode/{user}. It changes through the set_mode command, also run by
+// the scheduler (scheduler.rs).
+//
+use crate::events::{EventBus, SensorEvent, now_timestamp};
+use crate::metrics;
+use crate::store::SharedStore;
+use firestore::FirestoreDb;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+pub const SENSORS_MODE_COLLECTION: &str = "sensorsMode";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Armed,
+    Home,
+    Disarmed
+}
+
+impl Mode {
+    pub fn parse(s: &str) -> Option<Mode> {
+        match s {
+            "armed" => Some(Mode::Armed),
+            "home" => Some(Mode::Home),
+            "disarmed" => Some(Mode::Disarmed),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Armed => "armed",
+            Mode::Home => "home",
+            Mode::Disarmed => "disarmed"
+        }
+    }
+
+    /// What a door change triggers in this mode.
+    pub fn door_actions(&self) -> DoorActions {
+        match self {
+            Mode::Armed => DoorActions { notify: true, video: true },
+            Mode::Home => DoorActions { notify: true, video: false },
+            Mode::Disarmed => DoorActions { notify: false, video: false }
+        }
+    }
+}
+
+/// Notification and video for a door change (both for outbox entries written before modes).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DoorActions {
+    pub notify: bool,
+    pub video: bool
+}
+
+impl Default for DoorActions {
+    fn default() -> DoorActions {
+        Mode::Armed.door_actions()
+    }
+}
+
+/// Audited mode change.
+#[derive(Debug, Clone, Serialize)]
+pub struct ModeChange {
+    pub timestamp: f64,
+    pub mode: Mode,
+    pub previous: Mode,
+    /// "command" or "schedule".
+    pub source: String,
+    /// Issuer and command id for command changes.
+    pub issuer: Option<String>,
+    pub command_id: Option<String>
+}
+
+/// Current mode, shared by the cloud sink, the command processor and the schedule.
+#[derive(Clone)]
+pub struct ModeSwitch {
+    current: Arc<Mutex<Mode>>,
+    store: SharedStore,
+    bus: EventBus
+}
+
+impl ModeSwitch {
+    /// Mode from the last audited change (armed if none).
+    pub fn load(store: SharedStore, bus: &EventBus) -> ModeSwitch {
+        let mode = match store.lock().unwrap().last_mode() {
+            Ok(mode) => mode.unwrap_or_default(),
+            Err(e) => {
+                log::error!("Failed to load the mode, using {}: {:?}", Mode::default().as_str(), e);
+                Mode::default()
+            }
+        };
+        log::info!("Mode: {}", mode.as_str());
+        metrics::MODE.with_label_values(&[mode.as_str()]).set(1.0);
+        ModeSwitch { current: Arc::new(Mutex::new(mode)), store, bus: bus.clone() }
+    }
+
+    pub fn current(&self) -> Mode {
+        *self.current.lock().unwrap()
+    }
+
+    /// Switch to `mode`, auditing and publishing the change. Returns None if already in `mode`.
+    /// SQLite calls block, call from the blocking pool.
+    pub fn set(&self, mode: Mode, source: &str, issuer: Option<String>, command_id: Option<String>) -> rusqlite::Result<Option<ModeChange>> {
+        let mut current = self.current.lock().unwrap();
+        if *current == mode {
+            return Ok(None);
+        }
+
+        let change = ModeChange { timestamp: now_timestamp(), mode, previous: *current, source: source.to_string(), issuer, command_id };
+        self.store.lock().unwrap().insert_mode_change(&change)?;
+        *current = mode;
+
+        log::info!("Mode changed from {} to {} by {}{}", change.previous.as_str(), mode.as_str(), change.source,
+            change.issuer.as_deref().map(|issuer| format!(" ({})", issuer)).unwrap_or_default());
+        metrics::MODE.with_label_values(&[change.previous.as_str()]).set(0.0);
+        metrics::MODE.with_label_values(&[mode.as_str()]).set(1.0);
+        self.bus.publish(SensorEvent::ModeChanged { change: change.clone() });
+        Ok(Some(change))
+    }
+}
+
+// sensorsMode/{user}
+#[derive(Debug, Clone, Serialize)]
+struct ModeObject {
+    mode: Mode,
+    previous: Option<Mode>,
+    source: Option<String>,
+    issuer: Option<String>,
+    timestamp: f64
+}
+
+async fn write_mode(db: &FirestoreDb, user: &str, mode: &ModeObject) {
+    let started = Instant::now();
+    let result = db.fluent()
+        .update()
+        .in_col(SENSORS_MODE_COLLECTION)
+        .document_id(user)
+        .object(mode)
+        .execute::<()>()
+        .await;
+    metrics::observe_cloud_call("mode", started.elapsed().as_secs_f64(), result.is_ok());
+    if let Err(e) = result {
+        log::error!("Failed to mirror mode {} to Firestore: {:?}", mode.mode.as_str(), e);
+    }
+}
+
+/// Mirror the current mode, and every change, to sensorsMode/{user}.
+pub fn spawn_mode_mirror(bus: &EventBus, db: FirestoreDb, user: String, modes: ModeSwitch) -> JoinHandle<()> {
+    let mut events = bus.subscribe("mode");
+
+    tokio::spawn(async move {
+        write_mode(&db, &user, &ModeObject { mode: modes.current(), previous: None, source: None, issuer: None, timestamp: now_timestamp() }).await;
+
+        while let Some(event) = events.recv().await {
+            match event {
+                SensorEvent::ModeChanged { change } => {
+                    write_mode(&db, &user, &ModeObject {
+                        mode: change.mode,
+                        previous: Some(change.previous),
+                        source: Some(change.source),
+                        issuer: change.issuer,
+                        timestamp: change.timestamp
+                    }).await;
+                }
+                SensorEvent::ShutdownRequested { .. } => break,
+                _ => {}
+            }
+        }
+    })
+}
@@ -0,0 +1,225 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// MQTT publisher with Home Assistant discovery.
+//
+// Enabled by SENSOR_MQTT_HOST. Topics (prefix SENSOR_MQTT_PREFIX, default sensor-nhargrex):
+//   <prefix>/availability                online|offline (retained, offline is the last will)
+//   <prefix>/door/state                  open|closed (retained)
+//   <prefix>/<sensor_id>/temperature     °F (retained)
+//   <prefix>/<sensor_id>/humidity        % (retained)
+//   <prefix>/command                     refresh|status or a command object (subscribed)
+//
+// Discovery configs (binary_sensor garage_door, temperature and humidity sensors and
+// a refresh button) are published under SENSOR_MQTT_DISCOVERY_PREFIX (default
+// homeassistant) on every connect, so they survive broker restarts.
+//
+use crate::events::{EventBus, SensorEvent, now_timestamp};
+use crate::protocol::{Command, CommandRequest, CommandSource};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS};
+use serde_json::json;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration};
+
+pub const MQTT_PORT_DEFAULT: u16 = 1883;
+pub const MQTT_PREFIX_DEFAULT: &str = "sensor-nhargrex";
+pub const MQTT_DISCOVERY_PREFIX_DEFAULT: &str = "homeassistant";
+pub const MQTT_NODE_ID_DEFAULT: &str = "sensor_nhargrex";
+const MQTT_KEEP_ALIVE: Duration = Duration::from_secs(30);
+const MQTT_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const MQTT_DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const MQTT_REQUEST_CAPACITY: usize = 100;
+const MQTT_COMMAND_DOC_ID: &str = "mqtt";
+
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub prefix: String,
+    pub discovery_prefix: String,
+    pub node_id: String,
+    pub climate_sensors: Vec<&'static str>
+}
+
+impl MqttConfig {
+    fn topic(&self, suffix: &str) -> String {
+        format!("{}/{}", self.prefix, suffix)
+    }
+
+    fn availability_topic(&self) -> String {
+        self.topic("availability")
+    }
+
+    fn door_topic(&self) -> String {
+        self.topic("door/state")
+    }
+
+    fn command_topic(&self) -> String {
+        self.topic("command")
+    }
+
+    fn climate_topic(&self, sensor_id: &str, measurement: &str) -> String {
+        self.topic(&format!("{}/{}", sensor_id, measurement))
+    }
+
+    // (topic, payload) for every Home Assistant discovery config
+    fn discovery(&self) -> Vec<(String, String)> {
+        let device = json!({
+            "identifiers": [self.node_id],
+            "name": "Garage sensor",
+            "manufacturer": "nhargrex",
+            "model": "sensor-nhargrex",
+            "sw_version": env!("CARGO_PKG_VERSION")
+        });
+
+        let mut configs = vec![
+            (
+                format!("{}/binary_sensor/{}/door/config", self.discovery_prefix, self.node_id),
+                json!({
+                    "name": "Garage door",
+                    "unique_id": format!("{}_door", self.node_id),
+                    "device_class": "garage_door",
+                    "state_topic": self.door_topic(),
+                    "payload_on": "open",
+                    "payload_off": "closed",
+                    "availability_topic": self.availability_topic(),
+                    "device": device
+                })
+            ),
+            (
+                format!("{}/button/{}/refresh/config", self.discovery_prefix, self.node_id),
+                json!({
+                    "name": "Refresh",
+                    "unique_id": format!("{}_refresh", self.node_id),
+                    "command_topic": self.command_topic(),
+                    "payload_press": "refresh",
+                    "availability_topic": self.availability_topic(),
+                    "device": device
+                })
+            )
+        ];
+
+        for sensor_id in &self.climate_sensors {
+            let object_id = sensor_id.replace('-', "_");
+            for (measurement, device_class, unit) in [("temperature", "temperature", "°F"), ("humidity", "humidity", "%")] {
+                configs.push((
+                    format!("{}/sensor/{}/{}_{}/config", self.discovery_prefix, self.node_id, object_id, measurement),
+                    json!({
+                        "name": format!("{} {}", sensor_id, measurement),
+                        "unique_id": format!("{}_{}_{}", self.node_id, object_id, measurement),
+                        "device_class": device_class,
+                        "state_class": "measurement",
+                        "unit_of_measurement": unit,
+                        "state_topic": self.climate_topic(sensor_id, measurement),
+                        "availability_topic": self.availability_topic(),
+                        "device": device
+                    })
+                ));
+            }
+        }
+
+        configs.into_iter().map(|(topic, payload)| (topic, payload.to_string())).collect()
+    }
+}
+
+/// Connect to the broker and mirror door state and readings to MQTT until shutdown.
+pub fn spawn_mqtt(bus: &EventBus, config: MqttConfig) -> JoinHandle<()> {
+    let mut events = bus.subscribe("mqtt");
+    let bus = bus.clone();
+
+    let mut options = MqttOptions::new(config.node_id.clone(), config.host.clone(), config.port);
+    options.set_keep_alive(MQTT_KEEP_ALIVE);
+    options.set_last_will(LastWill::new(config.availability_topic(), "offline", QoS::AtLeastOnce, true));
+    if let Some(username) = &config.username {
+        options.set_credentials(username.clone(), config.password.clone().unwrap_or_default());
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, MQTT_REQUEST_CAPACITY);
+
+    tokio::spawn(async move {
+        log::info!("MQTT client started for {}:{}", config.host, config.port);
+
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Some(SensorEvent::DoorChanged { state, .. }) | Some(SensorEvent::StateReported { state, .. }) => {
+                        publish(&client, config.door_topic(), state.as_str().to_string());
+                    }
+                    Some(SensorEvent::ClimateSampled { sensor_id, temp_f, humidity, .. }) => {
+                        publish(&client, config.climate_topic(sensor_id, "temperature"), format!("{:.1}", temp_f));
+                        publish(&client, config.climate_topic(sensor_id, "humidity"), format!("{:.1}", humidity));
+                    }
+                    Some(SensorEvent::ShutdownRequested { .. }) | None => break,
+                    Some(_) => {}
+                },
+                polled = eventloop.poll() => match polled {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => on_connect(&client, &config),
+                    Ok(Event::Incoming(Packet::Publish(message))) if message.topic == config.command_topic() => {
+                        on_command(&bus, &String::from_utf8_lossy(&message.payload));
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        // the next poll reconnects
+                        log::warn!("MQTT connection error, retrying in {:?}: {:?}", MQTT_RECONNECT_DELAY, e);
+                        sleep(MQTT_RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        }
+
+        // the last will only fires on an unclean disconnect, so say offline explicitly
+        publish(&client, config.availability_topic(), "offline".to_string());
+        if let Err(e) = client.try_disconnect() {
+            log::warn!("MQTT disconnect failed: {:?}", e);
+        }
+        let _ = timeout(MQTT_DISCONNECT_TIMEOUT, drain(&mut eventloop)).await;
+        log::info!("MQTT client exiting");
+    })
+}
+
+fn on_connect(client: &AsyncClient, config: &MqttConfig) {
+    log::info!("MQTT connected, publishing discovery and subscribing to {}", config.command_topic());
+    if let Err(e) = client.try_subscribe(config.command_topic(), QoS::AtLeastOnce) {
+        log::error!("MQTT subscribe failed: {:?}", e);
+    }
+    for (topic, payload) in config.discovery() {
+        publish(client, topic, payload);
+    }
+    publish(client, config.availability_topic(), "online".to_string());
+}
+
+fn on_command(bus: &EventBus, payload: &str) {
+    let command = match payload.trim() {
+        "refresh" | "0" => Command::Refresh { notify: None },
+        "status" | "1" => Command::Status,
+        // command object, e.g. {"type":"refresh","notify":false}
+        other => match serde_json::from_str::<Command>(other) {
+            Ok(command @ (Command::Refresh { .. } | Command::Status)) => command,
+            _ => {
+                log::warn!("MQTT command ignored: {:?}", other);
+                return;
+            }
+        }
+    };
+
+    log::info!("MQTT command received: {:?}", command);
+    bus.publish(SensorEvent::CommandReceived {
+        source: CommandSource::Mqtt,
+        doc_id: MQTT_COMMAND_DOC_ID.to_string(),
+        request: CommandRequest::local(MQTT_COMMAND_DOC_ID, command),
+        timestamp: now_timestamp()
+    });
+}
+
+// retained, queued without waiting so the event loop is never blocked by its own requests
+fn publish(client: &AsyncClient, topic: String, payload: String) {
+    if let Err(e) = client.try_publish(topic.clone(), QoS::AtLeastOnce, true, payload) {
+        log::warn!("MQTT publish to {} dropped: {:?}", topic, e);
+    }
+}
+
+// poll until the disconnect has gone out (the event loop errors once it is closed)
+async fn drain(eventloop: &mut EventLoop) {
+    while eventloop.poll().await.is_ok() {}
+}
@@ -0,0 +1,362 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Durable outbox for cloud updates.
+//
+// Every outgoing state change, reading and notification is appended (and
+// synced) to <data dir>/outbox.jsonl before delivery is attempted, so nothing
+// is lost while Firestore or the network is down, or across reboots.
+// Entries are delivered strictly in order with their original timestamps;
+// outbox.ack holds the sequence number of the last delivered entry.
+//
+// The outbox is capped at a number of entries, when full the oldest entry is
+// dropped (with a warning) to make room.
+//
+// A power cut can leave a torn last line. On open, a log with a torn or
+// otherwise unreadable line is rewritten from the readable entries before
+// anything is appended, so a new entry never lands on a partial line.
+//
+use crate::events::{CorrelationId, State};
+use crate::mode::DoorActions;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const OUTBOX_MAX_ENTRIES_DEFAULT: usize = 10_000;
+const OUTBOX_FILE: &str = "outbox.jsonl";
+const OUTBOX_ACK_FILE: &str = "outbox.ack";
+
+/// Cloud update waiting to be delivered.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CloudUpdate {
+    /// Door state with temp/humidity, notifying the user on change (or when forced), as allowed by the mode.
+    State {
+        state: State,
+        temp_f: f32,
+        humidity: f32,
+        force_notify: bool,
+        // absent in entries written before modes
+        #[serde(default)]
+        actions: DoorActions,
+        timestamp: f64
+    },
+
+    /// Periodic temp/humidity update, keeping the stored door state.
+    Reading { temp_f: f32, humidity: f32, timestamp: f64 }
+}
+
+impl CloudUpdate {
+    pub fn timestamp(&self) -> f64 {
+        match self {
+            CloudUpdate::State { timestamp, .. } => *timestamp,
+            CloudUpdate::Reading { timestamp, .. } => *timestamp
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub seq: u64,
+    // absent in entries written before correlation ids
+    #[serde(default)]
+    pub correlation_id: Option<CorrelationId>,
+    pub update: CloudUpdate
+}
+
+pub struct Outbox {
+    log_path: PathBuf,
+    ack_path: PathBuf,
+    max_entries: usize,
+    pending: VecDeque<OutboxEntry>,
+    // lines in the log file, delivered or not (the log is rewritten when it grows well past pending)
+    log_entries: usize,
+    next_seq: u64,
+    dropped: u64
+}
+
+impl Outbox {
+
+    /// Open (or create) the outbox in `dir`, loading entries not yet delivered.
+    pub fn open(dir: &Path, max_entries: usize) -> std::io::Result<Outbox> {
+        std::fs::create_dir_all(dir)?;
+        let log_path = dir.join(OUTBOX_FILE);
+        let ack_path = dir.join(OUTBOX_ACK_FILE);
+
+        let acked: u64 = match std::fs::read_to_string(&ack_path) {
+            Ok(s) => s.trim().parse().unwrap_or(0),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e)
+        };
+
+        let mut pending = VecDeque::new();
+        let mut log_entries = 0;
+        let mut last_seq = acked;
+        let mut damaged = false;
+        match std::fs::read(&log_path) {
+            Ok(contents) => {
+                // a torn write from a power cut leaves the last line without its newline
+                let complete = contents.iter().rposition(|&b| b == b'\n').map_or(0, |end| end + 1);
+                if complete < contents.len() {
+                    log::warn!("Outbox ends in a torn entry ({} bytes), discarding it", contents.len() - complete);
+                    damaged = true;
+                }
+                for line in contents[..complete].split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+                    log_entries += 1;
+                    match serde_json::from_slice::<OutboxEntry>(line) {
+                        Ok(entry) => {
+                            last_seq = last_seq.max(entry.seq);
+                            if entry.seq > acked {
+                                pending.push_back(entry);
+                            }
+                        }
+                        Err(e) => {
+                            log::warn!("Skipping unreadable outbox entry: {:?}", e);
+                            damaged = true;
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e)
+        }
+
+        let mut outbox = Outbox {
+            log_path,
+            ack_path,
+            max_entries: max_entries.max(1),
+            pending,
+            log_entries,
+            next_seq: last_seq + 1,
+            dropped: 0
+        };
+        while outbox.pending.len() > outbox.max_entries {
+            outbox.drop_oldest()?;
+        }
+        if damaged {
+            // only readable, complete lines from here on
+            outbox.rewrite()?;
+        } else {
+            outbox.compact()?;
+        }
+
+        log::info!("Outbox opened at {:?}: {} pending", outbox.log_path, outbox.pending.len());
+        Ok(outbox)
+    }
+
+    /// Append `update` to the log (synced to disk), dropping the oldest entry if the outbox is full.
+    pub fn push(&mut self, update: CloudUpdate, correlation_id: Option<CorrelationId>) -> std::io::Result<u64> {
+        let entry = OutboxEntry { seq: self.next_seq, correlation_id, update };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.log_path)?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+
+        self.next_seq += 1;
+        self.log_entries += 1;
+        self.pending.push_back(entry);
+
+        if self.pending.len() > self.max_entries {
+            self.drop_oldest()?;
+        }
+        Ok(self.next_seq - 1)
+    }
+
+    /// Oldest entry not yet delivered.
+    pub fn front(&self) -> Option<&OutboxEntry> {
+        self.pending.front()
+    }
+
+    /// Mark the oldest entry delivered.
+    pub fn ack(&mut self, seq: u64) -> std::io::Result<()> {
+        match self.pending.front() {
+            Some(entry) if entry.seq == seq => {}
+            _ => return Ok(())
+        }
+        self.pending.pop_front();
+        self.write_ack(seq)?;
+        self.compact()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Entries dropped because the outbox was full (since open).
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    fn drop_oldest(&mut self) -> std::io::Result<()> {
+        if let Some(entry) = self.pending.pop_front() {
+            self.dropped += 1;
+            log::warn!("Outbox full ({} entries), dropping oldest update {} ({:?})", self.max_entries, entry.seq, entry.update);
+            self.write_ack(entry.seq)?;
+        }
+        Ok(())
+    }
+
+    // replace the ack file atomically so a power cut leaves either the old or the new value
+    fn write_ack(&self, seq: u64) -> std::io::Result<()> {
+        let tmp = self.ack_path.with_extension("ack.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(seq.to_string().as_bytes())?;
+        file.sync_data()?;
+        std::fs::rename(&tmp, &self.ack_path)
+    }
+
+    // truncate the log once everything is delivered, rewrite it when delivered entries pile up
+    fn compact(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            if self.log_entries > 0 {
+                File::create(&self.log_path)?.sync_data()?;
+                self.log_entries = 0;
+            }
+            return Ok(());
+        }
+        if self.log_entries <= self.pending.len() * 2 && self.log_entries <= self.max_entries {
+            return Ok(());
+        }
+        self.rewrite()
+    }
+
+    // replace the log with the pending entries
+    fn rewrite(&mut self) -> std::io::Result<()> {
+        let tmp = self.log_path.with_extension("jsonl.tmp");
+        let mut file = File::create(&tmp)?;
+        for entry in &self.pending {
+            let mut line = serde_json::to_string(entry)?;
+            line.push('\n');
+            file.write_all(line.as_bytes())?;
+        }
+        file.sync_data()?;
+        std::fs::rename(&tmp, &self.log_path)?;
+        self.log_entries = self.pending.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // fresh directory per test, removed on drop
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new() -> TestDir {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!("sensor-outbox-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+            let _ = std::fs::remove_dir_all(&dir);
+            TestDir(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn reading(timestamp: f64) -> CloudUpdate {
+        CloudUpdate::Reading { temp_f: 70.0, humidity: 40.0, timestamp }
+    }
+
+    fn pending_timestamps(outbox: &Outbox) -> Vec<f64> {
+        outbox.pending.iter().map(|entry| entry.update.timestamp()).collect()
+    }
+
+    #[test]
+    fn reopen_keeps_undelivered_entries_in_order() {
+        let dir = TestDir::new();
+        let mut outbox = Outbox::open(&dir.0, 10).unwrap();
+        let first = outbox.push(reading(1.0), None).unwrap();
+        outbox.push(reading(2.0), None).unwrap();
+        outbox.push(reading(3.0), None).unwrap();
+        outbox.ack(first).unwrap();
+        drop(outbox);
+
+        let mut outbox = Outbox::open(&dir.0, 10).unwrap();
+        assert_eq!(pending_timestamps(&outbox), vec![2.0, 3.0]);
+        assert_eq!(outbox.push(reading(4.0), None).unwrap(), 4);
+    }
+
+    #[test]
+    fn ack_ignores_anything_but_the_front() {
+        let dir = TestDir::new();
+        let mut outbox = Outbox::open(&dir.0, 10).unwrap();
+        outbox.push(reading(1.0), None).unwrap();
+        let second = outbox.push(reading(2.0), None).unwrap();
+
+        outbox.ack(second).unwrap();
+        assert_eq!(outbox.len(), 2);
+    }
+
+    #[test]
+    fn full_outbox_drops_oldest() {
+        let dir = TestDir::new();
+        let mut outbox = Outbox::open(&dir.0, 2).unwrap();
+        for timestamp in [1.0, 2.0, 3.0] {
+            outbox.push(reading(timestamp), None).unwrap();
+        }
+        assert_eq!(outbox.dropped(), 1);
+        assert_eq!(pending_timestamps(&outbox), vec![2.0, 3.0]);
+        drop(outbox);
+
+        // the drop was recorded as delivered
+        let outbox = Outbox::open(&dir.0, 2).unwrap();
+        assert_eq!(pending_timestamps(&outbox), vec![2.0, 3.0]);
+    }
+
+    #[test]
+    fn reopen_after_torn_write_discards_partial_line() {
+        let dir = TestDir::new();
+        let mut outbox = Outbox::open(&dir.0, 10).unwrap();
+        outbox.push(reading(1.0), None).unwrap();
+        outbox.push(reading(2.0), None).unwrap();
+        drop(outbox);
+
+        // power cut halfway through the next append
+        let log_path = dir.0.join(OUTBOX_FILE);
+        let mut file = OpenOptions::new().append(true).open(&log_path).unwrap();
+        file.write_all(br#"{"seq":3,"update":{"type":"rea"#).unwrap();
+        drop(file);
+
+        let mut outbox = Outbox::open(&dir.0, 10).unwrap();
+        assert_eq!(pending_timestamps(&outbox), vec![1.0, 2.0]);
+        assert!(std::fs::read(&log_path).unwrap().ends_with(b"\n"));
+
+        // the next entry gets a line of its own
+        outbox.push(reading(3.0), None).unwrap();
+        drop(outbox);
+        let outbox = Outbox::open(&dir.0, 10).unwrap();
+        assert_eq!(pending_timestamps(&outbox), vec![1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn reopen_skips_unreadable_line() {
+        let dir = TestDir::new();
+        let mut outbox = Outbox::open(&dir.0, 10).unwrap();
+        outbox.push(reading(1.0), None).unwrap();
+        drop(outbox);
+
+        let log_path = dir.0.join(OUTBOX_FILE);
+        let mut file = OpenOptions::new().append(true).open(&log_path).unwrap();
+        file.write_all(b"not json\n").unwrap();
+        drop(file);
+
+        let outbox = Outbox::open(&dir.0, 10).unwrap();
+        assert_eq!(pending_timestamps(&outbox), vec![1.0]);
+        assert_eq!(std::fs::read_to_string(&log_path).unwrap().lines().count(), 1);
+    }
+}
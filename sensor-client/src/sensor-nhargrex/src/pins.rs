@@ -0,0 +1,140 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Door and DHT22 pins, either real Raspberry Pi GPIO or simulated.
+//
+// Simulated GPIO lets the daemon run off the Pi (e.g. integration tests
+// against the Firestore emulator):
+//   export SENSOR_SIMULATED_GPIO=1
+//   export SENSOR_SIMULATED_DOOR=open            # open|closed (default closed)
+//   export SENSOR_SIMULATED_TEMP_C=20.0          # default 20.0
+//   export SENSOR_SIMULATED_HUMIDITY=45.0        # default 45.0
+//
+use crate::dht22::{Reading, ReadingError, read_dht22};
+use crate::metrics;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+use rppal::gpio::{Gpio, Trigger, InputPin, Mode, IoPin, Level};
+
+// Door sensor input pin
+#[derive(Clone)]
+pub enum DoorPin {
+    Gpio(Arc<Mutex<InputPin>>),
+    Simulated(Arc<Mutex<Level>>)
+}
+
+// DHT22 data pin (with its GPIO number)
+#[derive(Clone)]
+pub enum TempPin {
+    Gpio(u8, Arc<Mutex<IoPin>>),
+    Simulated(u8, Reading)
+}
+
+impl DoorPin {
+
+    /// Door sensor on `pin` with the internal pull-up enabled.
+    pub fn new(pin: u8) -> Result<DoorPin, rppal::gpio::Error> {
+        if simulated_gpio() {
+            let level = match std::env::var("SENSOR_SIMULATED_DOOR").as_deref() {
+                Ok("open") => Level::High,
+                _ => Level::Low
+            };
+            log::info!("Simulated door pin {}: {:?}", pin, level);
+            return Ok(DoorPin::Simulated(Arc::new(Mutex::new(level))));
+        }
+        Ok(DoorPin::Gpio(Arc::new(Mutex::new(Gpio::new()?.get(pin)?.into_input_pullup()))))
+    }
+
+    pub fn read(&self) -> Level {
+        match self {
+            DoorPin::Gpio(pin) => pin.lock().unwrap().read(),
+            DoorPin::Simulated(level) => *level.lock().unwrap()
+        }
+    }
+
+    /// Install an interrupt callback on the door pin, simulated pins never fire.
+    pub fn set_async_interrupt<C>(&self, trigger: Trigger, callback: C) -> Result<(), rppal::gpio::Error>
+    where
+        C: FnMut(Level) + Send + 'static,
+    {
+        match self {
+            DoorPin::Gpio(pin) => {
+                // Note: set_async_interrupt needs &mut InputPin. We can lock the mutex to get a &mut guard,
+                // then call set_async_interrupt on that guarded mutable reference.
+                let mut guard = pin.lock().unwrap();
+                guard.set_async_interrupt(trigger, callback)
+                // guard is dropped here (releases lock)
+            }
+            DoorPin::Simulated(_) => Ok(())
+        }
+    }
+
+    /// Remove the interrupt callback installed by set_async_interrupt.
+    pub fn clear_async_interrupt(&self) -> Result<(), rppal::gpio::Error> {
+        match self {
+            DoorPin::Gpio(pin) => pin.lock().unwrap().clear_async_interrupt(),
+            DoorPin::Simulated(_) => Ok(())
+        }
+    }
+}
+
+impl TempPin {
+
+    /// DHT22 data pin on `pin`.
+    pub fn new(pin: u8) -> Result<TempPin, rppal::gpio::Error> {
+        if simulated_gpio() {
+            let reading = Reading {
+                temperature: simulated_env_f32("SENSOR_SIMULATED_TEMP_C", 20.0),
+                humidity: simulated_env_f32("SENSOR_SIMULATED_HUMIDITY", 45.0)
+            };
+            log::info!("Simulated DHT22 pin {}: {:?}", pin, reading);
+            return Ok(TempPin::Simulated(pin, reading));
+        }
+        Ok(TempPin::Gpio(pin, Arc::new(Mutex::new(Gpio::new()?.get(pin)?.into_io(Mode::Output)))))
+    }
+
+    /// Raw DHT22 reading (°C), counted (once, here) and timed in the DHT metrics.
+    pub fn read(&self) -> Result<Reading, ReadingError> {
+        let started = Instant::now();
+        let result = match self {
+            TempPin::Gpio(_, pin) => read_dht22(pin),
+            TempPin::Simulated(_, reading) => Ok(*reading)
+        };
+
+        let sensor_id = self.sensor_id();
+        let outcome = match &result {
+            Ok(reading) if !plausible(reading) => "out_of_range",
+            Ok(_) => "ok",
+            Err(ReadingError::Timeout) => "timeout",
+            Err(ReadingError::Checksum) => "checksum",
+            Err(ReadingError::Gpio(_)) => "gpio"
+        };
+        metrics::DHT_READ_DURATION.with_label_values(&[&sensor_id]).observe(started.elapsed().as_secs_f64());
+        metrics::DHT_READS.with_label_values(&[&sensor_id, outcome]).inc();
+        result
+    }
+
+    /// Sensor id used in events and metrics, e.g. "dht22-gpio18".
+    pub fn sensor_id(&self) -> String {
+        match self {
+            TempPin::Gpio(pin, _) | TempPin::Simulated(pin, _) => format!("dht22-gpio{}", pin)
+        }
+    }
+}
+
+/// Whether a raw reading (°C) is physically possible, a DHT22 can pass its checksum with garbage
+/// (e.g. 0°C at 0% after a glitch). Implausible readings count as "out_of_range" and are skipped by callers.
+pub fn plausible(reading: &Reading) -> bool {
+    let temp_f = reading.temperature * 9.0 / 5.0 + 32.0;
+    (-40.0..=125.0).contains(&temp_f) && (0.0..=100.0).contains(&reading.humidity) && !(temp_f == 32.0 && reading.humidity == 0.0)
+}
+
+pub fn simulated_gpio() -> bool {
+    matches!(std::env::var("SENSOR_SIMULATED_GPIO").as_deref(), Ok("1") | Ok("true"))
+}
+
+fn simulated_env_f32(name: &str, default: f32) -> f32 {
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
@@ -0,0 +1,389 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Guard for the restart (daemon) and reboot (Pi) commands.
+//
+// Both together are limited to SENSOR_POWER_MAX_PER_DAY (default 3) in any 24
+// hours. The actions are recorded in the local store (power_actions), so the
+// limit holds across the restarts and reboots it counts and a bug can't
+// reboot-loop the Pi.
+//
+// Each needs a confirmation token: a command without one (or with a stale or
+// wrong one) is rejected with a fresh token in its result payload, valid for
+// two minutes, to be sent back in the command's "confirm" field. Tokens are
+// issued per action and issuer, so a token only confirms the action it was
+// issued for, by the same issuer, and issuers can't replace each other's.
+// The confirming command is a new command (a new id), the rejected one already
+// used its id. Version 1 reboots (r_cmd 3) only need a token with
+// SENSOR_POWER_CONFIRM_V1=1, so apps that can't send one keep working until
+// they do.
+//
+// Before acting, the action is recorded and its result set to "going_down".
+// On the next start the result is completed: done once the daemon (restart, a
+// new process) or the Pi (reboot, a new kernel boot id) came back, failed if
+// the Pi didn't actually reboot. Actions recorded by the running process are
+// still going down and are left for the next one.
+//
+use crate::events::{EventBus, SensorEvent, now_timestamp};
+use crate::protocol::{CommandSource, CommandStatus};
+use crate::store::{PowerActionRecord, SharedStore};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const POWER_MAX_PER_DAY_DEFAULT: u32 = 3;
+const POWER_LIMIT_WINDOW_SECS: f64 = 24.0 * 60.0 * 60.0;
+const POWER_CONFIRM_TTL: Duration = Duration::from_secs(120);
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+const PROCESS_STAT_PATH: &str = "/proc/self/stat";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerAction {
+    Restart,
+    Reboot
+}
+
+impl PowerAction {
+    pub fn parse(s: &str) -> Option<PowerAction> {
+        match s {
+            "restart" => Some(PowerAction::Restart),
+            "reboot" => Some(PowerAction::Reboot),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PowerAction::Restart => "restart",
+            PowerAction::Reboot => "reboot"
+        }
+    }
+}
+
+/// Outcome of checking a restart or reboot command.
+pub enum PowerCheck {
+    /// Within the limit and confirmed, go ahead.
+    Confirmed,
+    /// Send the command again with this token.
+    ConfirmationRequired { token: String, expires_in_secs: u64 }
+}
+
+struct Challenge {
+    token: String,
+    issued: Instant
+}
+
+/// Daily limit and confirmation tokens, shared with the command processor.
+#[derive(Clone)]
+pub struct PowerGuard {
+    store: SharedStore,
+    max_per_day: u32,
+    /// Version 1 commands need a token too.
+    confirm_v1: bool,
+    /// Outstanding tokens by action and issuer.
+    challenges: Arc<Mutex<HashMap<(PowerAction, String), Challenge>>>
+}
+
+impl PowerGuard {
+    pub fn new(store: SharedStore, max_per_day: u32, confirm_v1: bool) -> PowerGuard {
+        PowerGuard { store, max_per_day, confirm_v1, challenges: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Whether a command of protocol `version` needs a confirmation token.
+    pub fn confirmation_required(&self, version: u32) -> bool {
+        version >= 2 || self.confirm_v1
+    }
+
+    /// Check against the daily limit only, for commands that don't need a token.
+    /// SQLite calls block, call from the blocking pool.
+    pub fn check_unconfirmed(&self) -> Result<PowerCheck, String> {
+        self.check_limit_at(now_timestamp()).map(|()| PowerCheck::Confirmed)
+    }
+
+    /// Check `action` by `issuer` against the daily limit and the confirmation token, or say why it can't run.
+    /// SQLite calls block, call from the blocking pool.
+    pub fn check(&self, action: PowerAction, issuer: &str, confirm: Option<&str>) -> Result<PowerCheck, String> {
+        self.check_at(action, issuer, confirm, now_timestamp(), Instant::now())
+    }
+
+    // check at unix time `now`, monotonic time `at`
+    fn check_at(&self, action: PowerAction, issuer: &str, confirm: Option<&str>, now: f64, at: Instant) -> Result<PowerCheck, String> {
+        self.check_limit_at(now)?;
+
+        let mut challenges = self.challenges.lock().unwrap();
+        challenges.retain(|_, challenge| at.duration_since(challenge.issued) < POWER_CONFIRM_TTL);
+        let key = (action, issuer.to_string());
+        let confirmed = match (confirm, challenges.get(&key)) {
+            (Some(confirm), Some(issued)) => issued.token == confirm,
+            _ => false
+        };
+        if confirmed {
+            challenges.remove(&key);
+            return Ok(PowerCheck::Confirmed);
+        }
+
+        let token = new_token();
+        challenges.insert(key, Challenge { token: token.clone(), issued: at });
+        Ok(PowerCheck::ConfirmationRequired { token, expires_in_secs: POWER_CONFIRM_TTL.as_secs() })
+    }
+
+    // check the daily limit at unix time `now`
+    fn check_limit_at(&self, now: f64) -> Result<(), String> {
+        let recent = self.store.lock().unwrap()
+            .power_actions_since(now - POWER_LIMIT_WINDOW_SECS)
+            .map_err(|e| format!("power action log unavailable: {}", e))?;
+        if recent.len() >= self.max_per_day as usize {
+            // allowed again once the oldest one in the window expires
+            let next = recent.first().map_or(0.0, |oldest| oldest + POWER_LIMIT_WINDOW_SECS - now);
+            return Err(format!("limit of {} restarts and reboots per 24 hours reached, next allowed in {:.0}s", self.max_per_day, next.max(0.0)));
+        }
+        Ok(())
+    }
+
+    /// Record `action` before acting, counting towards the limit. Returns the record id.
+    /// SQLite calls block, call from the blocking pool.
+    pub fn record(&self, action: PowerAction, doc_id: &str, issuer: Option<&str>, command_id: &str, version: u32) -> rusqlite::Result<i64> {
+        self.store.lock().unwrap().insert_power_action(&PowerActionRecord {
+            id: 0,
+            timestamp: now_timestamp(),
+            action,
+            doc_id: doc_id.to_string(),
+            issuer: issuer.map(str::to_string),
+            command_id: command_id.to_string(),
+            version,
+            boot_id: boot_id(),
+            instance_id: instance_id()
+        })
+    }
+
+    /// Mark a recorded action failed (it never went down), so the next start doesn't report it.
+    pub fn fail(&self, id: i64) -> rusqlite::Result<()> {
+        self.store.lock().unwrap().complete_power_action(id, CommandStatus::Failed, now_timestamp())
+    }
+}
+
+// kernel boot id, changes on every boot
+fn boot_id() -> Option<String> {
+    std::fs::read_to_string(BOOT_ID_PATH).ok().map(|id| id.trim().to_string())
+}
+
+// this daemon process, "<pid>:<start time>" (clock ticks after boot), unique within a boot
+fn instance_id() -> Option<String> {
+    let stat = std::fs::read_to_string(PROCESS_STAT_PATH).ok()?;
+    // fields after the command name, which may contain spaces; the start time is field 22
+    let start_time = stat.rsplit_once(')')?.1.split_whitespace().nth(19)?;
+    Some(format!("{}:{}", std::process::id(), start_time))
+}
+
+// random hex token, from the time if the kernel's random source can't be read
+fn new_token() -> String {
+    let mut bytes = [0u8; 8];
+    match std::fs::File::open("/dev/urandom").and_then(|mut random| random.read_exact(&mut bytes)) {
+        Ok(()) => hex::encode(bytes),
+        Err(_) => format!("{:016x}", (now_timestamp() * 1e6) as u64)
+    }
+}
+
+/// Complete the results of restarts and reboots that went down before this start.
+pub async fn report_power_actions(bus: &EventBus, store: SharedStore) {
+    let pending = match tokio::task::spawn_blocking(move || -> rusqlite::Result<Vec<(PowerActionRecord, CommandStatus)>> {
+        let (current_boot, current_instance) = (boot_id(), instance_id());
+        let store = store.lock().unwrap();
+        let pending = store.pending_power_actions()?;
+        let mut completed = Vec::with_capacity(pending.len());
+        for record in pending {
+            if record.instance_id.is_some() && record.boot_id == current_boot && record.instance_id == current_instance {
+                // requested by this process, not down yet
+                continue;
+            }
+            let status = match record.action {
+                PowerAction::Reboot if record.boot_id.is_some() && record.boot_id == current_boot => CommandStatus::Failed,
+                _ => CommandStatus::Done
+            };
+            store.complete_power_action(record.id, status, now_timestamp())?;
+            completed.push((record, status));
+        }
+        Ok(completed)
+    }).await {
+        Ok(Ok(pending)) => pending,
+        Ok(Err(e)) => {
+            log::error!("Failed to read pending restarts and reboots: {:?}", e);
+            return;
+        }
+        Err(e) => {
+            log::error!("Failed to read pending restarts and reboots: {:?}", e);
+            return;
+        }
+    };
+
+    for (record, status) in pending {
+        let took = now_timestamp() - record.timestamp;
+        let error = if status == CommandStatus::Done {
+            log::info!("{} {} completed, back after {:.0}s", record.action.as_str(), record.command_id, took);
+            None
+        } else {
+            log::warn!("Reboot {} did not happen, the Pi is still on the same boot", record.command_id);
+            Some(String::from("the daemon restarted but the Pi did not reboot"))
+        };
+        bus.publish(SensorEvent::CommandResult {
+            source: CommandSource::Firestore,
+            doc_id: record.doc_id,
+            issuer: record.issuer,
+            command_id: record.command_id,
+            version: record.version,
+            command: Some(record.action.as_str().to_string()),
+            status,
+            error,
+            payload: Some(json!({ "action": record.action, "requested_at": record.timestamp, "back_after_secs": took })),
+            timestamp: now_timestamp()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{Retention, Store};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const HOUR: f64 = 60.0 * 60.0;
+
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new() -> TestDir {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!("sensor-power-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+            let _ = std::fs::remove_dir_all(&dir);
+            TestDir(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn guard(dir: &TestDir, max_per_day: u32) -> PowerGuard {
+        let store = Store::open(&dir.0, Retention { raw_days: 7, hourly_days: 730 }).unwrap();
+        PowerGuard::new(Arc::new(Mutex::new(store)), max_per_day, false)
+    }
+
+    fn record_at(guard: &PowerGuard, timestamp: f64) {
+        guard.store.lock().unwrap().insert_power_action(&PowerActionRecord {
+            id: 0,
+            timestamp,
+            action: PowerAction::Restart,
+            doc_id: String::from("owner"),
+            issuer: None,
+            command_id: format!("restart-{}", timestamp),
+            version: 2,
+            boot_id: None,
+            instance_id: None
+        }).unwrap();
+    }
+
+    fn token(check: Result<PowerCheck, String>) -> String {
+        match check {
+            Ok(PowerCheck::ConfirmationRequired { token, .. }) => token,
+            Ok(PowerCheck::Confirmed) => panic!("confirmed without a token"),
+            Err(e) => panic!("rejected: {}", e)
+        }
+    }
+
+    fn confirmed(check: Result<PowerCheck, String>) -> bool {
+        matches!(check, Ok(PowerCheck::Confirmed))
+    }
+
+    #[test]
+    fn confirms_once_with_the_issued_token() {
+        let dir = TestDir::new();
+        let guard = guard(&dir, 3);
+        let (now, at) = (100.0 * HOUR, Instant::now());
+
+        assert!(!confirmed(guard.check_at(PowerAction::Reboot, "alice", Some("guess"), now, at)));
+        let issued = token(guard.check_at(PowerAction::Reboot, "alice", None, now, at));
+        assert!(confirmed(guard.check_at(PowerAction::Reboot, "alice", Some(&issued), now, at)));
+        assert!(!confirmed(guard.check_at(PowerAction::Reboot, "alice", Some(&issued), now, at)));
+    }
+
+    #[test]
+    fn token_expires() {
+        let dir = TestDir::new();
+        let guard = guard(&dir, 3);
+        let (now, at) = (100.0 * HOUR, Instant::now());
+
+        let issued = token(guard.check_at(PowerAction::Restart, "alice", None, now, at));
+        let late = at + POWER_CONFIRM_TTL;
+        assert!(!confirmed(guard.check_at(PowerAction::Restart, "alice", Some(&issued), now, late)));
+
+        let issued = token(guard.check_at(PowerAction::Restart, "alice", None, now, late));
+        let in_time = late + POWER_CONFIRM_TTL - Duration::from_secs(1);
+        assert!(confirmed(guard.check_at(PowerAction::Restart, "alice", Some(&issued), now, in_time)));
+    }
+
+    #[test]
+    fn token_only_confirms_its_action_and_issuer() {
+        let dir = TestDir::new();
+        let guard = guard(&dir, 3);
+        let (now, at) = (100.0 * HOUR, Instant::now());
+
+        let alice = token(guard.check_at(PowerAction::Restart, "alice", None, now, at));
+        // another issuer's challenge doesn't replace alice's
+        let bob = token(guard.check_at(PowerAction::Restart, "bob", None, now, at));
+        assert!(!confirmed(guard.check_at(PowerAction::Reboot, "alice", Some(&alice), now, at)));
+        assert!(!confirmed(guard.check_at(PowerAction::Restart, "mallory", Some(&bob), now, at)));
+        assert!(confirmed(guard.check_at(PowerAction::Restart, "bob", Some(&bob), now, at)));
+
+        let alice = token(guard.check_at(PowerAction::Restart, "alice", None, now, at));
+        assert!(confirmed(guard.check_at(PowerAction::Restart, "alice", Some(&alice), now, at)));
+    }
+
+    #[test]
+    fn daily_limit_counts_the_last_24_hours() {
+        let dir = TestDir::new();
+        let guard = guard(&dir, 2);
+        let (now, at) = (100.0 * HOUR, Instant::now());
+        record_at(&guard, now - 25.0 * HOUR);
+        record_at(&guard, now - 23.0 * HOUR);
+        record_at(&guard, now - HOUR);
+
+        let error = guard.check_at(PowerAction::Reboot, "alice", None, now, at).err().unwrap();
+        assert_eq!(error, "limit of 2 restarts and reboots per 24 hours reached, next allowed in 3600s");
+
+        // allowed again once the oldest in the window is a day old
+        token(guard.check_at(PowerAction::Reboot, "alice", None, now + HOUR, at));
+    }
+
+    #[test]
+    fn daily_limit_rejects_confirmed_actions() {
+        let dir = TestDir::new();
+        let guard = guard(&dir, 1);
+        let (now, at) = (100.0 * HOUR, Instant::now());
+
+        let issued = token(guard.check_at(PowerAction::Reboot, "alice", None, now, at));
+        record_at(&guard, now);
+        assert!(guard.check_at(PowerAction::Reboot, "alice", Some(&issued), now, at).is_err());
+    }
+
+    #[test]
+    fn version_1_needs_a_token_only_when_configured() {
+        let dir = TestDir::new();
+        let guard = guard(&dir, 1);
+        assert!(guard.confirmation_required(2));
+        assert!(!guard.confirmation_required(1));
+        assert!(PowerGuard::new(guard.store.clone(), 1, true).confirmation_required(1));
+
+        // still within the daily limit
+        assert!(matches!(guard.check_unconfirmed(), Ok(PowerCheck::Confirmed)));
+        record_at(&guard, now_timestamp());
+        assert!(guard.check_unconfirmed().is_err());
+    }
+}
@@ -0,0 +1,296 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Command protocol (sensorsRefreshRequest documents) and command results.
+//
+// Version 2 documents carry a tagged command with optional parameters:
+//   { "v": 2, "id": "<unique id>", "r_ts": <unix secs>, "command": { "type": "refresh", "notify": false } }
+// Either version may name its "issuer" and carry a "sig" (see auth.rs).
+// Version 1 documents (no "v") are still accepted:
+//   { "r_ts": <unix secs>, "r_cmd": 0 (refresh) | 1 (status) | 3 (reboot) }
+// A restart or reboot needs a confirmation token (see power.rs), sent back in a
+// new command: the rejected one already used its command id (version 1: its
+// r_ts), so sending it again as is is rejected as a reused id. Version 1
+// documents carry the token in a "confirm" field, and only need one with
+// SENSOR_POWER_CONFIRM_V1=1 (off by default, so apps that only write r_cmd 3
+// can still reboot the Pi).
+//
+// Every command gets a result document, sensorsCommandResult/{doc id}, owned
+// by its issuer (the "user" field the security rules check), that moves from
+// accepted to done or failed (or is rejected outright, or expired when it
+// arrived too late), with an error message, a completion timestamp and an
+// optional payload. Restarts and reboots go to going_down instead of accepted
+// and are completed on the next start (see power.rs).
+//
+// Each revision of a command document (its server update time) runs at most
+// once, see Store::claim_command.
+//
+use crate::capture::CaptureKind;
+use crate::events::now_timestamp;
+use crate::mode::Mode;
+use crate::power::PowerAction;
+use firestore::FirestoreDb;
+use firestore::gcloud_sdk::google::firestore::v1::Document;
+use serde::{Deserialize, Serialize};
+
+pub const COMMAND_PROTOCOL_VERSION: u32 = 2;
+pub const SENSORS_COMMAND_RESULT_COLLECTION: &str = "sensorsCommandResult";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    /// Report door state and readings to the cloud, notifying the user unless `notify` is false.
+    Refresh {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        notify: Option<bool>
+    },
+
+    /// Write the current state and readings to sensors/{user}.
+    Status,
+
+    /// Reboot the Pi, `confirm` carries the confirmation token (see power.rs).
+    Reboot {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        confirm: Option<String>
+    },
+
+    /// Restart the daemon, `confirm` carries the confirmation token.
+    Restart {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        confirm: Option<String>
+    },
+
+    /// Record a clip (`seconds` long, default 5) or a photo and upload it to Storage.
+    Capture {
+        #[serde(default)]
+        kind: CaptureKind,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seconds: Option<u32>
+    },
+
+    /// Switch the security mode.
+    SetMode {
+        mode: Mode
+    },
+
+    /// Upload a diagnostics bundle with the last `lines` log lines (default 200) to Storage. The result's
+    /// `path` is the bundle's `gs://` object path, not a download URL: fetch it with gsutil or the Storage SDK.
+    Diagnostics {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lines: Option<usize>
+    },
+
+    /// Notify the user with a summary of sensor health.
+    HealthSummary
+}
+
+impl Command {
+    /// Command name, as used in the "type" field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Refresh { .. } => "refresh",
+            Command::Status => "status",
+            Command::Reboot { .. } => "reboot",
+            Command::Restart { .. } => "restart",
+            Command::Capture { .. } => "capture",
+            Command::SetMode { .. } => "set_mode",
+            Command::Diagnostics { .. } => "diagnostics",
+            Command::HealthSummary => "health_summary"
+        }
+    }
+
+    /// Command for a version 1 `r_cmd` code, `confirm` being the document's confirmation token.
+    pub fn from_r_cmd(r_cmd: i32, confirm: Option<String>) -> Result<Command, String> {
+        match r_cmd {
+            0 => Ok(Command::Refresh { notify: None }),
+            1 => Ok(Command::Status),
+            3 => Ok(Command::Reboot { confirm }),
+            other => Err(format!("unknown r_cmd {}", other))
+        }
+    }
+
+    /// Restart or reboot, with the confirmation token if given.
+    pub fn power_action(&self) -> Option<(PowerAction, Option<&str>)> {
+        match self {
+            Command::Restart { confirm } => Some((PowerAction::Restart, confirm.as_deref())),
+            Command::Reboot { confirm } => Some((PowerAction::Reboot, confirm.as_deref())),
+            _ => None
+        }
+    }
+}
+
+/// Where a command came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandSource {
+    Firestore,
+    Api,
+    Mqtt,
+    Schedule
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandStatus {
+    /// Valid and about to run.
+    Accepted,
+    /// Not run (malformed, unknown or unsupported).
+    Rejected,
+    /// Ran successfully.
+    Done,
+    /// Ran and failed.
+    Failed,
+    /// Not run, received outside the freshness window.
+    Expired,
+    /// Restart or reboot about to happen, completed on the next start.
+    #[serde(rename = "going_down")]
+    GoingDown
+}
+
+impl CommandStatus {
+    pub fn is_final(&self) -> bool {
+        !matches!(self, CommandStatus::Accepted | CommandStatus::GoingDown)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandStatus::Accepted => "accepted",
+            CommandStatus::Rejected => "rejected",
+            CommandStatus::Done => "done",
+            CommandStatus::Failed => "failed",
+            CommandStatus::Expired => "expired",
+            CommandStatus::GoingDown => "going_down"
+        }
+    }
+}
+
+/// A valid command ready to run.
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandRequest {
+    pub command_id: String,
+    pub version: u32,
+    pub r_ts: u64,
+    pub command: Command,
+    /// Server update time of the command document (unix nanoseconds), None for local commands.
+    pub update_time: Option<i64>,
+    /// User id of the issuer, if the document names one.
+    pub issuer: Option<String>,
+    /// Hex HMAC-SHA256 signature, if signed.
+    pub signature: Option<String>
+}
+
+impl CommandRequest {
+    /// Request for a command issued locally (HTTP API, MQTT) just now, with an id starting with `prefix`.
+    pub fn local(prefix: &str, command: Command) -> CommandRequest {
+        let timestamp = now_timestamp();
+        CommandRequest {
+            command_id: format!("{}-{}", prefix, (timestamp * 1000.0) as u64),
+            version: COMMAND_PROTOCOL_VERSION,
+            r_ts: timestamp as u64,
+            command,
+            update_time: None,
+            issuer: None,
+            signature: None
+        }
+    }
+
+    /// Identifies this revision of the command document in the command ledger.
+    pub fn revision(&self) -> String {
+        match self.update_time {
+            Some(update_time) => update_time.to_string(),
+            None => format!("id:{}", self.command_id)
+        }
+    }
+}
+
+// sensorsRefreshRequest document, either version
+#[derive(Debug, Clone, Deserialize)]
+struct CommandDocument {
+    #[serde(default)]
+    v: Option<u32>,
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    r_ts: Option<u64>,
+    #[serde(default)]
+    r_cmd: Option<i32>,
+    /// Confirmation token for a version 1 reboot.
+    #[serde(default)]
+    confirm: Option<String>,
+    #[serde(default)]
+    command: Option<serde_json::Value>,
+    #[serde(default)]
+    issuer: Option<String>,
+    #[serde(default)]
+    sig: Option<String>
+}
+
+/// A command document that could not be turned into a request.
+#[derive(Debug, Clone)]
+pub struct CommandRejection {
+    pub issuer: Option<String>,
+    pub command_id: String,
+    pub version: u32,
+    pub command: Option<String>,
+    pub error: String
+}
+
+/// Result document, sensorsCommandResult/{doc id}.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandResultObject {
+    /// User the result belongs to (the command's issuer, else its document id), checked by the security rules.
+    pub user: String,
+    pub command_id: String,
+    pub v: u32,
+    pub command: Option<String>,
+    pub status: CommandStatus,
+    pub error: Option<String>,
+    pub payload: Option<serde_json::Value>,
+    pub timestamp: f64,
+    pub completed_at: Option<f64>
+}
+
+/// User a command result belongs to: the issuer the document named, else the document id (as in CommandAuth).
+pub fn result_user(doc_id: &str, issuer: Option<&str>) -> String {
+    issuer.unwrap_or(doc_id).to_string()
+}
+
+/// Id of a Firestore document (last segment of its name).
+pub fn document_id(doc: &Document) -> String {
+    doc.name.rsplit('/').next().unwrap_or_default().to_string()
+}
+
+/// Parse a sensorsRefreshRequest document of either version.
+pub fn parse_command_document(doc: &Document) -> Result<CommandRequest, CommandRejection> {
+    let document = FirestoreDb::deserialize_doc_to::<CommandDocument>(doc).map_err(|e| CommandRejection {
+        issuer: None,
+        command_id: document_id(doc),
+        version: 1,
+        command: None,
+        error: format!("unreadable command document: {}", e)
+    })?;
+
+    let version = document.v.unwrap_or(1);
+    let command_id = document.id.clone()
+        .or_else(|| document.r_ts.map(|r_ts| r_ts.to_string()))
+        .unwrap_or_else(|| document_id(doc));
+    let reject = |command: Option<String>, error: String| CommandRejection { issuer: document.issuer.clone(), command_id: command_id.clone(), version, command, error };
+
+    let r_ts = document.r_ts.ok_or_else(|| reject(None, String::from("missing r_ts")))?;
+    let command = match version {
+        1 => {
+            let r_cmd = document.r_cmd.ok_or_else(|| reject(None, String::from("missing r_cmd")))?;
+            Command::from_r_cmd(r_cmd, document.confirm.clone()).map_err(|error| reject(Some(r_cmd.to_string()), error))?
+        }
+        COMMAND_PROTOCOL_VERSION => {
+            let command = document.command.ok_or_else(|| reject(None, String::from("missing command")))?;
+            let name = command.get("type").and_then(|name| name.as_str()).map(str::to_string);
+            serde_json::from_value::<Command>(command).map_err(|e| reject(name, format!("invalid command: {}", e)))?
+        }
+        other => return Err(reject(None, format!("unsupported protocol version {} (supported: 1, {})", other, COMMAND_PROTOCOL_VERSION)))
+    };
+
+    let update_time = doc.update_time.as_ref().map(|t| t.seconds * 1_000_000_000 + t.nanos as i64);
+    Ok(CommandRequest { command_id, version, r_ts, command, update_time, issuer: document.issuer, signature: document.sig })
+}
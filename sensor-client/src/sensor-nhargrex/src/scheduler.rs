@@ -0,0 +1,414 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Scheduled tasks: cron style schedules that run commands, handed to the
+// command processor exactly like API and MQTT commands (source "schedule").
+//
+// SENSOR_SCHEDULES holds ';' separated entries, each a 5 field cron
+// expression (minute hour day-of-month month day-of-week) and a command:
+//   "0 7 * * * status; 0 9 * * Mon health_summary; 0 23 * * * set_mode armed catch_up"
+// Commands: refresh [quiet], status, health_summary, set_mode <mode>,
+// capture [photo | clip [seconds]], diagnostics [lines]. Restarts and reboots
+// need a confirmation and can't be scheduled.
+//
+// Times are in SENSOR_SCHEDULE_TZ (e.g. "Europe/London", default the system
+// time zone from /etc/timezone, else UTC), on its wall clock: across a DST
+// change a time that occurs twice runs once, at the first, and a time that is
+// skipped runs as the clocks go forward (01:30 runs at 02:30). The last run of
+// every entry is kept in the local store; runs missed while the daemon was
+// down are skipped, or run once on start with the catch_up policy (per entry,
+// or for all with SENSOR_SCHEDULE_MISSED=catch_up).
+//
+// The older daily mode schedule, SENSOR_MODE_SCHEDULE ("22:30=armed,07:00=home"),
+// is deprecated and runs as the equivalent set_mode entries.
+//
+use crate::capture::CaptureKind;
+use crate::events::{EventBus, SensorEvent, now_timestamp};
+use crate::mode::Mode;
+use crate::protocol::{Command, CommandRequest, CommandSource};
+use crate::store::SharedStore;
+use chrono::{DateTime, Duration as ChronoDuration, LocalResult, NaiveDateTime, NaiveTime, Offset, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use std::str::FromStr;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+
+pub const SCHEDULE_DOC_ID: &str = "schedule";
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(30);
+const TIMEZONE_FILE: &str = "/etc/timezone";
+
+/// What to do with runs missed while the daemon was down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissedPolicy {
+    Skip,
+    CatchUp
+}
+
+impl MissedPolicy {
+    pub fn parse(s: &str) -> Option<MissedPolicy> {
+        match s {
+            "skip" => Some(MissedPolicy::Skip),
+            "catch_up" => Some(MissedPolicy::CatchUp),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScheduledTask {
+    /// The entry as configured, identifies it in the local store.
+    pub name: String,
+    pub schedule: Schedule,
+    pub command: Command,
+    pub missed: MissedPolicy
+}
+
+/// Time zone schedules are evaluated in: `name`, else the system's, else UTC.
+pub fn schedule_timezone(name: Option<&str>) -> Tz {
+    let system = std::fs::read_to_string(TIMEZONE_FILE).ok();
+    for candidate in [name, system.as_deref().map(str::trim)].into_iter().flatten() {
+        match candidate.parse::<Tz>() {
+            Ok(tz) => return tz,
+            Err(_) => log::warn!("Unknown time zone {:?} for schedules", candidate)
+        }
+    }
+    Tz::UTC
+}
+
+/// Parse SENSOR_SCHEDULES, logging and dropping invalid entries.
+pub fn parse_schedules(s: &str, default_missed: MissedPolicy) -> Vec<ScheduledTask> {
+    let mut tasks = Vec::new();
+    for entry in s.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+        match parse_task(entry, default_missed) {
+            Ok(task) => tasks.push(task),
+            Err(e) => log::warn!("Schedule entry {:?} ignored: {}", entry, e)
+        }
+    }
+    tasks
+}
+
+/// Parse the deprecated SENSOR_MODE_SCHEDULE ("22:30=armed,07:00=home") into daily set_mode tasks.
+pub fn parse_mode_schedule(s: &str, default_missed: MissedPolicy) -> Vec<ScheduledTask> {
+    let mut tasks = Vec::new();
+    for entry in s.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let task = entry.split_once('=')
+            .and_then(|(time, mode)| Some((NaiveTime::parse_from_str(time.trim(), "%H:%M").ok()?, Mode::parse(mode.trim())?)))
+            .map(|(at, mode)| format!("{} {} * * * set_mode {}", at.minute(), at.hour(), mode.as_str()))
+            .and_then(|cron| parse_task(&cron, default_missed).ok());
+        match task {
+            Some(task) => {
+                log::warn!("SENSOR_MODE_SCHEDULE is deprecated, add {:?} to SENSOR_SCHEDULES instead", task.name);
+                tasks.push(task);
+            }
+            None => log::warn!("Mode schedule entry {:?} ignored, expected HH:MM=armed|home|disarmed", entry)
+        }
+    }
+    tasks
+}
+
+fn parse_task(entry: &str, default_missed: MissedPolicy) -> Result<ScheduledTask, String> {
+    let fields: Vec<&str> = entry.split_whitespace().collect();
+    if fields.len() < 6 {
+        return Err(String::from("expected 5 cron fields and a command"));
+    }
+    // cron wants seconds first
+    let schedule = Schedule::from_str(&format!("0 {}", fields[..5].join(" "))).map_err(|e| format!("invalid cron expression: {}", e))?;
+
+    let mut words = &fields[5..];
+    let mut missed = default_missed;
+    if let Some(policy) = words.last().and_then(|word| MissedPolicy::parse(word)) {
+        missed = policy;
+        words = &words[..words.len() - 1];
+    }
+    Ok(ScheduledTask { name: entry.to_string(), schedule, command: parse_command(words)?, missed })
+}
+
+// optional numeric argument
+fn number<T: FromStr>(word: Option<&&str>) -> Result<Option<T>, String> {
+    word.map(|word| word.parse().map_err(|_| format!("invalid number {:?}", word))).transpose()
+}
+
+fn parse_command(words: &[&str]) -> Result<Command, String> {
+    match words {
+        ["refresh"] => Ok(Command::Refresh { notify: None }),
+        ["refresh", "quiet"] => Ok(Command::Refresh { notify: Some(false) }),
+        ["status"] => Ok(Command::Status),
+        ["health_summary"] => Ok(Command::HealthSummary),
+        ["set_mode", mode] => Mode::parse(mode)
+            .map(|mode| Command::SetMode { mode })
+            .ok_or_else(|| format!("unknown mode {:?}", mode)),
+        ["capture", "photo"] => Ok(Command::Capture { kind: CaptureKind::Photo, seconds: None }),
+        ["capture"] | ["capture", "clip", ..] if words.len() <= 3 => Ok(Command::Capture { kind: CaptureKind::Clip, seconds: number(words.get(2))? }),
+        ["diagnostics", ..] if words.len() <= 2 => Ok(Command::Diagnostics { lines: number(words.get(1))? }),
+        ["restart" | "reboot", ..] => Err(String::from("restarts and reboots can't be scheduled")),
+        _ => Err(format!("unknown command {:?}", words.join(" ")))
+    }
+}
+
+// wall clock time `local` in `tz`: the first if it occurs twice, as if the clocks hadn't gone forward yet if it
+// is skipped
+fn wall_clock(tz: &Tz, local: NaiveDateTime) -> Option<DateTime<Tz>> {
+    match tz.from_local_datetime(&local) {
+        LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => Some(time),
+        LocalResult::None => {
+            let before = tz.offset_from_utc_datetime(&(local - ChronoDuration::days(1))).fix();
+            Some(tz.from_utc_datetime(&(local - before)))
+        }
+    }
+}
+
+// times `task` is due after `after`, on the wall clock of `tz`
+fn due_times<'a>(task: &'a ScheduledTask, tz: &'a Tz, after: DateTime<Utc>) -> impl Iterator<Item = DateTime<Tz>> + 'a {
+    // the cron schedule runs on the wall clock as if it were UTC, without DST changes, from an hour
+    // earlier for skipped times that run after the clocks went forward
+    let wall = after.with_timezone(tz).naive_local().and_utc() - ChronoDuration::hours(1);
+    task.schedule.after(&wall)
+        .filter_map(move |local| wall_clock(tz, local.naive_utc()))
+        .filter(move |time| time.with_timezone(&Utc) > after)
+}
+
+// the latest time `task` was due in (after, now]
+fn last_due(task: &ScheduledTask, tz: &Tz, after: DateTime<Utc>, now: DateTime<Utc>) -> Option<(DateTime<Tz>, usize)> {
+    let mut due = None;
+    let mut count = 0;
+    for time in due_times(task, tz, after).take_while(|time| time.with_timezone(&Utc) <= now) {
+        due = Some(time);
+        count += 1;
+    }
+    due.map(|time| (time, count))
+}
+
+// time `task` runs after, on a start at `started` with its last run at `last_run`:
+// from the last run with catch_up (the missed runs come due once), else from the start
+fn runs_from(task: &ScheduledTask, tz: &Tz, last_run: Option<DateTime<Utc>>, started: DateTime<Utc>) -> DateTime<Utc> {
+    match (last_run, last_due(task, tz, last_run.unwrap_or(started), started)) {
+        (Some(last_run), Some((_, missed))) if task.missed == MissedPolicy::CatchUp => {
+            log::info!("Schedule {:?} missed {} runs while down, catching up once", task.name, missed);
+            last_run
+        }
+        (Some(_), Some((_, missed))) => {
+            log::info!("Schedule {:?} missed {} runs while down, skipped", task.name, missed);
+            started
+        }
+        _ => started
+    }
+}
+
+fn to_datetime(timestamp: f64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt((timestamp * 1000.0) as i64).single().unwrap_or_else(Utc::now)
+}
+
+// hand the command to the command processor, as the API and MQTT do
+fn publish_task(bus: &EventBus, task: &ScheduledTask) {
+    let request = CommandRequest::local(SCHEDULE_DOC_ID, task.command.clone());
+    log::info!("Scheduled command: {} ({}), from {:?}", request.command.name(), request.command_id, task.name);
+    bus.publish(SensorEvent::CommandReceived {
+        source: CommandSource::Schedule,
+        doc_id: SCHEDULE_DOC_ID.to_string(),
+        request,
+        timestamp: now_timestamp()
+    });
+}
+
+/// Run every task when it comes due, until shutdown.
+pub fn spawn_scheduler(bus: &EventBus, store: SharedStore, tasks: Vec<ScheduledTask>, tz: Tz) -> JoinHandle<()> {
+    let mut events = bus.subscribe("scheduler");
+    let bus = bus.clone();
+
+    tokio::spawn(async move {
+        log::info!("Scheduler started, {} tasks in {}", tasks.len(), tz.name());
+
+        // last run of each task, from the store so runs missed while down are known
+        let started = Utc::now();
+        let mut last_runs = Vec::with_capacity(tasks.len());
+        for task in &tasks {
+            let (lookup, name) = (store.clone(), task.name.clone());
+            let last_run = match tokio::task::spawn_blocking(move || lookup.lock().unwrap().schedule_last_run(&name)).await {
+                Ok(Ok(last_run)) => last_run.map(to_datetime),
+                Ok(Err(e)) => {
+                    log::error!("Failed to read the last run of {:?}: {:?}", task.name, e);
+                    None
+                }
+                Err(e) => {
+                    log::error!("Failed to read the last run of {:?}: {:?}", task.name, e);
+                    None
+                }
+            };
+            let from = runs_from(task, &tz, last_run, started);
+            if let Some(next) = due_times(task, &tz, started).next() {
+                log::info!("Schedule {:?} next runs at {}", task.name, next);
+            }
+            last_runs.push(from);
+        }
+
+        let mut iv = interval(SCHEDULER_INTERVAL);
+        iv.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = iv.tick() => {}
+                event = events.recv() => match event {
+                    Some(SensorEvent::ShutdownRequested { .. }) | None => break,
+                    Some(_) => continue
+                }
+            }
+
+            let now = Utc::now();
+            for (task, last_run) in tasks.iter().zip(last_runs.iter_mut()) {
+                // runs once, however many times it came due since the last check
+                let Some((due, _)) = last_due(task, &tz, *last_run, now) else { continue };
+                *last_run = now;
+                publish_task(&bus, task);
+
+                let (record, name, due) = (store.clone(), task.name.clone(), due.timestamp() as f64);
+                match tokio::task::spawn_blocking(move || record.lock().unwrap().set_schedule_last_run(&name, due)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => log::error!("Failed to record the run of {:?}: {:?}", task.name, e),
+                    Err(e) => log::error!("Failed to record the run of {:?}: {:?}", task.name, e)
+                }
+            }
+        }
+        log::info!("Scheduler exiting");
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Europe::London;
+
+    fn task(entry: &str) -> ScheduledTask {
+        parse_task(entry, MissedPolicy::Skip).unwrap()
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    // due times of `task` in London in (after, until], in UTC
+    fn due_between(task: &ScheduledTask, after: &str, until: &str) -> Vec<DateTime<Utc>> {
+        let until = utc(until);
+        due_times(task, &London, utc(after))
+            .map(|time| time.with_timezone(&Utc))
+            .take_while(|time| *time <= until)
+            .collect()
+    }
+
+    #[test]
+    fn parses_cron_command_and_policy() {
+        let task = parse_task("0 23 * * * set_mode armed catch_up", MissedPolicy::Skip).unwrap();
+        assert_eq!(task.name, "0 23 * * * set_mode armed catch_up");
+        assert_eq!(task.command, Command::SetMode { mode: Mode::Armed });
+        assert_eq!(task.missed, MissedPolicy::CatchUp);
+
+        let task = parse_task("0 9 * * Mon health_summary", MissedPolicy::CatchUp).unwrap();
+        assert_eq!(task.command, Command::HealthSummary);
+        assert_eq!(task.missed, MissedPolicy::CatchUp);
+        assert_eq!(due_between(&task, "2024-06-01T00:00:00Z", "2024-06-11T00:00:00Z"),
+            vec![utc("2024-06-03T08:00:00Z"), utc("2024-06-10T08:00:00Z")]);
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        assert_eq!(parse_task("0 7 * * status", MissedPolicy::Skip).unwrap_err(), "expected 5 cron fields and a command");
+        assert!(parse_task("61 7 * * * status", MissedPolicy::Skip).unwrap_err().starts_with("invalid cron expression"));
+        assert_eq!(parse_task("0 7 * * * reboot", MissedPolicy::Skip).unwrap_err(), "restarts and reboots can't be scheduled");
+        assert_eq!(parse_task("0 7 * * * set_mode away", MissedPolicy::Skip).unwrap_err(), "unknown mode \"away\"");
+
+        let tasks = parse_schedules("0 7 * * * status; nonsense; ; 0 8 * * * refresh quiet", MissedPolicy::Skip);
+        assert_eq!(tasks.iter().map(|task| task.name.as_str()).collect::<Vec<_>>(), ["0 7 * * * status", "0 8 * * * refresh quiet"]);
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_command(&["refresh"]), Ok(Command::Refresh { notify: None }));
+        assert_eq!(parse_command(&["refresh", "quiet"]), Ok(Command::Refresh { notify: Some(false) }));
+        assert_eq!(parse_command(&["status"]), Ok(Command::Status));
+        assert_eq!(parse_command(&["capture"]), Ok(Command::Capture { kind: CaptureKind::Clip, seconds: None }));
+        assert_eq!(parse_command(&["capture", "clip", "10"]), Ok(Command::Capture { kind: CaptureKind::Clip, seconds: Some(10) }));
+        assert_eq!(parse_command(&["capture", "photo"]), Ok(Command::Capture { kind: CaptureKind::Photo, seconds: None }));
+        assert_eq!(parse_command(&["diagnostics", "500"]), Ok(Command::Diagnostics { lines: Some(500) }));
+        assert_eq!(parse_command(&["diagnostics", "many"]), Err(String::from("invalid number \"many\"")));
+        assert!(parse_command(&["capture", "clip", "10", "extra"]).is_err());
+        assert!(parse_command(&["restart"]).is_err());
+        assert!(parse_command(&["dance"]).is_err());
+    }
+
+    #[test]
+    fn converts_the_mode_schedule() {
+        let tasks = parse_mode_schedule("22:30=armed, 7:05=home, 25:00=armed, 08:00=away", MissedPolicy::Skip);
+        assert_eq!(tasks.iter().map(|task| task.name.as_str()).collect::<Vec<_>>(), ["30 22 * * * set_mode armed", "5 7 * * * set_mode home"]);
+        assert_eq!(tasks[1].command, Command::SetMode { mode: Mode::Home });
+    }
+
+    #[test]
+    fn last_due_is_the_latest_run_with_the_number_missed() {
+        let task = task("0 7 * * * status");
+        let after = utc("2024-06-01T06:00:00Z");
+        assert_eq!(last_due(&task, &Tz::UTC, after, utc("2024-06-01T06:59:59Z")), None);
+
+        let (due, count) = last_due(&task, &Tz::UTC, after, utc("2024-06-03T08:00:00Z")).unwrap();
+        assert_eq!((due.with_timezone(&Utc), count), (utc("2024-06-03T07:00:00Z"), 3));
+        // not again at the time it was due
+        assert_eq!(last_due(&task, &Tz::UTC, utc("2024-06-03T07:00:00Z"), utc("2024-06-03T08:00:00Z")), None);
+    }
+
+    #[test]
+    fn follows_dst_changes() {
+        // 07:00 is 06:00 UTC in summer and 07:00 UTC in winter
+        let task = task("0 7 * * * status");
+        assert_eq!(due_between(&task, "2024-03-30T00:00:00Z", "2024-04-01T00:00:00Z"),
+            vec![utc("2024-03-30T07:00:00Z"), utc("2024-03-31T06:00:00Z")]);
+        assert_eq!(due_between(&task, "2024-10-26T00:00:00Z", "2024-10-28T00:00:00Z"),
+            vec![utc("2024-10-26T06:00:00Z"), utc("2024-10-27T07:00:00Z")]);
+    }
+
+    #[test]
+    fn runs_a_skipped_time_as_the_clocks_go_forward() {
+        // 01:30 doesn't exist on 31 March, it runs at 02:30 BST instead
+        let task = task("30 1 * * * set_mode armed");
+        assert_eq!(due_between(&task, "2024-03-30T00:00:00Z", "2024-04-01T12:00:00Z"),
+            vec![utc("2024-03-30T01:30:00Z"), utc("2024-03-31T01:30:00Z"), utc("2024-04-01T00:30:00Z")]);
+        // also when checked after the change
+        let (due, _) = last_due(&task, &London, utc("2024-03-31T00:59:00Z"), utc("2024-03-31T02:00:00Z")).unwrap();
+        assert_eq!(due.with_timezone(&Utc), utc("2024-03-31T01:30:00Z"));
+    }
+
+    #[test]
+    fn runs_a_repeated_time_once() {
+        // 01:30 occurs twice on 27 October (BST, then GMT), it runs at the first
+        let task = task("30 1 * * * set_mode armed");
+        assert_eq!(due_between(&task, "2024-10-26T00:00:00Z", "2024-10-28T12:00:00Z"),
+            vec![utc("2024-10-26T00:30:00Z"), utc("2024-10-27T00:30:00Z"), utc("2024-10-28T01:30:00Z")]);
+        assert_eq!(last_due(&task, &London, utc("2024-10-27T00:30:00Z"), utc("2024-10-27T02:00:00Z")), None);
+    }
+
+    #[test]
+    fn skips_runs_missed_while_down() {
+        let task = task("0 7 * * * status");
+        let (last_run, started) = (utc("2024-03-29T07:00:00Z"), utc("2024-03-31T12:00:00Z"));
+        assert_eq!(runs_from(&task, &London, Some(last_run), started), started);
+        assert_eq!(last_due(&task, &London, started, utc("2024-03-31T12:00:30Z")), None);
+    }
+
+    #[test]
+    fn catches_up_runs_missed_while_down_once() {
+        // down across the change to BST, missing 30 March 07:00 GMT and 31 March 07:00 BST
+        let task = parse_task("0 7 * * * set_mode armed catch_up", MissedPolicy::Skip).unwrap();
+        let (last_run, started) = (utc("2024-03-29T07:00:00Z"), utc("2024-03-31T12:00:00Z"));
+        let from = runs_from(&task, &London, Some(last_run), started);
+        assert_eq!(from, last_run);
+
+        let (due, missed) = last_due(&task, &London, from, utc("2024-03-31T12:00:30Z")).unwrap();
+        assert_eq!((due.with_timezone(&Utc), missed), (utc("2024-03-31T06:00:00Z"), 2));
+    }
+
+    #[test]
+    fn runs_from_the_start_without_missed_runs() {
+        let task = parse_task("0 7 * * * status catch_up", MissedPolicy::Skip).unwrap();
+        let started = utc("2024-06-01T12:00:00Z");
+        assert_eq!(runs_from(&task, &London, None, started), started);
+        assert_eq!(runs_from(&task, &London, Some(utc("2024-06-01T11:00:00Z")), started), started);
+    }
+}
@@ -0,0 +1,61 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Shutdown signal shared by main and long running tasks.
+//
+// SIGINT/SIGTERM (via ctrlc) trigger a graceful shutdown, a second signal
+// exits immediately in case the graceful path is stuck.
+//
+use std::sync::Arc;
+use tokio::sync::watch;
+
+#[derive(Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<Option<String>>>,
+    rx: watch::Receiver<Option<String>>
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (tx, rx) = watch::channel(None);
+        Shutdown { tx: Arc::new(tx), rx }
+    }
+
+    /// Request shutdown, the first reason wins.
+    pub fn trigger(&self, reason: &str) {
+        self.tx.send_if_modified(|current| {
+            if current.is_some() {
+                return false;
+            }
+            *current = Some(reason.to_string());
+            true
+        });
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.rx.borrow().is_some()
+    }
+
+    /// Wait until shutdown is requested and return the reason.
+    pub async fn wait(&mut self) -> String {
+        match self.rx.wait_for(|reason| reason.is_some()).await {
+            Ok(reason) => reason.clone().unwrap_or_default(),
+            // sender lives in self, so this can't happen
+            Err(_) => String::from("unknown")
+        }
+    }
+}
+
+/// Trigger `shutdown` on SIGINT or SIGTERM.
+pub fn install_signal_handler(shutdown: &Shutdown) -> Result<(), ctrlc::Error> {
+    let shutdown = shutdown.clone();
+    ctrlc::set_handler(move || {
+        if shutdown.is_triggered() {
+            log::warn!("Second shutdown signal, exiting now");
+            std::process::exit(1);
+        }
+        log::info!("Shutdown signal received");
+        shutdown.trigger("signal");
+    })
+}
@@ -0,0 +1,379 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Event bus sinks: cloud (Firestore via Python), low temperature alerts and Pub/Sub telemetry.
+//
+// The security mode in effect when a state is reported decides whether it
+// notifies (unless forced) and records video.
+//
+// Cloud updates are queued in the durable outbox as soon as they are received.
+// A separate delivery task sends them (Python, blocking) on the blocking pool
+// once the cloud is reachable, so a slow or unreachable cloud never holds up
+// the bus. Every sink exits after ShutdownRequested, having handled all events published
+// before it; anything the cloud sink could not deliver stays in the outbox for the next start.
+//
+// The Python writes set online: true, so at shutdown the cloud writes are closed
+// (waiting for the one in flight) before online: false is written.
+//
+use crate::connectivity::CloudReady;
+use crate::events::{EventBus, SensorEvent, AlertKind, now_timestamp};
+use crate::logging::correlation_span;
+use crate::metrics;
+use crate::mode::{DoorActions, ModeSwitch};
+use crate::outbox::{Outbox, CloudUpdate};
+use crate::pins::DoorPin;
+use crate::telemetry::TelemetryPublisher;
+use crate::{update_state_temp_f_humidity_and_notify_user, update_temp_and_humidity, read_shared_state, UpdateRejected};
+use crate::{SENSOR_ID_SECONDARY, DHT22_TEMP_WARNING_F, UPDATE_TEMP_HUMIDITY_FREQUENCY_SECONDS};
+use pyo3::{PyResult, Python};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Instant, Duration, MissedTickBehavior};
+
+const WARNING_COOLDOWN: Duration = Duration::from_secs(8 * 60 * 60); // 8 hours
+const OUTBOX_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+const OUTBOX_DELAYED_AFTER_SECONDS: f64 = 60.0;
+
+/// Python cloud writes, closed at shutdown so none lands after online: false.
+#[derive(Clone, Default)]
+pub struct CloudWrites(Arc<RwLock<bool>>);
+
+impl CloudWrites {
+    // run `write` unless closed, holding off `close` until it is done
+    fn run<T>(&self, write: impl FnOnce() -> T) -> Option<T> {
+        let closed = self.0.read().unwrap();
+        (!*closed).then(write)
+    }
+
+    /// Wait for the write in flight, then refuse new ones.
+    /// Blocks, call from the blocking pool.
+    pub fn close(&self) {
+        *self.0.write().unwrap() = true;
+    }
+}
+
+/// Queue reported state (with notification) and periodic secondary readings in the outbox,
+/// delivering them in order once `ready` (and until `writes` are closed).
+pub fn spawn_cloud_sink(bus: &EventBus, user: String, outbox: Outbox, modes: ModeSwitch, ready: CloudReady, writes: CloudWrites) -> JoinHandle<()> {
+    let mut events = bus.subscribe_lossless("cloud");
+    metrics::OUTBOX_DEPTH.set(outbox.len() as i64);
+    let outbox = Arc::new(Mutex::new(outbox));
+    let queued = Arc::new(Notify::new());
+    let (stop, stopped) = watch::channel(false);
+    let delivery = spawn_delivery(outbox.clone(), user, queued.clone(), stopped, ready, writes);
+
+    tokio::spawn(async move {
+        log::info!("Cloud sink started");
+        let publish_interval = Duration::from_secs(UPDATE_TEMP_HUMIDITY_FREQUENCY_SECONDS);
+        let mut last_publish_time: Option<Instant> = None;
+
+        // queue on receipt, delivery never holds up the bus
+        while let Some((correlation_id, event)) = events.recv_correlated().await {
+            let update = match event {
+                SensorEvent::StateReported { state, temp_f, humidity, force_notify, timestamp } => {
+                    let mode = modes.current().door_actions();
+                    let actions = DoorActions { notify: mode.notify || force_notify, video: mode.video };
+                    CloudUpdate::State { state, temp_f, humidity, force_notify, actions, timestamp }
+                }
+                SensorEvent::ClimateSampled { sensor_id: SENSOR_ID_SECONDARY, temp_f, humidity, timestamp } => {
+                    // Periodic Cloud Update (Robust)
+                    let should_publish = match last_publish_time {
+                        None => true,
+                        Some(last) => last.elapsed() >= publish_interval,
+                    };
+                    if !should_publish {
+                        continue;
+                    }
+                    last_publish_time = Some(Instant::now());
+                    CloudUpdate::Reading { temp_f, humidity, timestamp }
+                }
+                SensorEvent::ShutdownRequested { .. } => break,
+                _ => continue
+            };
+
+            let mut outbox = outbox.lock().unwrap();
+            if let Err(e) = outbox.push(update, Some(correlation_id)) {
+                log::error!("Failed to queue cloud update: {:?}", e);
+            }
+            metrics::OUTBOX_DEPTH.set(outbox.len() as i64);
+            queued.notify_one();
+        }
+
+        // one last delivery of everything queued before the shutdown
+        stop.send_replace(true);
+        if let Err(e) = delivery.await {
+            log::error!("Outbox delivery task failed: {:?}", e);
+        }
+
+        let outbox = outbox.lock().unwrap();
+        if !outbox.is_empty() {
+            log::warn!("Cloud sink exiting with {} undelivered updates, they will be sent on next start", outbox.len());
+        }
+        if outbox.dropped() > 0 {
+            log::warn!("{} cloud updates were dropped because the outbox was full", outbox.dropped());
+        }
+        log::info!("Cloud sink exiting");
+    })
+}
+
+// Deliver queued updates whenever some are queued (and every retry interval) once the cloud is reachable,
+// until stopped
+fn spawn_delivery(outbox: Arc<Mutex<Outbox>>, user: String, queued: Arc<Notify>, mut stopped: watch::Receiver<bool>, mut ready: CloudReady, writes: CloudWrites) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut retry = interval(OUTBOX_RETRY_INTERVAL);
+        retry.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            if !*stopped.borrow() {
+                tokio::select! {
+                    _ = queued.notified() => {}
+                    _ = retry.tick() => {}
+                    changed = stopped.changed() => if changed.is_err() {
+                        // the sink is gone
+                        return;
+                    },
+                    _ = ready.wait(), if !ready.is_ready() => {
+                        log::info!("Cloud reachable, delivering {} queued updates", outbox.lock().unwrap().len());
+                    }
+                }
+            }
+            let stopping = *stopped.borrow();
+
+            // keep queueing until the cloud is reachable
+            if ready.is_ready() {
+                // Python calls block, deliver on the blocking pool
+                let (drain_outbox, drain_user, drain_writes) = (outbox.clone(), user.clone(), writes.clone());
+                if let Err(e) = tokio::task::spawn_blocking(move || drain(&drain_outbox, &drain_writes, |update, delayed| deliver(&drain_user, update, delayed))).await {
+                    log::error!("Outbox delivery failed: {:?}", e);
+                }
+                metrics::OUTBOX_DEPTH.set(outbox.lock().unwrap().len() as i64);
+            }
+
+            if stopping {
+                break;
+            }
+        }
+    })
+}
+
+// Send `update` to the cloud (Python, blocking), returning the call's name and its result.
+fn deliver(user: &str, update: &CloudUpdate, delayed: bool) -> (&'static str, PyResult<()>) {
+    match *update {
+        CloudUpdate::State { state, temp_f, humidity, force_notify, actions, timestamp } => {
+            ("update_state", update_state_temp_f_humidity_and_notify_user(user.to_string(), state, Some(temp_f), Some(humidity), Some(force_notify), actions, timestamp, delayed))
+        }
+        CloudUpdate::Reading { temp_f, humidity, timestamp } => {
+            ("update_readings", update_temp_and_humidity(user.to_string(), Some(temp_f), Some(humidity), timestamp))
+        }
+    }
+}
+
+// Deliver queued updates in order with `deliver`, stopping at the first failure (retried later)
+// or once `writes` are closed. Only updates rejected by validation are dropped. The outbox is only
+// locked to read the next entry and to ack it, never across a Python call.
+fn drain(outbox: &Mutex<Outbox>, writes: &CloudWrites, mut deliver: impl FnMut(&CloudUpdate, bool) -> (&'static str, PyResult<()>)) {
+    loop {
+        let Some(entry) = outbox.lock().unwrap().front().cloned() else { return };
+        let _span = entry.correlation_id.map(|correlation_id| correlation_span("cloud", correlation_id).entered());
+        let delayed = now_timestamp() - entry.update.timestamp() > OUTBOX_DELAYED_AFTER_SECONDS;
+        let started = Instant::now();
+        let Some((call, result)) = writes.run(|| deliver(&entry.update, delayed)) else {
+            log::info!("Cloud writes closed, {} updates left for the next start", outbox.lock().unwrap().len());
+            return;
+        };
+        metrics::observe_cloud_call(call, started.elapsed().as_secs_f64(), result.is_ok());
+
+        match result {
+            Ok(()) => {
+                if delayed {
+                    log::info!("Delivered delayed update {} ({:.0}s late)", entry.seq, now_timestamp() - entry.update.timestamp());
+                }
+            }
+            // rejected by validation, retrying can't help
+            Err(e) if Python::with_gil(|py| e.is_instance_of::<UpdateRejected>(py)) => {
+                log::error!("Cloud update {} rejected, dropping it: {:?}", entry.seq, e);
+            }
+            Err(e) => {
+                log::error!("Cloud update {} failed, {} queued for retry: {:?}", entry.seq, outbox.lock().unwrap().len(), e);
+                return;
+            }
+        }
+
+        if let Err(e) = outbox.lock().unwrap().ack(entry.seq) {
+            log::error!("Failed to ack cloud update {}: {:?}", entry.seq, e);
+            return;
+        }
+    }
+}
+
+/// Notify the user when the secondary sensor reads below the warning level, at most once per cooldown.
+/// The notification goes out as a forced StateReported, so it is queued with every other cloud update.
+pub fn spawn_alert_sink(bus: &EventBus, door_pin: DoorPin) -> JoinHandle<()> {
+    let mut events = bus.subscribe("alerts");
+    let bus = bus.clone();
+
+    tokio::spawn(async move {
+        log::info!("Alert sink started");
+        let mut last_warning_time: Option<Instant> = None;
+
+        while let Some((correlation_id, event)) = events.recv_correlated().await {
+            let (temp_f, humidity, timestamp) = match event {
+                SensorEvent::ClimateSampled { sensor_id: SENSOR_ID_SECONDARY, temp_f, humidity, timestamp } => (temp_f, humidity, timestamp),
+                SensorEvent::ShutdownRequested { .. } => break,
+                _ => continue
+            };
+
+            if temp_f < DHT22_TEMP_WARNING_F && temp_f != 0.0 {
+                let can_warn = match last_warning_time {
+                    None => true,
+                    Some(last) => last.elapsed() >= WARNING_COOLDOWN,
+                };
+
+                if can_warn {
+                    let _span = correlation_span("alert", correlation_id).entered();
+                    log::warn!("(Secondary) Temp below warning level: {:.2} °F", temp_f);
+                    bus.publish_correlated(correlation_id, SensorEvent::StateReported {
+                        state: read_shared_state(&door_pin),
+                        temp_f,
+                        humidity,
+                        force_notify: true,
+                        timestamp
+                    });
+                    last_warning_time = Some(Instant::now());
+                    log::info!("Warning queued. Cooldown active for 8 hours.");
+                    bus.publish_correlated(correlation_id, SensorEvent::AlertRaised {
+                        kind: AlertKind::LowTemperature,
+                        sensor_id: SENSOR_ID_SECONDARY,
+                        temp_f,
+                        humidity,
+                        timestamp: now_timestamp()
+                    });
+                }
+            }
+        }
+        log::info!("Alert sink exiting");
+    })
+}
+
+/// Publish every accepted reading to Pub/Sub, flushing batched messages on shutdown.
+pub fn spawn_telemetry_sink(bus: &EventBus, mut telemetry: TelemetryPublisher) -> JoinHandle<()> {
+    let mut events = bus.subscribe("telemetry");
+
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            match event {
+                SensorEvent::ClimateSampled { sensor_id, temp_f, humidity, timestamp } => {
+                    telemetry.spawn_publish_reading(sensor_id, temp_f, humidity, timestamp);
+                }
+                SensorEvent::ShutdownRequested { .. } => break,
+                _ => {}
+            }
+        }
+        telemetry.shutdown().await;
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::exceptions::{PyRuntimeError, PyValueError};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // fresh directory per test, removed on drop
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new() -> TestDir {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!("sensor-sinks-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+            let _ = std::fs::remove_dir_all(&dir);
+            TestDir(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn outbox_with(dir: &TestDir, timestamps: &[f64]) -> Mutex<Outbox> {
+        let mut outbox = Outbox::open(&dir.0, 10).unwrap();
+        for &timestamp in timestamps {
+            outbox.push(CloudUpdate::Reading { temp_f: 70.0, humidity: 40.0, timestamp }, None).unwrap();
+        }
+        Mutex::new(outbox)
+    }
+
+    #[test]
+    fn transient_failure_stays_queued() {
+        let dir = TestDir::new();
+        let outbox = outbox_with(&dir, &[1.0, 2.0]);
+
+        // e.g. Firebase init failed (Python return code 1)
+        let mut calls = 0;
+        drain(&outbox, &CloudWrites::default(), |_, _| { calls += 1; ("update_readings", Err(PyRuntimeError::new_err("Unexpected error"))) });
+        assert_eq!(calls, 1);
+        assert_eq!(outbox.lock().unwrap().len(), 2);
+
+        // a Python ValueError is not a validation reject either
+        drain(&outbox, &CloudWrites::default(), |_, _| ("update_readings", Err(PyValueError::new_err("bad response"))));
+        assert_eq!(outbox.lock().unwrap().len(), 2);
+
+        // and survives a restart
+        drop(outbox);
+        assert_eq!(Outbox::open(&dir.0, 10).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn validation_reject_is_dropped() {
+        let dir = TestDir::new();
+        let outbox = outbox_with(&dir, &[1.0, 2.0]);
+
+        // the rejected update is dropped, the next one still delivered
+        let mut delivered = Vec::new();
+        drain(&outbox, &CloudWrites::default(), |update, _| {
+            if update.timestamp() == 1.0 {
+                return ("update_readings", Err(UpdateRejected::new_err("rejected")));
+            }
+            delivered.push(update.timestamp());
+            ("update_readings", Ok(()))
+        });
+        assert_eq!(delivered, vec![2.0]);
+        assert!(outbox.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn nothing_is_written_once_closed() {
+        let dir = TestDir::new();
+        let outbox = outbox_with(&dir, &[1.0]);
+        let writes = CloudWrites::default();
+        writes.close();
+
+        drain(&outbox, &writes, |_, _| panic!("written after close"));
+        assert_eq!(outbox.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn close_waits_for_the_write_in_flight() {
+        let writes = CloudWrites::default();
+        let (started, wait_started) = std::sync::mpsc::channel();
+        let writer = {
+            let writes = writes.clone();
+            std::thread::spawn(move || writes.run(|| {
+                started.send(()).unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                now_timestamp()
+            }))
+        };
+        wait_started.recv().unwrap();
+
+        writes.close();
+        let closed = now_timestamp();
+        assert!(writer.join().unwrap().is_some_and(|written| written <= closed));
+        assert_eq!(writes.run(|| ()), None);
+    }
+}
@@ -0,0 +1,709 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// On-device time-series store (SQLite) for climate samples and door transitions.
+//
+// Raw samples are kept for SENSOR_STORE_RAW_DAYS (default 7), completed hours
+// are rolled up into climate_hourly (min/max/avg per sensor) and kept for
+// SENSOR_STORE_HOURLY_DAYS (default 730). Door transitions are small and are
+// kept as long as the hourly aggregates. Maintenance (roll-up and retention)
+// runs on a timer, about once an hour, so a quiet sensor is still pruned.
+//
+// Mode changes (mode_changes) are the audit trail of the security mode, the
+// latest one is the current mode; they are kept as long as door transitions,
+// except the latest, which is kept however old it is.
+//
+// Restarts and reboots run by command (power_actions) are counted for their
+// daily limit and completed on the next start, see power.rs.
+//
+// The last run of every scheduled task (schedule_runs) is kept so runs missed
+// while the daemon was down are known, see scheduler.rs.
+//
+// It also holds the command ledger: every command document revision that was
+// run (or expired), so replays (listener reconnects, restarts) are not run
+// again. A listener only ever replays a document's current revision, for as
+// long as the document exists, so the newest entry of every document is kept
+// forever; older revisions can't come back and are kept as long as raw
+// samples. Freshness is checked before the ledger, so a revision pruned from
+// it is still never run twice.
+//
+use crate::events::{EventBus, SensorEvent, State};
+use crate::mode::{Mode, ModeChange};
+use crate::power::PowerAction;
+use crate::protocol::CommandStatus;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+
+pub const STORE_RAW_DAYS_DEFAULT: u32 = 7;
+pub const STORE_HOURLY_DAYS_DEFAULT: u32 = 730;
+const STORE_FILE: &str = "sensor-nhargrex.db";
+const STORE_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const SECONDS_PER_HOUR: i64 = 60 * 60;
+const SECONDS_PER_DAY: f64 = 24.0 * 60.0 * 60.0;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS climate_samples (
+        ts REAL NOT NULL,
+        sensor_id TEXT NOT NULL,
+        temp_f REAL NOT NULL,
+        humidity REAL NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS climate_samples_sensor_ts ON climate_samples (sensor_id, ts);
+
+    CREATE TABLE IF NOT EXISTS climate_hourly (
+        hour INTEGER NOT NULL,
+        sensor_id TEXT NOT NULL,
+        samples INTEGER NOT NULL,
+        temp_f_min REAL NOT NULL,
+        temp_f_max REAL NOT NULL,
+        temp_f_avg REAL NOT NULL,
+        humidity_min REAL NOT NULL,
+        humidity_max REAL NOT NULL,
+        humidity_avg REAL NOT NULL,
+        PRIMARY KEY (sensor_id, hour)
+    );
+
+    CREATE TABLE IF NOT EXISTS door_events (
+        ts REAL NOT NULL,
+        sensor_id TEXT NOT NULL,
+        state TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS door_events_ts ON door_events (ts);
+
+    CREATE TABLE IF NOT EXISTS mode_changes (
+        ts REAL NOT NULL,
+        mode TEXT NOT NULL,
+        previous TEXT NOT NULL,
+        source TEXT NOT NULL,
+        issuer TEXT,
+        command_id TEXT
+    );
+    CREATE INDEX IF NOT EXISTS mode_changes_ts ON mode_changes (ts);
+
+    CREATE TABLE IF NOT EXISTS power_actions (
+        id INTEGER PRIMARY KEY,
+        ts REAL NOT NULL,
+        action TEXT NOT NULL,
+        doc_id TEXT NOT NULL,
+        issuer TEXT,
+        command_id TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        boot_id TEXT,
+        instance_id TEXT,
+        status TEXT NOT NULL DEFAULT 'going_down',
+        completed_at REAL
+    );
+    CREATE INDEX IF NOT EXISTS power_actions_ts ON power_actions (ts);
+
+    CREATE TABLE IF NOT EXISTS schedule_runs (
+        schedule TEXT PRIMARY KEY,
+        last_run REAL NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS processed_commands (
+        doc_id TEXT NOT NULL,
+        revision TEXT NOT NULL,
+        command_id TEXT NOT NULL,
+        command TEXT NOT NULL,
+        processed_at REAL NOT NULL,
+        PRIMARY KEY (doc_id, revision)
+    );
+    CREATE INDEX IF NOT EXISTS processed_commands_command_id ON processed_commands (command_id);
+
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );
+";
+
+// columns added to existing tables since they were created, (table, column, definition)
+const COLUMNS_ADDED: &[(&str, &str, &str)] = &[
+    ("power_actions", "issuer", "TEXT"),
+    ("power_actions", "instance_id", "TEXT")
+];
+
+// add `column` to `table` in a store created before it existed
+fn add_column(conn: &Connection, table: &str, column: &str, definition: &str) -> rusqlite::Result<()> {
+    let exists = conn
+        .query_row(&format!("SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1", table), params![column], |_| Ok(()))
+        .optional()?
+        .is_some();
+    if !exists {
+        log::info!("Store: adding {}.{}", table, column);
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    pub raw_days: u32,
+    pub hourly_days: u32
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClimateSample {
+    pub timestamp: f64,
+    pub sensor_id: String,
+    pub temp_f: f32,
+    pub humidity: f32
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClimateHourly {
+    pub hour: i64,
+    pub sensor_id: String,
+    pub samples: u32,
+    pub temp_f_min: f32,
+    pub temp_f_max: f32,
+    pub temp_f_avg: f32,
+    pub humidity_min: f32,
+    pub humidity_max: f32,
+    pub humidity_avg: f32
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DoorEvent {
+    pub timestamp: f64,
+    pub sensor_id: String,
+    pub state: String
+}
+
+/// Restart or reboot run by command.
+#[derive(Debug, Clone)]
+pub struct PowerActionRecord {
+    pub id: i64,
+    pub timestamp: f64,
+    pub action: PowerAction,
+    /// Command document, issuer and id, for the result.
+    pub doc_id: String,
+    pub issuer: Option<String>,
+    pub command_id: String,
+    pub version: u32,
+    /// Kernel boot id and daemon process (pid and start time) when it was requested.
+    pub boot_id: Option<String>,
+    pub instance_id: Option<String>
+}
+
+/// Outcome of recording a command in the ledger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandClaim {
+    /// First time this revision is seen, and its command id is new.
+    New,
+    /// First time this revision is seen, but its command id was used before.
+    ReusedId,
+    /// This revision was already processed.
+    Replay
+}
+
+pub struct Store {
+    conn: Connection,
+    retention: Retention
+}
+
+pub type SharedStore = Arc<Mutex<Store>>;
+
+impl Store {
+
+    /// Open (or create) the store in `dir`.
+    pub fn open(dir: &Path, retention: Retention) -> rusqlite::Result<Store> {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            log::warn!("Failed to create store directory {:?}: {:?}", dir, e);
+        }
+        let conn = Connection::open(dir.join(STORE_FILE))?;
+        // WAL keeps readers (history queries) from blocking the writer
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.execute_batch(SCHEMA)?;
+        for (table, column, definition) in COLUMNS_ADDED {
+            add_column(&conn, table, column, definition)?;
+        }
+
+        log::info!("Store opened at {:?}: raw {} days, hourly {} days", dir.join(STORE_FILE), retention.raw_days, retention.hourly_days);
+        Ok(Store { conn, retention })
+    }
+
+    pub fn insert_climate(&self, sensor_id: &str, temp_f: f32, humidity: f32, timestamp: f64) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT INTO climate_samples (ts, sensor_id, temp_f, humidity) VALUES (?1, ?2, ?3, ?4)",
+            params![timestamp, sensor_id, temp_f, humidity])?;
+        Ok(())
+    }
+
+    pub fn insert_door(&self, sensor_id: &str, state: State, timestamp: f64) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT INTO door_events (ts, sensor_id, state) VALUES (?1, ?2, ?3)",
+            params![timestamp, sensor_id, state.as_str()])?;
+        Ok(())
+    }
+
+    /// Raw samples in [from, to), oldest first, optionally for one sensor.
+    pub fn climate_range(&self, sensor_id: Option<&str>, from: f64, to: f64) -> rusqlite::Result<Vec<ClimateSample>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT ts, sensor_id, temp_f, humidity FROM climate_samples
+             WHERE ts >= ?1 AND ts < ?2 AND (?3 IS NULL OR sensor_id = ?3)
+             ORDER BY ts")?;
+        let rows = stmt.query_map(params![from, to, sensor_id], |row| {
+            Ok(ClimateSample {
+                timestamp: row.get(0)?,
+                sensor_id: row.get(1)?,
+                temp_f: row.get(2)?,
+                humidity: row.get(3)?
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Hourly aggregates for hours starting in [from, to), oldest first, optionally for one sensor.
+    pub fn climate_hourly_range(&self, sensor_id: Option<&str>, from: f64, to: f64) -> rusqlite::Result<Vec<ClimateHourly>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT hour, sensor_id, samples, temp_f_min, temp_f_max, temp_f_avg, humidity_min, humidity_max, humidity_avg
+             FROM climate_hourly
+             WHERE hour >= ?1 AND hour < ?2 AND (?3 IS NULL OR sensor_id = ?3)
+             ORDER BY hour")?;
+        let rows = stmt.query_map(params![from, to, sensor_id], |row| {
+            Ok(ClimateHourly {
+                hour: row.get(0)?,
+                sensor_id: row.get(1)?,
+                samples: row.get(2)?,
+                temp_f_min: row.get(3)?,
+                temp_f_max: row.get(4)?,
+                temp_f_avg: row.get(5)?,
+                humidity_min: row.get(6)?,
+                humidity_max: row.get(7)?,
+                humidity_avg: row.get(8)?
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub fn insert_mode_change(&self, change: &ModeChange) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT INTO mode_changes (ts, mode, previous, source, issuer, command_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![change.timestamp, change.mode.as_str(), change.previous.as_str(), change.source, change.issuer, change.command_id])?;
+        Ok(())
+    }
+
+    /// Epoch for this process's journal ids, one more than the previous start's.
+    pub fn next_journal_epoch(&self) -> rusqlite::Result<u32> {
+        self.conn.execute(
+            "INSERT INTO meta (key, value) VALUES ('journal_epoch', 1)
+             ON CONFLICT (key) DO UPDATE SET value = value + 1", [])?;
+        let epoch: i64 = self.conn.query_row("SELECT value FROM meta WHERE key = 'journal_epoch'", [], |row| row.get(0))?;
+        Ok(epoch as u32)
+    }
+
+    /// Mode set by the latest change, None if it was never changed.
+    pub fn last_mode(&self) -> rusqlite::Result<Option<Mode>> {
+        let mode: Option<String> = self.conn
+            .query_row("SELECT mode FROM mode_changes ORDER BY ts DESC, rowid DESC LIMIT 1", [], |row| row.get(0))
+            .optional()?;
+        Ok(mode.and_then(|mode| Mode::parse(&mode)))
+    }
+
+    /// Mode changes in [from, to), oldest first.
+    pub fn mode_changes_range(&self, from: f64, to: f64) -> rusqlite::Result<Vec<ModeChange>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT ts, mode, previous, source, issuer, command_id FROM mode_changes WHERE ts >= ?1 AND ts < ?2 ORDER BY ts")?;
+        let rows = stmt.query_map(params![from, to], |row| {
+            let mode: String = row.get(1)?;
+            let previous: String = row.get(2)?;
+            Ok(ModeChange {
+                timestamp: row.get(0)?,
+                mode: Mode::parse(&mode).unwrap_or_default(),
+                previous: Mode::parse(&previous).unwrap_or_default(),
+                source: row.get(3)?,
+                issuer: row.get(4)?,
+                command_id: row.get(5)?
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Record a restart or reboot about to happen, returning its id.
+    pub fn insert_power_action(&self, record: &PowerActionRecord) -> rusqlite::Result<i64> {
+        self.conn.execute(
+            "INSERT INTO power_actions (ts, action, doc_id, issuer, command_id, version, boot_id, instance_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![record.timestamp, record.action.as_str(), record.doc_id, record.issuer, record.command_id, record.version, record.boot_id, record.instance_id])?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Times of the restarts and reboots after `from`, oldest first.
+    pub fn power_actions_since(&self, from: f64) -> rusqlite::Result<Vec<f64>> {
+        let mut stmt = self.conn.prepare("SELECT ts FROM power_actions WHERE ts > ?1 ORDER BY ts")?;
+        let rows = stmt.query_map(params![from], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    /// Restarts and reboots that went down and were not completed yet.
+    pub fn pending_power_actions(&self) -> rusqlite::Result<Vec<PowerActionRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, ts, action, doc_id, issuer, command_id, version, boot_id, instance_id FROM power_actions WHERE completed_at IS NULL ORDER BY ts")?;
+        let rows = stmt.query_map([], |row| {
+            let action: String = row.get(2)?;
+            Ok(PowerActionRecord {
+                id: row.get(0)?,
+                timestamp: row.get(1)?,
+                action: PowerAction::parse(&action).unwrap_or(PowerAction::Reboot),
+                doc_id: row.get(3)?,
+                issuer: row.get(4)?,
+                command_id: row.get(5)?,
+                version: row.get(6)?,
+                boot_id: row.get(7)?,
+                instance_id: row.get(8)?
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub fn complete_power_action(&self, id: i64, status: CommandStatus, completed_at: f64) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "UPDATE power_actions SET status = ?2, completed_at = ?3 WHERE id = ?1",
+            params![id, status.as_str(), completed_at])?;
+        Ok(())
+    }
+
+    /// When the scheduled task `schedule` last ran, None if never.
+    pub fn schedule_last_run(&self, schedule: &str) -> rusqlite::Result<Option<f64>> {
+        self.conn
+            .query_row("SELECT last_run FROM schedule_runs WHERE schedule = ?1", params![schedule], |row| row.get(0))
+            .optional()
+    }
+
+    pub fn set_schedule_last_run(&self, schedule: &str, last_run: f64) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO schedule_runs (schedule, last_run) VALUES (?1, ?2)",
+            params![schedule, last_run])?;
+        Ok(())
+    }
+
+    /// Record a command document revision as processed, unless it already was.
+    pub fn claim_command(&mut self, doc_id: &str, revision: &str, command_id: &str, command: &str, now: f64) -> rusqlite::Result<CommandClaim> {
+        let tx = self.conn.transaction()?;
+        let replay = tx
+            .query_row("SELECT 1 FROM processed_commands WHERE doc_id = ?1 AND revision = ?2", params![doc_id, revision], |_| Ok(()))
+            .optional()?
+            .is_some();
+        if replay {
+            return Ok(CommandClaim::Replay);
+        }
+
+        let reused = tx
+            .query_row("SELECT 1 FROM processed_commands WHERE command_id = ?1", params![command_id], |_| Ok(()))
+            .optional()?
+            .is_some();
+        tx.execute(
+            "INSERT INTO processed_commands (doc_id, revision, command_id, command, processed_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![doc_id, revision, command_id, command, now])?;
+        tx.commit()?;
+        Ok(if reused { CommandClaim::ReusedId } else { CommandClaim::New })
+    }
+
+    /// Door transitions in [from, to), oldest first.
+    pub fn door_range(&self, from: f64, to: f64) -> rusqlite::Result<Vec<DoorEvent>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT ts, sensor_id, state FROM door_events WHERE ts >= ?1 AND ts < ?2 ORDER BY ts")?;
+        let rows = stmt.query_map(params![from, to], |row| {
+            Ok(DoorEvent {
+                timestamp: row.get(0)?,
+                sensor_id: row.get(1)?,
+                state: row.get(2)?
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Oldest time raw samples are still kept for.
+    pub fn raw_cutoff(&self, now: f64) -> f64 {
+        now - self.retention.raw_days as f64 * SECONDS_PER_DAY
+    }
+
+    /// Roll completed hours up into climate_hourly and apply the retention policy.
+    pub fn maintain(&mut self, now: f64) -> rusqlite::Result<()> {
+        let current_hour = (now as i64).div_euclid(SECONDS_PER_HOUR) * SECONDS_PER_HOUR;
+        let raw_cutoff = self.raw_cutoff(now);
+        let hourly_cutoff = now - self.retention.hourly_days as f64 * SECONDS_PER_DAY;
+
+        let tx = self.conn.transaction()?;
+        let aggregated_until: i64 = tx
+            .query_row("SELECT value FROM meta WHERE key = 'aggregated_until'", [], |row| row.get(0))
+            .optional()?
+            .unwrap_or(0);
+
+        let aggregated = tx.execute(
+            "INSERT OR REPLACE INTO climate_hourly
+                (hour, sensor_id, samples, temp_f_min, temp_f_max, temp_f_avg, humidity_min, humidity_max, humidity_avg)
+             SELECT CAST(ts / 3600 AS INTEGER) * 3600 AS hour, sensor_id, COUNT(*),
+                    MIN(temp_f), MAX(temp_f), AVG(temp_f), MIN(humidity), MAX(humidity), AVG(humidity)
+             FROM climate_samples
+             WHERE ts >= ?1 AND ts < ?2
+             GROUP BY hour, sensor_id",
+            params![aggregated_until, current_hour])?;
+        tx.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES ('aggregated_until', ?1)",
+            params![current_hour.max(aggregated_until)])?;
+
+        let raw_deleted = tx.execute("DELETE FROM climate_samples WHERE ts < ?1", params![raw_cutoff])?;
+        let hourly_deleted = tx.execute("DELETE FROM climate_hourly WHERE hour < ?1", params![hourly_cutoff])?;
+        let door_deleted = tx.execute("DELETE FROM door_events WHERE ts < ?1", params![hourly_cutoff])?;
+        // never the latest change, the current mode restored on start
+        let modes_deleted = tx.execute(
+            "DELETE FROM mode_changes WHERE ts < ?1
+                AND rowid != (SELECT rowid FROM mode_changes ORDER BY ts DESC, rowid DESC LIMIT 1)",
+            params![hourly_cutoff])?;
+        // never the newest revision of a document, the one a listener replays
+        let commands_deleted = tx.execute(
+            "DELETE FROM processed_commands WHERE processed_at < ?1
+                AND rowid NOT IN (SELECT MAX(rowid) FROM processed_commands GROUP BY doc_id)",
+            params![raw_cutoff])?;
+        tx.execute("DELETE FROM power_actions WHERE ts < ?1 AND completed_at IS NOT NULL", params![raw_cutoff])?;
+        tx.commit()?;
+
+        log::info!("Store maintenance: {} hours aggregated, deleted {} raw, {} hourly, {} door, {} mode, {} command rows",
+            aggregated, raw_deleted, hourly_deleted, door_deleted, modes_deleted, commands_deleted);
+        Ok(())
+    }
+}
+
+/// Record every climate sample and door transition.
+pub fn spawn_store_sink(bus: &EventBus, store: SharedStore, door_sensor_id: &'static str) -> JoinHandle<()> {
+    let mut events = bus.subscribe_lossless("store");
+
+    tokio::task::spawn_blocking(move || {
+        log::info!("Store sink thread started");
+
+        while let Some((_, event)) = events.blocking_recv() {
+            let store = store.lock().unwrap();
+            let result = match event {
+                SensorEvent::ClimateSampled { sensor_id, temp_f, humidity, timestamp } => {
+                    store.insert_climate(sensor_id, temp_f, humidity, timestamp)
+                }
+                SensorEvent::DoorChanged { state, timestamp } => {
+                    store.insert_door(door_sensor_id, state, timestamp)
+                }
+                SensorEvent::ShutdownRequested { .. } => break,
+                _ => continue
+            };
+            if let Err(e) = result {
+                log::error!("Store write failed: {:?}", e);
+            }
+        }
+        log::info!("Store sink thread exiting");
+    })
+}
+
+/// Run store maintenance at start and then about once an hour, until shutdown.
+pub fn spawn_store_maintenance(bus: &EventBus, store: SharedStore) -> JoinHandle<()> {
+    let mut events = bus.subscribe("store-maintenance");
+
+    tokio::spawn(async move {
+        let mut iv = interval(STORE_MAINTENANCE_INTERVAL);
+        iv.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = iv.tick() => {}
+                event = events.recv() => match event {
+                    Some(SensorEvent::ShutdownRequested { .. }) | None => break,
+                    Some(_) => continue
+                }
+            }
+
+            // SQLite calls block, run on the blocking pool
+            let store = store.clone();
+            match tokio::task::spawn_blocking(move || store.lock().unwrap().maintain(crate::events::now_timestamp())).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => log::error!("Store maintenance failed: {:?}", e),
+                Err(e) => log::error!("Store maintenance failed: {:?}", e)
+            }
+        }
+        log::info!("Store maintenance exiting");
+    })
+}
+
+/// Print the last `hours` of mode changes, door transitions and climate history (raw, or hourly once past raw retention).
+pub fn print_history(store: &Store, hours: f64, now: f64) -> rusqlite::Result<()> {
+    let from = now - hours * 3600.0;
+
+    for change in store.mode_changes_range(from, now)? {
+        println!("{:.0}\tmode\t{}\t{} -> {}\t{}", change.timestamp, change.source, change.previous.as_str(), change.mode.as_str(),
+            change.issuer.as_deref().unwrap_or("-"));
+    }
+
+    for door in store.door_range(from, now)? {
+        println!("{:.0}\tdoor\t{}\t{}", door.timestamp, door.sensor_id, door.state);
+    }
+
+    if from >= store.raw_cutoff(now) {
+        for sample in store.climate_range(None, from, now)? {
+            println!("{:.0}\tclimate\t{}\t{:.1}°F\t{:.1}%", sample.timestamp, sample.sensor_id, sample.temp_f, sample.humidity);
+        }
+    } else {
+        for hour in store.climate_hourly_range(None, from, now)? {
+            println!("{}\thourly\t{}\t{:.1}..{:.1} (avg {:.1})°F\t{:.1}..{:.1} (avg {:.1})%\t{} samples",
+                hour.hour, hour.sensor_id,
+                hour.temp_f_min, hour.temp_f_max, hour.temp_f_avg,
+                hour.humidity_min, hour.humidity_max, hour.humidity_avg,
+                hour.samples);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new() -> TestDir {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!("sensor-store-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+            let _ = std::fs::remove_dir_all(&dir);
+            TestDir(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn open(dir: &TestDir) -> Store {
+        Store::open(&dir.0, Retention { raw_days: STORE_RAW_DAYS_DEFAULT, hourly_days: STORE_HOURLY_DAYS_DEFAULT }).unwrap()
+    }
+
+    #[test]
+    fn maintenance_keeps_the_newest_ledger_entry_of_every_document() {
+        let dir = TestDir::new();
+        let mut store = open(&dir);
+        assert_eq!(store.claim_command("alice", "r1", "c1", "refresh", 0.0).unwrap(), CommandClaim::New);
+        assert_eq!(store.claim_command("alice", "r2", "c2", "refresh", 1.0).unwrap(), CommandClaim::New);
+        assert_eq!(store.claim_command("bob", "r1", "c3", "reboot", 2.0).unwrap(), CommandClaim::New);
+
+        store.maintain(30.0 * SECONDS_PER_DAY).unwrap();
+
+        // the current revisions are still replays, however old
+        assert_eq!(store.claim_command("alice", "r2", "c2", "refresh", 3.0).unwrap(), CommandClaim::Replay);
+        assert_eq!(store.claim_command("bob", "r1", "c3", "reboot", 3.0).unwrap(), CommandClaim::Replay);
+        // a superseded revision is pruned
+        assert_eq!(store.claim_command("alice", "r1", "c1", "refresh", 3.0).unwrap(), CommandClaim::New);
+    }
+
+    fn mode_change(timestamp: f64, mode: Mode, previous: Mode) -> ModeChange {
+        ModeChange { timestamp, mode, previous, source: String::from("command"), issuer: None, command_id: None }
+    }
+
+    #[test]
+    fn maintenance_keeps_the_current_mode() {
+        let dir = TestDir::new();
+        let mut store = open(&dir);
+        store.insert_mode_change(&mode_change(1.0, Mode::Home, Mode::Armed)).unwrap();
+        store.insert_mode_change(&mode_change(2.0, Mode::Disarmed, Mode::Home)).unwrap();
+
+        let now = (STORE_HOURLY_DAYS_DEFAULT + 1) as f64 * SECONDS_PER_DAY;
+        store.maintain(now).unwrap();
+
+        assert_eq!(store.last_mode().unwrap(), Some(Mode::Disarmed));
+        assert_eq!(store.mode_changes_range(0.0, now).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn maintenance_keeps_recent_ledger_entries() {
+        let dir = TestDir::new();
+        let mut store = open(&dir);
+        let now = 30.0 * SECONDS_PER_DAY;
+        store.claim_command("alice", "r1", "c1", "refresh", now - SECONDS_PER_DAY).unwrap();
+        store.claim_command("alice", "r2", "c2", "refresh", now).unwrap();
+
+        store.maintain(now).unwrap();
+
+        assert_eq!(store.claim_command("alice", "r1", "c1", "refresh", now).unwrap(), CommandClaim::Replay);
+    }
+
+    const HOUR: f64 = SECONDS_PER_HOUR as f64;
+
+    #[test]
+    fn maintenance_rolls_up_completed_hours_once() {
+        let dir = TestDir::new();
+        let mut store = open(&dir);
+        store.insert_climate("a", 60.0, 40.0, 100.0 * HOUR + 10.0).unwrap();
+        store.insert_climate("a", 70.0, 50.0, 100.0 * HOUR + 20.0).unwrap();
+        store.insert_climate("b", 65.0, 45.0, 100.0 * HOUR + 30.0).unwrap();
+        store.insert_climate("a", 80.0, 60.0, 101.0 * HOUR + 10.0).unwrap();
+        // the current hour isn't complete yet
+        store.insert_climate("a", 90.0, 70.0, 102.0 * HOUR + 10.0).unwrap();
+
+        store.maintain(102.5 * HOUR).unwrap();
+        // nothing new to roll up, nothing counted twice
+        store.maintain(102.6 * HOUR).unwrap();
+
+        let hourly = store.climate_hourly_range(Some("a"), 0.0, 200.0 * HOUR).unwrap();
+        assert_eq!(hourly.iter().map(|hour| (hour.hour, hour.samples)).collect::<Vec<_>>(),
+            vec![(100 * SECONDS_PER_HOUR, 2), (101 * SECONDS_PER_HOUR, 1)]);
+        let first = &hourly[0];
+        assert_eq!((first.temp_f_min, first.temp_f_max, first.temp_f_avg), (60.0, 70.0, 65.0));
+        assert_eq!((first.humidity_min, first.humidity_max, first.humidity_avg), (40.0, 50.0, 45.0));
+        assert_eq!(store.climate_hourly_range(Some("b"), 0.0, 200.0 * HOUR).unwrap().len(), 1);
+
+        // rolled up once complete
+        store.maintain(103.0 * HOUR).unwrap();
+        let hourly = store.climate_hourly_range(Some("a"), 0.0, 200.0 * HOUR).unwrap();
+        assert_eq!(hourly.last().map(|hour| (hour.hour, hour.samples)), Some((102 * SECONDS_PER_HOUR, 1)));
+    }
+
+    #[test]
+    fn maintenance_applies_raw_and_hourly_retention() {
+        let dir = TestDir::new();
+        let mut store = Store::open(&dir.0, Retention { raw_days: 1, hourly_days: 3 }).unwrap();
+        let now = 10.0 * SECONDS_PER_DAY;
+        for days_ago in [4.0, 2.0, 0.5] {
+            let timestamp = now - days_ago * SECONDS_PER_DAY;
+            store.insert_climate("a", 70.0, 50.0, timestamp).unwrap();
+            store.insert_door("door", State::Open, timestamp).unwrap();
+        }
+
+        store.maintain(now).unwrap();
+
+        // raw samples for a day, their hourly aggregates and door transitions for three
+        let raw = store.climate_range(None, 0.0, now).unwrap();
+        assert_eq!(raw.iter().map(|sample| sample.timestamp).collect::<Vec<_>>(), vec![now - 0.5 * SECONDS_PER_DAY]);
+        let hourly = store.climate_hourly_range(None, 0.0, now).unwrap();
+        assert_eq!(hourly.len(), 2);
+        assert!(hourly.iter().all(|hour| hour.hour as f64 >= now - 3.0 * SECONDS_PER_DAY));
+        assert_eq!(store.door_range(0.0, now).unwrap().len(), 2);
+        assert_eq!(store.raw_cutoff(now), now - SECONDS_PER_DAY);
+    }
+
+    #[test]
+    fn range_queries_are_half_open_and_filter_by_sensor() {
+        let dir = TestDir::new();
+        let mut store = open(&dir);
+        for (sensor_id, timestamp) in [("a", 10.0), ("b", 20.0), ("a", 30.0)] {
+            store.insert_climate(sensor_id, 70.0, 50.0, timestamp).unwrap();
+        }
+        store.insert_door("door", State::Open, 10.0).unwrap();
+        store.insert_door("door", State::Closed, 30.0).unwrap();
+
+        let timestamps = |samples: Vec<ClimateSample>| samples.iter().map(|sample| sample.timestamp).collect::<Vec<_>>();
+        assert_eq!(timestamps(store.climate_range(None, 10.0, 30.0).unwrap()), vec![10.0, 20.0]);
+        assert_eq!(timestamps(store.climate_range(Some("a"), 0.0, 31.0).unwrap()), vec![10.0, 30.0]);
+        assert!(store.climate_range(Some("c"), 0.0, 31.0).unwrap().is_empty());
+
+        let doors = store.door_range(10.0, 30.0).unwrap();
+        assert_eq!(doors.iter().map(|door| (door.timestamp, door.state.as_str())).collect::<Vec<_>>(), vec![(10.0, "open")]);
+
+        store.maintain(2.0 * HOUR).unwrap();
+        assert_eq!(store.climate_hourly_range(None, 0.0, HOUR).unwrap().len(), 2);
+        assert!(store.climate_hourly_range(None, 1.0, HOUR).unwrap().is_empty());
+        assert_eq!(store.climate_hourly_range(Some("b"), 0.0, HOUR).unwrap()[0].samples, 1);
+    }
+}
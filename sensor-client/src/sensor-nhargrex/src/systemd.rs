@@ -0,0 +1,165 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// systemd notifications (Type=notify): READY, STATUS and watchdog pings.
+//
+// Critical tasks are either loops with a timer, which beat a Pulse every
+// round, or event driven tasks/threads that block while idle, which are only
+// checked for having exited. The watchdog is pinged while every pulse is
+// fresh and nothing has exited, so a stalled or dead task ends in a restart
+// (WatchdogSec=). Without NOTIFY_SOCKET (not run by systemd) nothing is sent.
+//
+use crate::health::{HealthStats, SharedHealth};
+use sd_notify::NotifyState;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio::time::{interval, Duration};
+
+// STATUS refresh (and stall check) when systemd has no watchdog configured
+const STATUS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Liveness signal of a periodic task, beat once per round.
+#[derive(Clone)]
+pub struct Pulse {
+    started: Instant,
+    // millis since started
+    last: Arc<AtomicU64>
+}
+
+impl Pulse {
+    fn new() -> Pulse {
+        Pulse { started: Instant::now(), last: Arc::new(AtomicU64::new(0)) }
+    }
+
+    pub fn beat(&self) {
+        self.last.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn silence(&self) -> Duration {
+        self.started.elapsed().saturating_sub(Duration::from_millis(self.last.load(Ordering::Relaxed)))
+    }
+}
+
+enum Watched {
+    Pulse(Pulse, Duration),
+    Task(AbortHandle),
+    Thread(std::thread::JoinHandle<()>)
+}
+
+/// The critical tasks that must stay alive for the watchdog to be pinged.
+pub struct Watchdog {
+    watched: Vec<(&'static str, Watched)>
+}
+
+impl Watchdog {
+    pub fn new() -> Watchdog {
+        Watchdog { watched: Vec::new() }
+    }
+
+    /// Watch a periodic task, stalled once it hasn't beaten for `max_silence`.
+    pub fn pulse(&mut self, name: &'static str, max_silence: Duration) -> Pulse {
+        let pulse = Pulse::new();
+        self.watched.push((name, Watched::Pulse(pulse.clone(), max_silence)));
+        pulse
+    }
+
+    /// Watch an event driven task, stalled once it has exited.
+    pub fn task(&mut self, name: &'static str, handle: &JoinHandle<()>) {
+        self.watched.push((name, Watched::Task(handle.abort_handle())));
+    }
+
+    /// Watch a thread, stalled once it has exited.
+    pub fn thread(&mut self, name: &'static str, handle: std::thread::JoinHandle<()>) {
+        self.watched.push((name, Watched::Thread(handle)));
+    }
+
+    /// Description of every stalled or exited task.
+    pub fn stalled(&self) -> Vec<String> {
+        self.watched.iter().filter_map(|(name, watched)| match watched {
+            Watched::Pulse(pulse, max_silence) => {
+                let silence = pulse.silence();
+                (silence > *max_silence).then(|| format!("{} (silent {}s)", name, silence.as_secs()))
+            }
+            Watched::Task(handle) => handle.is_finished().then(|| format!("{} (exited)", name)),
+            Watched::Thread(handle) => handle.is_finished().then(|| format!("{} (exited)", name))
+        }).collect()
+    }
+}
+
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        log::warn!("systemd notify failed: {:?}", e);
+    }
+}
+
+/// Tell systemd startup is complete.
+pub fn notify_ready(status: &str) {
+    log::info!("Ready: {}", status);
+    notify(&[NotifyState::Ready, NotifyState::Status(status)]);
+}
+
+/// Tell systemd a graceful shutdown has started.
+pub fn notify_stopping(reason: &str) {
+    notify(&[NotifyState::Stopping, NotifyState::Status(&format!("Shutting down: {}", reason))]);
+}
+
+// e.g. "Door closed, dht22-gpio18 68.2°F 45.0%, dht22-gpio27 67.9°F 46.1%, 2 read errors"
+fn status_line(health: &HealthStats) -> String {
+    let mut parts = vec![match health.door_state {
+        Some(state) => format!("Door {}", state.as_str()),
+        None => String::from("Door unknown")
+    }];
+    for (id, sensor) in &health.sensors {
+        if let (Some(temp_f), Some(humidity)) = (sensor.last_temp_f, sensor.last_humidity) {
+            parts.push(format!("{} {:.1}°F {:.1}%", id, temp_f, humidity));
+        }
+    }
+    let errors: u64 = health.sensors.values().map(|sensor| sensor.read_errors).sum();
+    if errors > 0 {
+        parts.push(format!("{} read errors", errors));
+    }
+    if !health.degraded.is_empty() {
+        parts.push(format!("degraded: {}", health.degraded.keys().cloned().collect::<Vec<_>>().join(", ")));
+    }
+    parts.join(", ")
+}
+
+/// Ping the systemd watchdog (at half of WatchdogSec) while nothing has stalled,
+/// and keep STATUS up to date, until aborted.
+pub fn spawn_watchdog(watchdog: Watchdog, health: SharedHealth) -> JoinHandle<()> {
+    let mut usec = 0;
+    let enabled = sd_notify::watchdog_enabled(false, &mut usec);
+    let period = if enabled { Duration::from_micros(usec / 2) } else { STATUS_INTERVAL };
+
+    tokio::spawn(async move {
+        if enabled {
+            log::info!("systemd watchdog enabled, pinging every {:?}", period);
+        }
+        let mut last_status = String::new();
+        let mut iv = interval(period);
+
+        loop {
+            iv.tick().await;
+
+            let stalled = watchdog.stalled();
+            let status = if stalled.is_empty() {
+                if enabled {
+                    notify(&[NotifyState::Watchdog]);
+                }
+                status_line(&health.lock().unwrap())
+            } else {
+                // no ping, systemd restarts us once WatchdogSec has passed
+                log::error!("Critical task stalled, withholding watchdog ping: {}", stalled.join(", "));
+                format!("Stalled: {}", stalled.join(", "))
+            };
+
+            if status != last_status {
+                notify(&[NotifyState::Status(&status)]);
+                last_status = status;
+            }
+        }
+    })
+}
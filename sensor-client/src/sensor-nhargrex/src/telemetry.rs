@@ -0,0 +1,133 @@
+//
+// sensor-nhargrex Rust program
+// (c) 2024 Nicholas Hargreaves
+//
+// Pub/Sub telemetry publisher for accepted temperature and humidity readings.
+//
+// Every message carries the reading as JSON (same shape as the Python
+// publish_temp_and_humidity) plus user, sensor_id and units attributes.
+// Messages are batched by the publisher and ordered per sensor id.
+//
+// Honors PUBSUB_EMULATOR_HOST so it can be run against the Pub/Sub emulator:
+//   gcloud beta emulators pubsub start --project=<projectId>
+//   export PUBSUB_EMULATOR_HOST=localhost:8085
+//
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+use google_cloud_pubsub::client::{Client, ClientConfig};
+use google_cloud_pubsub::publisher::{Publisher, PublisherConfig};
+use serde::Serialize;
+use std::collections::HashMap;
+use tokio::time::Duration;
+use anyhow::Result;
+
+// Constants
+pub const PUBSUB_TOPIC_ID_DEFAULT: &str = "sensor-data";
+const PUBSUB_FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+const PUBSUB_BUNDLE_SIZE: usize = 10;
+const PUBSUB_UNITS: &str = "temp_f=fahrenheit,humidity=percent";
+
+// Telemetry message payload
+#[derive(Debug, Clone, Serialize)]
+struct TelemetryMessage {
+    user: String,
+    sensor_id: String,
+    temp_f: f32,
+    humidity: f32,
+    timestamp: f64
+}
+
+#[derive(Clone)]
+pub struct TelemetryPublisher {
+    user: String,
+    topic_id: String,
+    publisher: Publisher
+}
+
+impl TelemetryPublisher {
+
+    /// Connect to Pub/Sub (or the emulator) and create a batching publisher for `topic_id`.
+    pub async fn new(project_id: String, topic_id: String, user: String) -> Result<TelemetryPublisher> {
+        let emulator = std::env::var("PUBSUB_EMULATOR_HOST").ok();
+
+        // ClientConfig::default() picks up PUBSUB_EMULATOR_HOST, with_auth() is a no-op for the emulator
+        let mut config = ClientConfig::default().with_auth().await?;
+        config.project_id = Some(project_id);
+
+        let client = Client::new(config).await?;
+        let topic = client.topic(&topic_id);
+
+        // the emulator starts empty, so create the topic on first use
+        if let Some(host) = emulator {
+            log::info!("Pub/Sub emulator at {}", host);
+            if !topic.exists(None).await? {
+                topic.create(None, None).await?;
+                log::info!("Pub/Sub emulator topic created: {}", topic_id);
+            }
+        }
+
+        let publisher = topic.new_publisher(Some(PublisherConfig {
+            flush_interval: PUBSUB_FLUSH_INTERVAL,
+            bundle_size: PUBSUB_BUNDLE_SIZE,
+            ..Default::default()
+        }));
+
+        log::info!("Pub/Sub telemetry publisher ready for topic {}", topic_id);
+
+        Ok(TelemetryPublisher {
+            user,
+            topic_id,
+            publisher
+        })
+    }
+
+    /// Publish one reading and wait for the server to assign a message id.
+    pub async fn publish_reading(&self, sensor_id: &str, temp_f: f32, humidity: f32, timestamp: f64) -> Result<String> {
+        if (temp_f == 0.0) || (humidity == 0.0) {
+            return Err(anyhow::anyhow!("invalid temp/humidity: t={}, h={}", temp_f, humidity));
+        }
+
+        let data = serde_json::to_vec(&TelemetryMessage {
+            user: self.user.clone(),
+            sensor_id: sensor_id.to_string(),
+            temp_f,
+            humidity,
+            timestamp
+        })?;
+
+        let attributes = HashMap::from([
+            ("user".to_string(), self.user.clone()),
+            ("sensor_id".to_string(), sensor_id.to_string()),
+            ("units".to_string(), PUBSUB_UNITS.to_string())
+        ]);
+
+        let message = PubsubMessage {
+            data,
+            attributes,
+            // keep each sensor's readings in order for the time-series pipeline
+            ordering_key: sensor_id.to_string(),
+            ..Default::default()
+        };
+
+        let awaiter = self.publisher.publish(message).await;
+        let message_id = awaiter.get().await?;
+        log::debug!("Published reading {} to topic {}: {:.2}°F, {:.2}%", message_id, self.topic_id, temp_f, humidity);
+
+        Ok(message_id)
+    }
+
+    /// Publish one reading from a background task, logging (not returning) failures.
+    pub fn spawn_publish_reading(&self, sensor_id: &'static str, temp_f: f32, humidity: f32, timestamp: f64) {
+        let telemetry = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = telemetry.publish_reading(sensor_id, temp_f, humidity, timestamp).await {
+                log::error!("Pub/Sub publish failed for {}: {:?}", sensor_id, e);
+            }
+        });
+    }
+
+    /// Flush any batched messages and stop the publisher.
+    pub async fn shutdown(&mut self) {
+        self.publisher.shutdown().await;
+        log::info!("Pub/Sub telemetry publisher stopped");
+    }
+}
@@ -0,0 +1,475 @@
+//
+// sensor-nhargrex integration tests
+// (c) 2024 Nicholas Hargreaves
+//
+// Runs the daemon on simulated GPIO against the Firestore emulator, writes
+// commands to sensorsRefreshRequest and checks the resulting sensors/{user}
+// and sensorsCommandResult/{user} documents.
+//
+// Requires the gcloud CLI with the Firestore emulator component (or an emulator
+// that is already running, see FIRESTORE_EMULATOR_HOST) and the Python
+// sensors_nhargrex_firestore dependencies for the refresh command:
+//   gcloud components install cloud-firestore-emulator
+//   cargo test --test emulator -- --ignored
+//
+// The MQTT test also needs mosquitto (or a broker at SENSOR_MQTT_TEST_HOST:1884).
+//
+use firestore::*;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const TEST_USER_ID: &str = "TestUser0123456789abcdefghij";
+const EMULATOR_START_TIMEOUT: Duration = Duration::from_secs(60);
+const DAEMON_START_DELAY: Duration = Duration::from_secs(5);
+const DOCUMENT_WAIT_TIMEOUT: Duration = Duration::from_secs(20);
+const MQTT_TEST_PORT: u16 = 1884;
+
+// Simulated sensor values, 20.0 °C is 68.0 °F
+const SIMULATED_TEMP_C: &str = "20.0";
+const SIMULATED_TEMP_F: f32 = 68.0;
+const SIMULATED_HUMIDITY: f32 = 45.0;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct SensorRefreshRequestObject {
+    r_ts: u64,
+    r_cmd: i32
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct CommandRequestObject {
+    v: u32,
+    id: String,
+    r_ts: u64,
+    command: serde_json::Value
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct CommandResultObject {
+    command_id: String,
+    v: u32,
+    command: Option<String>,
+    status: String,
+    error: Option<String>,
+    payload: Option<serde_json::Value>,
+    timestamp: f64,
+    completed_at: Option<f64>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct SensorObject {
+    online: bool,
+    state: String,
+    temp_f: f32,
+    humidity: f32,
+    timestamp: f64
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct FcmTokenObject {
+    token: String
+}
+
+// Firestore emulator, killed on drop if started by the test
+struct Emulator {
+    host: String,
+    child: Option<Child>
+}
+
+impl Emulator {
+    fn start(port: u16) -> Emulator {
+        if let Ok(host) = std::env::var("FIRESTORE_EMULATOR_HOST") {
+            return Emulator { host, child: None };
+        }
+
+        let host = format!("127.0.0.1:{}", port);
+        let child = Command::new("gcloud")
+            .args(["emulators", "firestore", "start", &format!("--host-port={}", host)])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("gcloud firestore emulator should start");
+
+        let started = Instant::now();
+        while TcpStream::connect(&host).is_err() {
+            assert!(started.elapsed() < EMULATOR_START_TIMEOUT, "Firestore emulator did not start on {}", host);
+            std::thread::sleep(Duration::from_millis(500));
+        }
+
+        Emulator { host, child: Some(child) }
+    }
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+// mosquitto broker, killed on drop if started by the test
+struct Broker {
+    host: String,
+    child: Option<Child>
+}
+
+impl Broker {
+    fn start(port: u16) -> Broker {
+        if let Ok(host) = std::env::var("SENSOR_MQTT_TEST_HOST") {
+            return Broker { host, child: None };
+        }
+
+        let child = Command::new("mosquitto")
+            .args(["-p", &port.to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("mosquitto should start");
+
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(started.elapsed() < EMULATOR_START_TIMEOUT, "mosquitto did not start on {}", port);
+            std::thread::sleep(Duration::from_millis(200));
+        }
+
+        Broker { host: "127.0.0.1".to_string(), child: Some(child) }
+    }
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+// Daemon running on simulated GPIO, killed on drop
+struct Daemon {
+    child: Child
+}
+
+impl Daemon {
+    fn start(emulator: &Emulator, project_id: &str, door: &str) -> Daemon {
+        Daemon::start_with_env(emulator, project_id, door, &[])
+    }
+
+    fn start_with_env(emulator: &Emulator, project_id: &str, door: &str, env: &[(&str, String)]) -> Daemon {
+        let python_path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../nhargrex");
+        // fresh outbox per test, nothing replayed from an earlier run
+        let data_dir = std::env::temp_dir().join(format!("sensor-nhargrex-{}", project_id));
+        let _ = std::fs::remove_dir_all(&data_dir);
+        let child = Command::new(env!("CARGO_BIN_EXE_sensor-nhargrex"))
+            .env("FIRESTORE_EMULATOR_HOST", &emulator.host)
+            .env("GOOGLE_PROJECT_ID", project_id)
+            .env("GOOGLE_USER_ID", TEST_USER_ID)
+            .env("GOOGLE_APPLICATION_CREDENTIALS", std::env::var("GOOGLE_APPLICATION_CREDENTIALS").unwrap_or_else(|_| "/dev/null".to_string()))
+            .env("SENSOR_SIMULATED_GPIO", "1")
+            .env("SENSOR_SIMULATED_DOOR", door)
+            .env("SENSOR_SIMULATED_TEMP_C", SIMULATED_TEMP_C)
+            .env("SENSOR_SIMULATED_HUMIDITY", SIMULATED_HUMIDITY.to_string())
+            .env("PYTHONPATH", python_path)
+            .env("SENSOR_DATA_DIR", &data_dir)
+            .envs(env.iter().map(|(name, value)| (*name, value.as_str())))
+            .spawn()
+            .expect("daemon should start");
+
+        // let the daemon connect and register its listener
+        std::thread::sleep(DAEMON_START_DELAY);
+        Daemon { child }
+    }
+
+    fn is_running(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+async fn connect(emulator: &Emulator, project_id: &str) -> FirestoreDb {
+    FirestoreDb::with_options(
+        FirestoreDbOptions::new(project_id.to_string())
+            .with_firebase_api_url(format!("http://{}", emulator.host))
+    )
+    .await
+    .expect("connect to Firestore emulator")
+}
+
+// seed the documents the Python side expects to exist
+async fn seed_sensor(db: &FirestoreDb, state: &str) -> SensorObject {
+    let sensor = SensorObject {
+        online: false,
+        state: state.to_string(),
+        temp_f: 50.0,
+        humidity: 10.0,
+        timestamp: 1.0
+    };
+    db.fluent()
+        .update()
+        .in_col("sensors")
+        .document_id(TEST_USER_ID)
+        .object(&sensor)
+        .execute::<()>()
+        .await
+        .expect("seed sensors document");
+    db.fluent()
+        .update()
+        .in_col("fcmTokens")
+        .document_id(TEST_USER_ID)
+        .object(&FcmTokenObject { token: "test-token".to_string() })
+        .execute::<()>()
+        .await
+        .expect("seed fcmTokens document");
+    sensor
+}
+
+async fn send_command(db: &FirestoreDb, r_cmd: i32) {
+    let r_ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    db.fluent()
+        .update()
+        .in_col("sensorsRefreshRequest")
+        .document_id(TEST_USER_ID)
+        .object(&SensorRefreshRequestObject { r_ts, r_cmd })
+        .execute::<()>()
+        .await
+        .expect("write command document");
+}
+
+// version 2 command document, returning its command id
+async fn send_command_v2(db: &FirestoreDb, command: serde_json::Value) -> String {
+    let r_ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let id = format!("test-{}", r_ts);
+    db.fluent()
+        .update()
+        .in_col("sensorsRefreshRequest")
+        .document_id(TEST_USER_ID)
+        .object(&CommandRequestObject { v: 2, id: id.clone(), r_ts, command })
+        .execute::<()>()
+        .await
+        .expect("write command document");
+    id
+}
+
+// poll sensorsCommandResult/{user} until it has a final status
+async fn wait_for_command_result(db: &FirestoreDb) -> CommandResultObject {
+    let started = Instant::now();
+    loop {
+        let result = db.fluent()
+            .select()
+            .by_id_in("sensorsCommandResult")
+            .obj::<CommandResultObject>()
+            .one(TEST_USER_ID)
+            .await
+            .expect("read sensorsCommandResult document");
+        if let Some(result) = result.filter(|result| result.completed_at.is_some()) {
+            return result;
+        }
+        assert!(started.elapsed() < DOCUMENT_WAIT_TIMEOUT, "no final command result");
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+async fn read_sensor(db: &FirestoreDb) -> SensorObject {
+    db.fluent()
+        .select()
+        .by_id_in("sensors")
+        .obj::<SensorObject>()
+        .one(TEST_USER_ID)
+        .await
+        .expect("read sensors document")
+        .expect("sensors document exists")
+}
+
+// poll sensors/{user} until it no longer has the seeded timestamp
+async fn wait_for_sensor_update(db: &FirestoreDb, seeded: &SensorObject) -> SensorObject {
+    let started = Instant::now();
+    loop {
+        let sensor = read_sensor(db).await;
+        if sensor.timestamp != seeded.timestamp {
+            return sensor;
+        }
+        assert!(started.elapsed() < DOCUMENT_WAIT_TIMEOUT, "sensors document was not updated: {:?}", sensor);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires the Firestore emulator"]
+async fn status_command_writes_current_state() {
+    let project_id = "demo-sensor-status";
+    let emulator = Emulator::start(8181);
+    let db = connect(&emulator, project_id).await;
+    let seeded = seed_sensor(&db, "closed").await;
+    let mut daemon = Daemon::start(&emulator, project_id, "open");
+
+    send_command(&db, 1).await;
+    let sensor = wait_for_sensor_update(&db, &seeded).await;
+
+    assert!(sensor.online);
+    assert_eq!(sensor.state, "open");
+    assert_eq!(sensor.temp_f, SIMULATED_TEMP_F);
+    assert_eq!(sensor.humidity, SIMULATED_HUMIDITY);
+    assert!(daemon.is_running());
+}
+
+#[tokio::test]
+#[ignore = "requires the Firestore emulator and the Python sensors_nhargrex_firestore module"]
+async fn refresh_command_updates_sensor_document() {
+    let project_id = "demo-sensor-refresh";
+    let emulator = Emulator::start(8182);
+    let db = connect(&emulator, project_id).await;
+    let seeded = seed_sensor(&db, "closed").await;
+    let mut daemon = Daemon::start(&emulator, project_id, "closed");
+
+    send_command(&db, 0).await;
+    let sensor = wait_for_sensor_update(&db, &seeded).await;
+
+    assert!(sensor.online);
+    assert_eq!(sensor.state, "closed");
+    assert_eq!(sensor.temp_f, SIMULATED_TEMP_F);
+    assert_eq!(sensor.humidity, SIMULATED_HUMIDITY);
+    assert!(daemon.is_running());
+}
+
+#[tokio::test]
+#[ignore = "requires the Firestore emulator"]
+async fn unknown_command_leaves_sensor_document_unchanged() {
+    let project_id = "demo-sensor-unknown";
+    let emulator = Emulator::start(8183);
+    let db = connect(&emulator, project_id).await;
+    let seeded = seed_sensor(&db, "closed").await;
+    let mut daemon = Daemon::start(&emulator, project_id, "closed");
+
+    send_command(&db, 99).await;
+    let result = wait_for_command_result(&db).await;
+    let sensor = read_sensor(&db).await;
+
+    assert_eq!(result.status, "rejected");
+    assert_eq!(result.command.as_deref(), Some("99"));
+    assert!(result.error.is_some());
+    assert!(!sensor.online);
+    assert_eq!(sensor.timestamp, seeded.timestamp);
+    assert!(daemon.is_running());
+}
+
+#[tokio::test]
+#[ignore = "requires the Firestore emulator"]
+async fn v2_status_command_writes_done_result() {
+    let project_id = "demo-sensor-v2-status";
+    let emulator = Emulator::start(8185);
+    let db = connect(&emulator, project_id).await;
+    let seeded = seed_sensor(&db, "closed").await;
+    let mut daemon = Daemon::start(&emulator, project_id, "open");
+
+    let command_id = send_command_v2(&db, serde_json::json!({ "type": "status" })).await;
+    let result = wait_for_command_result(&db).await;
+    let sensor = wait_for_sensor_update(&db, &seeded).await;
+
+    assert_eq!(result.command_id, command_id);
+    assert_eq!(result.v, 2);
+    assert_eq!(result.command.as_deref(), Some("status"));
+    assert_eq!(result.status, "done");
+    assert_eq!(result.payload.as_ref().and_then(|payload| payload.get("state")).and_then(|state| state.as_str()), Some("open"));
+    assert_eq!(sensor.state, "open");
+    assert!(daemon.is_running());
+}
+
+#[tokio::test]
+#[ignore = "requires the Firestore emulator"]
+async fn reboot_without_admin_role_is_rejected() {
+    let project_id = "demo-sensor-reboot-role";
+    let emulator = Emulator::start(8186);
+    let db = connect(&emulator, project_id).await;
+    seed_sensor(&db, "closed").await;
+    let mut daemon = Daemon::start(&emulator, project_id, "closed");
+
+    // the device user is allowed with the user role only by default
+    let command_id = send_command_v2(&db, serde_json::json!({ "type": "reboot" })).await;
+    let result = wait_for_command_result(&db).await;
+
+    assert_eq!(result.command_id, command_id);
+    assert_eq!(result.status, "rejected");
+    assert!(result.error.unwrap_or_default().contains("admin role"));
+    assert!(daemon.is_running());
+}
+
+#[tokio::test]
+#[ignore = "requires the Firestore emulator"]
+async fn reboot_without_confirmation_is_rejected_with_a_token() {
+    let project_id = "demo-sensor-reboot-confirm";
+    let emulator = Emulator::start(8187);
+    let db = connect(&emulator, project_id).await;
+    seed_sensor(&db, "closed").await;
+    let mut daemon = Daemon::start_with_env(&emulator, project_id, "closed", &[
+        ("SENSOR_COMMAND_ISSUERS", format!("{}:admin", TEST_USER_ID))
+    ]);
+
+    // never confirmed, so the test host is not rebooted
+    let command_id = send_command_v2(&db, serde_json::json!({ "type": "reboot" })).await;
+    let result = wait_for_command_result(&db).await;
+
+    assert_eq!(result.command_id, command_id);
+    assert_eq!(result.status, "rejected");
+    assert!(result.error.unwrap_or_default().contains("confirmation required"));
+    assert!(result.payload.as_ref().and_then(|payload| payload.get("confirm")).and_then(|token| token.as_str()).is_some_and(|token| !token.is_empty()));
+    assert!(daemon.is_running());
+}
+
+// wait for a message on `topic`, returning its payload
+async fn wait_for_message(client_events: &mut rumqttc::EventLoop, topic: &str) -> String {
+    let started = Instant::now();
+    loop {
+        let remaining = DOCUMENT_WAIT_TIMEOUT.checked_sub(started.elapsed()).unwrap_or_default();
+        let event = match tokio::time::timeout(remaining, client_events.poll()).await {
+            Ok(event) => event.expect("mqtt connection"),
+            Err(_) => panic!("no message on {}", topic)
+        };
+        if let Event::Incoming(Packet::Publish(message)) = event {
+            if message.topic == topic {
+                return String::from_utf8_lossy(&message.payload).to_string();
+            }
+        }
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires the Firestore emulator and mosquitto"]
+async fn mqtt_publishes_discovery_state_and_accepts_refresh() {
+    let project_id = "demo-sensor-mqtt";
+    let emulator = Emulator::start(8184);
+    let broker = Broker::start(MQTT_TEST_PORT);
+    let db = connect(&emulator, project_id).await;
+    seed_sensor(&db, "closed").await;
+    let mut daemon = Daemon::start_with_env(&emulator, project_id, "open", &[
+        ("SENSOR_MQTT_HOST", broker.host.clone()),
+        ("SENSOR_MQTT_PORT", MQTT_TEST_PORT.to_string()),
+        ("SENSOR_MQTT_PREFIX", "test-garage".to_string())
+    ]);
+
+    let (client, mut client_events) = AsyncClient::new(MqttOptions::new("emulator-test", broker.host.clone(), MQTT_TEST_PORT), 10);
+    client.subscribe("homeassistant/#", QoS::AtLeastOnce).await.unwrap();
+    client.subscribe("test-garage/#", QoS::AtLeastOnce).await.unwrap();
+
+    // retained, so they arrive even though the daemon connected first
+    let discovery = wait_for_message(&mut client_events, "homeassistant/binary_sensor/sensor_nhargrex/door/config").await;
+    let discovery: serde_json::Value = serde_json::from_str(&discovery).unwrap();
+    assert_eq!(discovery["device_class"], "garage_door");
+    assert_eq!(discovery["state_topic"], "test-garage/door/state");
+    assert_eq!(wait_for_message(&mut client_events, "test-garage/availability").await, "online");
+    assert_eq!(wait_for_message(&mut client_events, "test-garage/door/state").await, "open");
+
+    // a refresh reports the state again
+    client.publish("test-garage/command", QoS::AtLeastOnce, false, "refresh").await.unwrap();
+    assert_eq!(wait_for_message(&mut client_events, "test-garage/door/state").await, "open");
+    assert!(daemon.is_running());
+}